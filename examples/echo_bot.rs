@@ -0,0 +1,42 @@
+//! A server with a bot in `#lobby` that echoes and tells the time.
+//!
+//! Run with `cargo run --example echo_bot [port]` and try `!help`,
+//! `!echo hello` or `!uptime` from any client.
+
+use std::{env, time::Duration};
+
+use rust_tokio_chat_server::{Bot, ChatServer};
+use tokio::{net::TcpListener, time::Instant};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let port = match env::args().nth(1) {
+        Some(port) => port.parse()?,
+        None => 8080,
+    };
+    let server = ChatServer::builder().build()?;
+    let handle = server.handle();
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("listening on {}", listener.local_addr()?);
+
+    let started = Instant::now();
+    let bot = Bot::new("echobot")
+        .join("#lobby")
+        .command("echo", "!echo <text>: say it back", |cmd| async move {
+            (!cmd.args.is_empty()).then_some(cmd.args)
+        })
+        .command(
+            "uptime",
+            "!uptime: how long the server has been up",
+            move |_| async move { Some(format!("up for {}s", started.elapsed().as_secs())) },
+        )
+        .after(Duration::from_secs(1), "#lobby", "echobot here, try !help")
+        .every(Duration::from_secs(3600), "#lobby", "another hour went by");
+    handle.spawn_bot(bot).await?;
+
+    let running = tokio::spawn(server.run(listener));
+    tokio::signal::ctrl_c().await?;
+    handle.shutdown();
+    running.await?;
+    Ok(())
+}
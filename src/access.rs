@@ -0,0 +1,299 @@
+//! Which addresses may connect at all.
+//!
+//! The access file has one rule per line, `#` starts a comment:
+//! ```text
+//! allow 10.0.0.0/8
+//! deny 10.66.0.0/16
+//! deny 2001:db8::/32
+//! ```
+//! `deny` wins over `allow`, and once there is any `allow` line, addresses
+//! that match none are turned away too. The file is read on startup and
+//! again on SIGHUP. Admins can also ban ranges at runtime, those bans last
+//! until the server restarts and survive reloads.
+
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+};
+
+use tokio::sync::watch;
+
+/// An address range like `192.0.2.0/24`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    /// With the host bits cleared
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask_v4(ip.into(), self.prefix) == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask_v6(ip.into(), self.prefix) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+/// The first `prefix` bits of `bits`.
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    bits & u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    bits & u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max).ok_or(())?,
+            None => max,
+        };
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4(mask_v4(ip.into(), prefix).into()),
+            IpAddr::V6(ip) => IpAddr::V6(mask_v6(ip.into(), prefix).into()),
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// The rules from the access file.
+#[derive(Debug, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    fn read(path: &Path) -> io::Result<AccessList> {
+        let mut list = AccessList::default();
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let rule = line.split('#').next().unwrap_or_default().trim();
+            if rule.is_empty() {
+                continue;
+            }
+            let parsed = rule
+                .split_once(char::is_whitespace)
+                .and_then(|(kind, range)| {
+                    let range = range.trim().parse().ok()?;
+                    match kind {
+                        "allow" => Some((&mut list.allow, range)),
+                        "deny" => Some((&mut list.deny, range)),
+                        _ => None,
+                    }
+                });
+            let Some((rules, range)) = parsed else {
+                let message = format!(
+                    "{}:{}: expected `allow <range>` or `deny <range>`",
+                    path.display(),
+                    index + 1
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            };
+            rules.push(range);
+        }
+        Ok(list)
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+pub struct Access {
+    /// `None` lets everyone in who isn't banned
+    path: Option<PathBuf>,
+    list: RwLock<AccessList>,
+    bans: Mutex<Vec<Cidr>>,
+    /// Bumped on every new ban, so sessions from the range can be ended
+    banned: watch::Sender<()>,
+}
+
+impl Access {
+    pub fn load(path: Option<&Path>) -> io::Result<Access> {
+        let list = match path {
+            Some(path) => AccessList::read(path)?,
+            None => AccessList::default(),
+        };
+        Ok(Access {
+            path: path.map(Path::to_path_buf),
+            list: RwLock::new(list),
+            bans: Mutex::default(),
+            banned: watch::channel(()).0,
+        })
+    }
+
+    /// Read the access file again. On errors the old rules stay. Returns
+    /// the number of `allow` and `deny` rules, `None` without a file.
+    /// Connected clients are left alone either way.
+    pub fn reload(&self) -> io::Result<Option<(usize, usize)>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let list = AccessList::read(path)?;
+        let counts = (list.allow.len(), list.deny.len());
+        *self.list.write().unwrap() = list;
+        Ok(Some(counts))
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.is_banned(ip) && self.list.read().unwrap().allows(ip)
+    }
+
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().iter().any(|ban| ban.contains(ip))
+    }
+
+    /// Returns `false` if `range` was banned already.
+    pub fn ban(&self, range: Cidr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        if bans.contains(&range) {
+            return false;
+        }
+        bans.push(range);
+        drop(bans);
+        self.banned.send_replace(());
+        true
+    }
+
+    /// Returns `false` if `range` wasn't banned.
+    pub fn unban(&self, range: Cidr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let before = bans.len();
+        bans.retain(|ban| *ban != range);
+        bans.len() < before
+    }
+
+    pub fn bans(&self) -> Vec<Cidr> {
+        self.bans.lock().unwrap().clone()
+    }
+
+    /// Resolves once `ip` is banned. Cancel safe, so it can be used in
+    /// `select!`.
+    pub async fn banned(&self, ip: IpAddr) {
+        let mut banned = self.banned.subscribe();
+        while !self.is_banned(ip) {
+            // The sender lives as long as `self`
+            let _ = banned.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr("192.0.2.7/24").to_string(), "192.0.2.0/24");
+        assert_eq!(cidr("10.1.2.3/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("2001:db8:1:2:3::/64").to_string(), "2001:db8:1:2::/64");
+        assert_eq!(cidr("192.0.2.7/24"), cidr("192.0.2.200/24"));
+        for bad in [
+            "",
+            "nope",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "192.0.2.0/-1",
+            "10/8",
+        ] {
+            assert_eq!(bad.parse::<Cidr>(), Err(()), "{bad}");
+        }
+    }
+
+    #[test]
+    fn matches_addresses() {
+        let net = cidr("192.0.2.0/24");
+        assert!(net.contains(ip("192.0.2.0")));
+        assert!(net.contains(ip("192.0.2.255")));
+        assert!(!net.contains(ip("192.0.3.0")));
+        assert!(!net.contains(ip("192.0.1.255")));
+
+        let one = cidr("192.0.2.7");
+        assert!(one.contains(ip("192.0.2.7")));
+        assert!(!one.contains(ip("192.0.2.8")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::9")));
+
+        let v6 = cidr("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_v4_rules() {
+        assert!(cidr("192.0.2.0/24").contains(ip("::ffff:192.0.2.7")));
+        assert!(cidr("0.0.0.0/0").contains(ip("::ffff:203.0.113.9")));
+        assert!(!cidr("::/0").contains(ip("192.0.2.7")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn reads_rules() {
+        let path = std::env::temp_dir().join(format!("chat-access-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# office\nallow 10.0.0.0/8\n\ndeny 10.66.0.0/16 # lab\n",
+        )
+        .unwrap();
+        let access = Access::load(Some(&path)).unwrap();
+        assert!(access.allows(ip("10.1.2.3")));
+        assert!(!access.allows(ip("10.66.0.1")));
+        assert!(!access.allows(ip("192.0.2.7")));
+
+        fs::write(&path, "deny 10.66.0.0/16\n").unwrap();
+        assert_eq!(access.reload().unwrap(), Some((0, 1)));
+        assert!(access.allows(ip("192.0.2.7")));
+
+        fs::write(&path, "allow 10.0.0.0/8\nblock 10.66.0.0/16\n").unwrap();
+        assert!(access.reload().is_err());
+        assert!(access.allows(ip("192.0.2.7")), "old rules stay");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bans_ranges() {
+        let access = Access::load(None).unwrap();
+        assert!(access.allows(ip("192.0.2.7")));
+        assert!(access.ban(cidr("192.0.2.0/24")));
+        assert!(!access.ban(cidr("192.0.2.9/24")));
+        assert!(!access.allows(ip("192.0.2.7")));
+        assert!(!access.allows(ip("::ffff:192.0.2.7")));
+        assert!(access.allows(ip("192.0.3.7")));
+        assert!(access.unban(cidr("192.0.2.0/24")));
+        assert!(!access.unban(cidr("192.0.2.0/24")));
+        assert!(access.allows(ip("192.0.2.7")));
+    }
+}
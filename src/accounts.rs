@@ -0,0 +1,411 @@
+//! Registered nicknames and their passwords.
+//!
+//! Accounts live in a plain text file, one `nick hash` line each, where the
+//! hash is a PHC string: `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+//! The file is read once on startup and appended to on every registration.
+//!
+//! Hashing is slow on purpose, so only a few hashes are computed at once,
+//! and an address that keeps failing to log in has to wait longer and
+//! longer between attempts.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use tokio::{sync::Semaphore, task};
+
+use crate::{
+    crypto::random_bytes,
+    limits::host_of,
+    users::{validate_nick, NickError},
+};
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+const SALT_LEN: usize = 16;
+
+/// Checked instead when a login names no account, so it takes as long as a
+/// wrong password and the timing doesn't tell which nicks are registered.
+/// Made like any other hash, for a password nobody knows.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$dk70ii09svy+uQJVFDxSEQ$wjzGTQjGRn/1zR/64IVrQuZ8u5UUXOErpHhuTEIBdFY";
+
+/// Hashes computed at once. Each takes a core and argon2's 19 MiB for a
+/// moment, the others wait for a turn.
+const MAX_HASHING: usize = 2;
+
+/// Logins and registrations an address gets before it has to wait
+const FREE_ATTEMPTS: u32 = 3;
+
+/// The first wait, doubled on every further attempt up to the maximum
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Attempts are forgotten after this long without one
+const FORGET_AFTER: Duration = Duration::from_secs(900);
+
+/// Addresses are only forgotten once the table grows past this
+const PRUNE_AT: usize = 4096;
+
+#[derive(Debug)]
+pub enum AccountError {
+    Disabled,
+    Nick(NickError),
+    Registered(String),
+    PasswordLength,
+    /// Unknown nick or wrong password, deliberately not saying which
+    BadLogin,
+    /// Too many attempts from the client's address, it has to wait this long
+    Backoff(Duration),
+    Store(io::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Disabled => write!(f, "accounts are turned off on this server"),
+            AccountError::Nick(err) => write!(f, "{err}"),
+            AccountError::Registered(nick) => write!(f, "{nick} is already registered"),
+            AccountError::PasswordLength => write!(
+                f,
+                "passwords are {MIN_PASSWORD_LEN} to {MAX_PASSWORD_LEN} characters long"
+            ),
+            AccountError::BadLogin => write!(f, "wrong nickname or password"),
+            AccountError::Backoff(wait) => write!(
+                f,
+                "too many attempts, try again in {}s",
+                wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+            ),
+            AccountError::Store(err) => write!(f, "cannot save the account: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AccountError::Nick(err) => Some(err),
+            AccountError::Store(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+struct Account {
+    /// As it was registered
+    nick: String,
+    hash: String,
+}
+
+pub struct Accounts {
+    path: PathBuf,
+    // lowercased nick -> account
+    accounts: Mutex<HashMap<String, Account>>,
+    /// Held while a registration is checked and written, so two sessions
+    /// can't register the same nick
+    writer: tokio::sync::Mutex<()>,
+    hashing: Arc<Semaphore>,
+    attempts: Attempts,
+}
+
+impl Accounts {
+    /// Read the accounts file, a missing one just means no accounts yet.
+    pub fn load(path: &Path) -> io::Result<Accounts> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut accounts = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (nick, hash) = line
+                .split_once(' ')
+                .filter(|(nick, hash)| validate_nick(nick).is_ok() && hash.starts_with('$'))
+                .ok_or_else(|| {
+                    let message = format!("{}:{}: expected `nick hash`", path.display(), index + 1);
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                })?;
+            let account = Account {
+                nick: nick.to_string(),
+                hash: hash.to_string(),
+            };
+            accounts.insert(nick.to_ascii_lowercase(), account);
+        }
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            accounts: Mutex::new(accounts),
+            writer: tokio::sync::Mutex::new(()),
+            hashing: Arc::new(Semaphore::new(MAX_HASHING)),
+            attempts: Attempts::default(),
+        })
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.accounts
+            .lock()
+            .unwrap()
+            .contains_key(&nick.to_ascii_lowercase())
+    }
+
+    /// Create an account for a client at `ip`. Every registration counts as
+    /// an attempt, since it costs a hash just like a login.
+    pub async fn register(
+        &self,
+        nick: &str,
+        password: &str,
+        ip: IpAddr,
+    ) -> Result<(), AccountError> {
+        validate_nick(nick).map_err(AccountError::Nick)?;
+        if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.chars().count()) {
+            return Err(AccountError::PasswordLength);
+        }
+        let _writer = self.writer.lock().await;
+        if self.is_registered(nick) {
+            return Err(AccountError::Registered(nick.to_string()));
+        }
+        self.attempts
+            .attempt(host_of(ip))
+            .map_err(AccountError::Backoff)?;
+        let password = password.to_string();
+        let hash = self
+            .hash(move || hash_password(&password))
+            .await
+            .map_err(AccountError::Store)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(AccountError::Store)?;
+        writeln!(file, "{nick} {hash}").map_err(AccountError::Store)?;
+        let account = Account {
+            nick: nick.to_string(),
+            hash,
+        };
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(nick.to_ascii_lowercase(), account);
+        Ok(())
+    }
+
+    /// Check a login from a client at `ip`. Returns the nick as it was
+    /// registered. A successful login forgets the address's attempts.
+    pub async fn verify(
+        &self,
+        nick: &str,
+        password: &str,
+        ip: IpAddr,
+    ) -> Result<String, AccountError> {
+        let host = host_of(ip);
+        self.attempts.attempt(host).map_err(AccountError::Backoff)?;
+        let account = self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(&nick.to_ascii_lowercase())
+            .map(|account| (account.nick.clone(), account.hash.clone()));
+        let (nick, hash) = match account {
+            Some((nick, hash)) => (Some(nick), hash),
+            None => (None, DUMMY_HASH.to_string()),
+        };
+        let password = password.to_string();
+        let matches = self.hash(move || verify_password(&password, &hash)).await;
+        match nick {
+            Some(nick) if matches => {
+                self.attempts.forget(host);
+                Ok(nick)
+            }
+            _ => Err(AccountError::BadLogin),
+        }
+    }
+
+    /// Run `hash` on the blocking pool once it's its turn.
+    async fn hash<T: Send + 'static>(&self, hash: impl FnOnce() -> T + Send + 'static) -> T {
+        let turn =
+            (self.hashing.clone().acquire_owned().await).expect("the semaphore is never closed");
+        task::spawn_blocking(move || {
+            let _turn = turn;
+            hash()
+        })
+        .await
+        .expect("hashing doesn't panic")
+    }
+}
+
+/// Logins and registrations by host, so passwords can't be guessed quickly
+/// and nobody can keep the server busy hashing.
+#[derive(Default)]
+struct Attempts {
+    hosts: Mutex<HashMap<IpAddr, Strikes>>,
+}
+
+struct Strikes {
+    count: u32,
+    last: Instant,
+}
+
+impl Attempts {
+    /// Count an attempt from `host`, or say how long it has to wait first.
+    /// Counting before the hash keeps parallel connections from getting
+    /// more attempts in.
+    fn attempt(&self, host: IpAddr) -> Result<(), Duration> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= PRUNE_AT {
+            hosts.retain(|_, strikes| strikes.last.elapsed() < FORGET_AFTER);
+        }
+        let now = Instant::now();
+        let strikes = hosts.entry(host).or_insert(Strikes {
+            count: 0,
+            last: now,
+        });
+        let since = now - strikes.last;
+        if since >= FORGET_AFTER {
+            strikes.count = 0;
+        }
+        let wait = backoff(strikes.count);
+        if since < wait {
+            return Err(wait - since);
+        }
+        strikes.count += 1;
+        strikes.last = now;
+        Ok(())
+    }
+
+    fn forget(&self, host: IpAddr) {
+        self.hosts.lock().unwrap().remove(&host);
+    }
+}
+
+/// How long to wait after `count` attempts.
+fn backoff(count: u32) -> Duration {
+    match count.checked_sub(FREE_ATTEMPTS) {
+        Some(extra) => BACKOFF_MIN
+            .saturating_mul(2u32.saturating_pow(extra))
+            .min(BACKOFF_MAX),
+        None => Duration::ZERO,
+    }
+}
+
+fn hash_password(password: &str) -> io::Result<String> {
+    let salt = random_bytes::<SALT_LEN>()?;
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes make a valid salt");
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, process};
+
+    use super::*;
+
+    const HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn accounts(name: &str) -> Accounts {
+        let path = std::env::temp_dir().join(format!("chat-accounts-{}-{name}", process::id()));
+        let _ = fs::remove_file(&path);
+        Accounts::load(&path).unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(FREE_ATTEMPTS - 1), Duration::ZERO);
+        assert_eq!(backoff(FREE_ATTEMPTS), BACKOFF_MIN);
+        assert_eq!(backoff(FREE_ATTEMPTS + 2), BACKOFF_MIN * 4);
+        assert_eq!(backoff(FREE_ATTEMPTS + 20), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn attempts_are_counted_per_host() {
+        let attempts = Attempts::default();
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(attempts.attempt(HOST), Ok(()));
+        }
+        let wait = attempts.attempt(HOST).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= BACKOFF_MIN, "{wait:?}");
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(attempts.attempt(other), Ok(()));
+        attempts.forget(HOST);
+        assert_eq!(attempts.attempt(HOST), Ok(()));
+    }
+
+    #[test]
+    fn the_dummy_hash_costs_as_much_as_real_ones() {
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        let stored = hash_password("correct-horse").unwrap();
+        let real = PasswordHash::new(&stored).unwrap();
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+    }
+
+    #[test]
+    fn backoff_errors_round_up() {
+        let err = AccountError::Backoff(Duration::from_millis(1500));
+        assert_eq!(err.to_string(), "too many attempts, try again in 2s");
+    }
+
+    #[tokio::test]
+    async fn registered_passwords_log_in() {
+        let accounts = accounts("login");
+        accounts
+            .register("Alice", "correct-horse", HOST)
+            .await
+            .unwrap();
+        let stored = fs::read_to_string(&accounts.path).unwrap();
+        assert!(stored.starts_with("Alice $argon2id$"), "{stored}");
+        assert!(!stored.contains("correct-horse"));
+
+        let verify = |password| accounts.verify("alice", password, HOST);
+        assert_eq!(verify("correct-horse").await.unwrap(), "Alice");
+        assert!(matches!(
+            verify("wrong-horse").await,
+            Err(AccountError::BadLogin)
+        ));
+        assert!(matches!(
+            accounts.register("alice", "another-one", HOST).await,
+            Err(AccountError::Registered(_))
+        ));
+        assert!(matches!(
+            accounts.verify("nobody", "correct-horse", HOST).await,
+            Err(AccountError::BadLogin)
+        ));
+        assert!(matches!(
+            verify("wrong-horse").await,
+            Err(AccountError::BadLogin)
+        ));
+        // Three failed attempts since the last login, the next has to wait
+        assert!(matches!(
+            verify("correct-horse").await,
+            Err(AccountError::Backoff(_))
+        ));
+        fs::remove_file(&accounts.path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! What the client knows about the chat, picked up from the frames going
+//! by: who we are, where plain lines go, and names for completion.
+
+use std::collections::BTreeSet;
+
+use rust_tokio_chat_server::client::{Event, ServerFrame};
+
+/// Commands for `/help` and Tab, with their arguments. `/quit`, `/help` and
+/// `/clear` are the client's own.
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("/join", "#room"),
+    ("/part", "[#room]"),
+    ("/rooms", ""),
+    ("/names", "[#room]"),
+    ("/msg", "<nick> <text>"),
+    ("/nick", "<name>"),
+    ("/topic", "[text]"),
+    ("/history", "<n>"),
+    ("/register", "<nick> <password>"),
+    ("/login", "<nick> <password>"),
+    ("/ban", "<address or range>"),
+    ("/unban", "<address or range>"),
+    ("/bans", ""),
+    ("/clear", ""),
+    ("/help", ""),
+    ("/quit", ""),
+];
+
+#[derive(Debug, Default)]
+pub struct Chat {
+    /// Our nick once the server took it
+    pub me: Option<String>,
+    /// Where plain lines go
+    pub room: Option<String>,
+    /// Rooms we are in
+    pub joined: BTreeSet<String>,
+    /// Everyone seen so far, for completion
+    pub nicks: BTreeSet<String>,
+    /// Rooms seen so far, also ones we aren't in
+    pub rooms: BTreeSet<String>,
+}
+
+impl Chat {
+    /// A new connection starts outside every room.
+    pub fn reconnected(&mut self) {
+        self.room = None;
+        self.joined.clear();
+    }
+
+    pub fn learn(&mut self, frame: &ServerFrame) {
+        if let Some(room) = frame.room() {
+            self.rooms.insert(room.to_string());
+        }
+        match frame {
+            ServerFrame::Message { from, .. } | ServerFrame::Private { from, .. } => {
+                self.nicks.insert(from.clone());
+            }
+            ServerFrame::Event {
+                room: None,
+                nick,
+                event,
+                ..
+            } => match event {
+                Event::Join => {
+                    self.nicks.insert(nick.clone());
+                }
+                Event::Part(_) => {
+                    self.nicks.remove(nick);
+                }
+                Event::Nick(new_nick) => {
+                    self.nicks.remove(nick);
+                    self.nicks.insert(new_nick.clone());
+                }
+                Event::Topic(_) => {}
+            },
+            ServerFrame::Event { nick, .. } => {
+                self.nicks.insert(nick.clone());
+            }
+            // Our own joins, parts and renames are only confirmed in words
+            ServerFrame::Ack(text) => self.acked(text),
+            _ => {}
+        }
+    }
+
+    fn acked(&mut self, text: &str) {
+        if let Some(nick) = (text.strip_prefix("you are now known as "))
+            .or_else(|| text.strip_prefix("logged in as "))
+        {
+            self.me = Some(nick.to_string());
+        } else if let Some(room) =
+            (text.strip_prefix("you joined ")).or_else(|| text.strip_prefix("now talking in "))
+        {
+            self.joined.insert(room.to_string());
+            self.rooms.insert(room.to_string());
+            self.room = Some(room.to_string());
+        } else if let Some(left) = text.strip_prefix("you left ") {
+            let (left, active) = match left.split_once(", now talking in ") {
+                Some((left, active)) => (left, Some(active.to_string())),
+                None => (left, None),
+            };
+            self.joined.remove(left);
+            self.room = active;
+        }
+    }
+
+    /// Whether `text` says our nick as a word of its own.
+    pub fn mentions_me(&self, text: &str) -> bool {
+        let Some(me) = &self.me else { return false };
+        text.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .any(|word| word.eq_ignore_ascii_case(me))
+    }
+
+    /// Commands for the first word, rooms for words starting with `#`, nicks
+    /// for the rest.
+    pub fn completions(&self, word: &str, first: bool) -> Vec<String> {
+        let matches = |name: &&str| {
+            (name.get(..word.len())).is_some_and(|start| start.eq_ignore_ascii_case(word))
+        };
+        if word.is_empty() {
+            return Vec::new();
+        }
+        if first && word.starts_with('/') {
+            let commands = COMMANDS.iter().map(|(name, _)| *name);
+            return commands.filter(matches).map(str::to_string).collect();
+        }
+        let names = match word.starts_with('#') {
+            true => &self.rooms,
+            false => &self.nicks,
+        };
+        (names.iter().map(String::as_str))
+            .filter(matches)
+            .filter(|name| Some(*name) != self.me.as_deref())
+            .map(str::to_string)
+            .collect()
+    }
+}
@@ -0,0 +1,228 @@
+//! The input line: editing keys in the readline style, history and Tab
+//! completion. It only keeps state, drawing is up to the caller.
+
+use crate::term::{self, Key};
+
+/// Lines kept for Up and Down
+const HISTORY_LIMIT: usize = 500;
+
+/// What the caller should do after a key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing besides drawing the line again
+    Edited,
+    /// Enter was pressed on this line
+    Submit(String),
+    /// Ctrl-C, or Ctrl-D on an empty line
+    Quit,
+    /// Ctrl-L
+    ClearScreen,
+    /// Tab found several completions and can't narrow them down further
+    Candidates(Vec<String>),
+}
+
+#[derive(Debug, Default)]
+pub struct Editor {
+    line: Vec<char>,
+    /// In chars, 0 to `line.len()`
+    cursor: usize,
+    history: Vec<String>,
+    /// Index into `history` while going through it with Up and Down
+    browsing: Option<usize>,
+    /// The line that was being typed before browsing started
+    draft: String,
+}
+
+impl Editor {
+    /// Apply `key`. Tab asks `complete` for the words that could replace the
+    /// one before the cursor, and whether that word starts the line.
+    pub fn key(&mut self, key: Key, complete: impl Fn(&str, bool) -> Vec<String>) -> Action {
+        match key {
+            Key::Char(c) => self.insert(c),
+            Key::Enter => return self.submit(),
+            Key::Tab => return self.complete(complete),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Ctrl('d') if self.line.is_empty() => return Action::Quit,
+            Key::Ctrl('d') if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Ctrl('c') => return Action::Quit,
+            Key::Ctrl('l') => return Action::ClearScreen,
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.line.len(),
+            Key::Ctrl('u') => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('k') => self.line.truncate(self.cursor),
+            Key::Ctrl('w') => {
+                let start = self.word_start();
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up | Key::Ctrl('p') => self.browse_back(),
+            Key::Down | Key::Ctrl('n') => self.browse_forward(),
+            _ => {}
+        }
+        Action::Edited
+    }
+
+    fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn set_line(&mut self, line: &str) {
+        self.line = line.chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    fn submit(&mut self) -> Action {
+        let line: String = self.line.drain(..).collect();
+        self.cursor = 0;
+        self.browsing = None;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            if self.history.len() == HISTORY_LIMIT {
+                self.history.remove(0);
+            }
+            self.history.push(line.clone());
+        }
+        Action::Submit(line)
+    }
+
+    fn browse_back(&mut self) {
+        let index = match self.browsing {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.iter().collect();
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1),
+        };
+        self.browsing = Some(index);
+        self.set_line(&self.history[index].clone());
+    }
+
+    fn browse_forward(&mut self) {
+        let Some(index) = self.browsing else { return };
+        if index + 1 < self.history.len() {
+            self.browsing = Some(index + 1);
+            self.set_line(&self.history[index + 1].clone());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set_line(&draft);
+        }
+    }
+
+    /// Start of the word the cursor is in or right after.
+    fn word_start(&self) -> usize {
+        let before = &self.line[..self.cursor];
+        let end = before
+            .iter()
+            .rposition(|c| !c.is_whitespace())
+            .map_or(0, |i| i + 1);
+        before[..end]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |i| i + 1)
+    }
+
+    fn complete(&mut self, complete: impl Fn(&str, bool) -> Vec<String>) -> Action {
+        let start = match self.line[..self.cursor].last() {
+            Some(c) if c.is_whitespace() => self.cursor,
+            _ => self.word_start(),
+        };
+        let word: String = self.line[start..self.cursor].iter().collect();
+        let candidates = complete(&word, start == 0);
+        let replacement = match candidates.as_slice() {
+            [] => return Action::Edited,
+            [only] => format!("{only} "),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.as_str(), |common, candidate| {
+                    let len = common
+                        .char_indices()
+                        .zip(candidate.chars())
+                        .take_while(|((_, a), b)| a.eq_ignore_ascii_case(b))
+                        .last()
+                        .map_or(0, |((i, a), _)| i + a.len_utf8());
+                    &common[..len]
+                });
+                if common.chars().count() <= word.chars().count() {
+                    return Action::Candidates(candidates);
+                }
+                common.to_string()
+            }
+        };
+        self.line.splice(start..self.cursor, replacement.chars());
+        self.cursor = start + replacement.chars().count();
+        Action::Edited
+    }
+
+    /// `prompt` and as much of the line as fits in `width` columns, scrolled
+    /// so the cursor is visible, and the cursor's column. Control characters
+    /// are shown as `term::printable` does.
+    pub fn view(&self, prompt: &str, width: usize) -> (String, usize) {
+        let prompt_width = term::width(prompt);
+        let room = width.saturating_sub(prompt_width + 1).max(1);
+        // Scroll just far enough for the cursor to stay on screen
+        let mut scroll = self.cursor;
+        let mut before = 0;
+        while let Some(&c) = scroll.checked_sub(1).and_then(|i| self.line.get(i)) {
+            if before + term::char_width(c) > room {
+                break;
+            }
+            before += term::char_width(c);
+            scroll -= 1;
+        }
+        let mut visible = String::new();
+        let mut used = 0;
+        for &c in &self.line[scroll..] {
+            used += term::char_width(c);
+            if used > room {
+                break;
+            }
+            visible.push(c);
+        }
+        (
+            format!("{prompt}{}", term::printable(&visible)),
+            prompt_width + before,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Editor {
+        let mut editor = Editor::default();
+        for c in text.chars() {
+            editor.key(Key::Char(c), |_, _| Vec::new());
+        }
+        editor
+    }
+
+    #[test]
+    fn views_by_columns() {
+        assert_eq!(typed("hello").view("> ", 20), ("> hello".to_string(), 7));
+        assert_eq!(typed("日本語").view("> ", 20), ("> 日本語".to_string(), 8));
+        // Six columns of room, the cursor at the end needs the last ones
+        assert_eq!(
+            typed("日本語です").view("> ", 9),
+            ("> 語です".to_string(), 8)
+        );
+        assert_eq!(
+            typed("a\u{85}b").view("#日> ", 20),
+            ("#日> a\u{fffd}b".to_string(), 8)
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! `chat-client`: a line client for the chat server. Messages scroll above
+//! an input line that keeps what you are typing in place, Tab completes
+//! commands, rooms and nicks, and a lost connection is picked up again.
+//!
+//! Without a terminal on stdin and stdout it reads lines and prints frames
+//! plainly, so it can be scripted. `--tui` gives every room a window of its
+//! own instead, see `tui`.
+
+mod chat;
+mod editor;
+mod term;
+mod tui;
+
+use std::{
+    io::{self, Write},
+    process,
+};
+
+use rust_tokio_chat_server::client::{Client, ClientFrame, ServerFrame, Stamp, Update};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    chat::{Chat, COMMANDS},
+    editor::{Action, Editor},
+    term::{printable, Keys, RawMode},
+};
+
+const USAGE: &str = "\
+Usage: chat-client [OPTIONS] [HOST:PORT]
+
+Connects to a chat server [default: 127.0.0.1:8080]
+
+Options:
+  -n, --nick <NAME>   Pick this nickname once connected
+      --tui           Full screen, with a window per room and the room's users
+      --no-color      Don't color the output (also when NO_COLOR is set)
+  -h, --help          Print help";
+
+/// Colors for nicks, see `nick_color`
+const NICK_COLORS: &[&str] = &["32", "33", "34", "35", "36", "92", "93", "94", "95", "96"];
+
+struct Args {
+    addr: String,
+    nick: Option<String>,
+    tui: bool,
+    color: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args {
+            addr: "127.0.0.1:8080".to_string(),
+            nick: None,
+            tui: false,
+            color: std::env::var_os("NO_COLOR").is_none(),
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    process::exit(0);
+                }
+                "-n" | "--nick" => {
+                    parsed.nick = Some(args.next().ok_or("--nick needs a name")?);
+                }
+                "--tui" => parsed.tui = true,
+                "--no-color" => parsed.color = false,
+                flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+                addr => parsed.addr = addr.to_string(),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        process::exit(2);
+    });
+    let interactive = term::is_tty(libc::STDIN_FILENO) && term::is_tty(libc::STDOUT_FILENO);
+    if args.tui && !interactive {
+        eprintln!("--tui needs a terminal on stdin and stdout");
+        process::exit(2);
+    }
+    let raw_mode = match interactive {
+        true => match RawMode::enable() {
+            Ok(raw_mode) => Some(raw_mode),
+            Err(err) => {
+                eprintln!("cannot set up the terminal: {err}");
+                process::exit(1);
+            }
+        },
+        false => None,
+    };
+    let mut screen = Screen {
+        editor: Editor::default(),
+        chat: Chat::default(),
+        interactive,
+        color: args.color && interactive,
+    };
+    let mut client = Client::connect(args.addr.clone());
+    if let Some(nick) = args.nick {
+        client.send(ClientFrame::Nick(nick)).await;
+    }
+    if args.tui {
+        tui::run(client, &args.addr, args.color).await;
+        drop(raw_mode);
+        return;
+    }
+    screen.print(&screen.paint("2", &format!("connecting to {}", args.addr)));
+
+    let mut stdin = tokio::io::stdin();
+    let mut keys = Keys::default();
+    let mut buf = [0; 1024];
+    'session: loop {
+        tokio::select! {
+            read = stdin.read(&mut buf) => {
+                let n = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                for key in keys.feed(&buf[..n]) {
+                    let chat = &screen.chat;
+                    let action = screen.editor.key(key, |word, first| chat.completions(word, first));
+                    match action {
+                        Action::Edited => screen.draw(),
+                        Action::Submit(line) => match screen.submit(&line) {
+                            Some(Input::Send(frame)) => client.send(frame).await,
+                            Some(Input::Quit) => break 'session,
+                            None => {}
+                        },
+                        Action::Quit => break 'session,
+                        Action::ClearScreen => {
+                            screen.write("\x1b[2J\x1b[H");
+                            screen.draw();
+                        }
+                        Action::Candidates(candidates) => {
+                            screen.print(&printable(&candidates.join("  ")))
+                        }
+                    }
+                }
+            }
+            update = client.next() => match update {
+                Some(update) => screen.update(update),
+                None => break,
+            },
+        }
+    }
+    client.close().await;
+    if screen.interactive {
+        screen.write("\r\x1b[K");
+    }
+    drop(raw_mode);
+}
+
+/// What to do with a submitted line.
+enum Input {
+    Send(ClientFrame),
+    Quit,
+}
+
+/// The output scrolling by, with the input line kept at the bottom.
+struct Screen {
+    editor: Editor,
+    chat: Chat,
+    /// Draw the input line, otherwise just print lines
+    interactive: bool,
+    color: bool,
+}
+
+impl Screen {
+    fn write(&self, text: &str) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn prompt(&self) -> String {
+        match &self.chat.room {
+            Some(room) => format!("{}> ", printable(room)),
+            None => "> ".to_string(),
+        }
+    }
+
+    /// Redraw the input line.
+    fn draw(&self) {
+        if !self.interactive {
+            return;
+        }
+        let (width, _) = term::size();
+        let (line, cursor) = self.editor.view(&self.prompt(), width);
+        let right = match cursor {
+            0 => String::new(),
+            cursor => format!("\x1b[{cursor}C"),
+        };
+        self.write(&format!("\r\x1b[K{line}\r{right}"));
+    }
+
+    /// Print `text` above the input line. Anything from the server in it
+    /// has to go through `printable` first.
+    fn print(&self, text: &str) {
+        match self.interactive {
+            // Output processing stays on in raw mode, so `\n` starts a line
+            true => self.write(&format!("\r\x1b[K{text}\n")),
+            false => self.write(&format!("{text}\n")),
+        }
+        self.draw();
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        match self.color {
+            true => format!("\x1b[{style}m{text}\x1b[0m"),
+            false => text.to_string(),
+        }
+    }
+
+    fn update(&mut self, update: Update) {
+        let text = match update {
+            Update::Connected => {
+                self.chat.reconnected();
+                self.paint("2", "connected")
+            }
+            Update::Frame(frame) => {
+                self.chat.learn(&frame);
+                self.frame(&frame)
+            }
+            Update::Disconnected { reason, retry_in } => {
+                let text = format!(
+                    "{}, reconnecting in {}s",
+                    printable(&reason),
+                    retry_in.as_secs()
+                );
+                self.paint("31", &text)
+            }
+            Update::Unsent(_) => self.paint("31", "not connected, that wasn't sent"),
+        };
+        self.print(&text);
+    }
+
+    fn frame(&self, frame: &ServerFrame) -> String {
+        let text = match frame {
+            ServerFrame::Message {
+                room, from, text, ..
+            } => {
+                let text = printable(text);
+                let text = match self.chat.mentions_me(&text) {
+                    true => self.paint("1;33", &text),
+                    false => text.into_owned(),
+                };
+                format!("[{}] <{}> {text}", printable(room), self.nick(from))
+            }
+            ServerFrame::Private { from, text, .. } => {
+                let text = printable(text);
+                let text = match self.chat.mentions_me(&text) {
+                    true => self.paint("1;35", &text),
+                    false => self.paint("35", &text),
+                };
+                format!("{} <{}> {text}", self.paint("35", "[pm]"), self.nick(from))
+            }
+            ServerFrame::Notice { .. }
+            | ServerFrame::Event { .. }
+            | ServerFrame::Names { .. }
+            | ServerFrame::Hello { .. } => self.paint("2", &printable(&frame.to_string())),
+            ServerFrame::Ack(text) => self.paint("32", &printable(text)),
+            ServerFrame::Error(text) => self.paint("31", &printable(text)),
+            ServerFrame::Ping(_) => printable(&frame.to_string()).into_owned(),
+        };
+        match stamp(frame) {
+            Some(stamp) => format!("{} {text}", self.paint("2", &clock(stamp.ts))),
+            None => text,
+        }
+    }
+
+    fn nick(&self, nick: &str) -> String {
+        self.paint(nick_color(nick), &printable(nick))
+    }
+
+    /// Handle the client's own commands, turn the rest into frames.
+    fn submit(&mut self, line: &str) -> Option<Input> {
+        if self.interactive && !line.trim().is_empty() {
+            // Keep what was typed in the scrollback, the server doesn't echo
+            // our own messages
+            let said = match line.strip_prefix('/') {
+                Some(rest) => rest.strip_prefix('/').map(|text| format!("/{text}")),
+                None => Some(line.to_string()),
+            };
+            let echo = match (said, &self.chat.room, &self.chat.me) {
+                (Some(text), Some(room), Some(me)) => {
+                    let now = self.paint("2", &clock(Stamp::new().ts));
+                    let (room, text) = (printable(room), printable(&text));
+                    format!("{now} [{room}] <{}> {text}", self.nick(me))
+                }
+                _ => self.paint("2", &format!("{}{}", self.prompt(), printable(line))),
+            };
+            self.print(&echo);
+        }
+        let command = line.split_whitespace().next().unwrap_or_default();
+        match command {
+            "/quit" | "/exit" => return Some(Input::Quit),
+            "/clear" => {
+                self.write("\x1b[2J\x1b[H");
+                self.draw();
+                return None;
+            }
+            "/help" => {
+                for (name, arguments) in COMMANDS {
+                    self.print(&format!("{name} {arguments}"));
+                }
+                self.print("Tab completes commands, #rooms and nicks. Ctrl-C or /quit to leave.");
+                return None;
+            }
+            "/hello" => {
+                self.print(&self.paint("31", "chat-client always speaks JSON mode"));
+                return None;
+            }
+            _ => {}
+        }
+        match ClientFrame::parse(line) {
+            Ok(Some(frame)) => Some(Input::Send(frame)),
+            Ok(None) => {
+                self.draw();
+                None
+            }
+            Err(err) => {
+                self.print(&self.paint("31", &err.to_string()));
+                None
+            }
+        }
+    }
+}
+
+/// The color `nick` is shown in, picked by a hash so everyone keeps theirs.
+fn nick_color(nick: &str) -> &'static str {
+    let hash = nick.bytes().fold(0usize, |hash, b| {
+        hash.wrapping_mul(31)
+            .wrapping_add(usize::from(b.to_ascii_lowercase()))
+    });
+    NICK_COLORS[hash % NICK_COLORS.len()]
+}
+
+fn stamp(frame: &ServerFrame) -> Option<Stamp> {
+    match frame {
+        ServerFrame::Message { stamp, .. }
+        | ServerFrame::Private { stamp, .. }
+        | ServerFrame::Notice { stamp, .. }
+        | ServerFrame::Event { stamp, .. } => Some(*stamp),
+        _ => None,
+    }
+}
+
+/// `HH:MM` in local time for a timestamp in milliseconds.
+fn clock(ts: u64) -> String {
+    let secs = (ts / 1000) as libc::time_t;
+    // SAFETY: localtime_r only writes into the struct we pass
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return "--:--".to_string();
+    }
+    format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+}
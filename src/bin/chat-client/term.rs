@@ -0,0 +1,321 @@
+//! Just enough terminal handling for the client: raw mode through termios,
+//! the window size, turning the bytes keys send into `Key`s, keeping what
+//! others wrote from talking to the terminal, and how wide text is on it.
+
+use std::{borrow::Cow, io, mem};
+
+/// Puts the terminal into raw mode and back when dropped, also when
+/// unwinding from a panic.
+pub struct RawMode {
+    saved: libc::termios,
+}
+
+impl RawMode {
+    /// Keys arrive one by one without echo. Ctrl-C and Ctrl-Z arrive as keys
+    /// too, so the client can clean up before quitting. Output processing
+    /// stays on.
+    pub fn enable() -> io::Result<RawMode> {
+        // SAFETY: tcgetattr only writes into the struct we pass
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        termios.c_iflag &= !(libc::IXON | libc::ICRNL);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+pub fn is_tty(fd: libc::c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Columns and rows of the terminal, 80x24 if it won't say.
+pub fn size() -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ only writes into the struct we pass
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    match ok && size.ws_col > 0 && size.ws_row > 0 {
+        true => (size.ws_col.into(), size.ws_row.into()),
+        false => (80, 24),
+    }
+}
+
+/// `text` with its control characters made visible, so text from the
+/// server can't move the cursor, retitle the window or worse. C0 and DEL
+/// show as `^X`, C1 as U+FFFD.
+pub fn printable(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(char::is_control) {
+        return Cow::Borrowed(text);
+    }
+    let mut shown = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\0'..='\x1f' => {
+                shown.push('^');
+                shown.push(char::from(c as u8 + b'@'));
+            }
+            '\x7f' => shown.push_str("^?"),
+            '\u{80}'..='\u{9f}' => shown.push('\u{fffd}'),
+            c => shown.push(c),
+        }
+    }
+    Cow::Owned(shown)
+}
+
+/// Columns `text` takes once it went through `printable`.
+pub fn width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// Columns `c` takes once it went through `printable`. East Asian wide
+/// characters and most emoji take two, combining marks and other zero
+/// width characters none. Close to what terminals do, which don't agree
+/// among themselves either.
+pub fn char_width(c: char) -> usize {
+    match c {
+        '\0'..='\x1f' | '\x7f' => 2,
+        '\u{0300}'..='\u{036f}'
+        | '\u{0483}'..='\u{0489}'
+        | '\u{0591}'..='\u{05bd}'
+        | '\u{0610}'..='\u{061a}'
+        | '\u{064b}'..='\u{065f}'
+        | '\u{0670}'
+        | '\u{06d6}'..='\u{06dc}'
+        | '\u{06df}'..='\u{06e4}'
+        | '\u{0e31}'
+        | '\u{0e34}'..='\u{0e3a}'
+        | '\u{0e47}'..='\u{0e4e}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{200b}'..='\u{200f}'
+        | '\u{202a}'..='\u{202e}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{fe20}'..='\u{fe2f}'
+        | '\u{feff}'
+        | '\u{e0000}'..='\u{e007f}'
+        | '\u{e0100}'..='\u{e01ef}' => 0,
+        '\u{1100}'..='\u{115f}'
+        | '\u{231a}'..='\u{231b}'
+        | '\u{2329}'..='\u{232a}'
+        | '\u{23e9}'..='\u{23ec}'
+        | '\u{23f0}'
+        | '\u{23f3}'
+        | '\u{25fd}'..='\u{25fe}'
+        | '\u{2614}'..='\u{2615}'
+        | '\u{2648}'..='\u{2653}'
+        | '\u{267f}'
+        | '\u{2693}'
+        | '\u{26a1}'
+        | '\u{26aa}'..='\u{26ab}'
+        | '\u{26bd}'..='\u{26be}'
+        | '\u{26c4}'..='\u{26c5}'
+        | '\u{26ce}'
+        | '\u{26d4}'
+        | '\u{26ea}'
+        | '\u{26f2}'..='\u{26f3}'
+        | '\u{26f5}'
+        | '\u{26fa}'
+        | '\u{26fd}'
+        | '\u{2705}'
+        | '\u{270a}'..='\u{270b}'
+        | '\u{2728}'
+        | '\u{274c}'
+        | '\u{274e}'
+        | '\u{2753}'..='\u{2755}'
+        | '\u{2757}'
+        | '\u{2795}'..='\u{2797}'
+        | '\u{27b0}'
+        | '\u{27bf}'
+        | '\u{2b1b}'..='\u{2b1c}'
+        | '\u{2b50}'
+        | '\u{2b55}'
+        | '\u{2e80}'..='\u{303e}'
+        | '\u{3041}'..='\u{33ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{a000}'..='\u{a4cf}'
+        | '\u{a960}'..='\u{a97f}'
+        | '\u{ac00}'..='\u{d7a3}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{fe10}'..='\u{fe19}'
+        | '\u{fe30}'..='\u{fe6f}'
+        | '\u{ff00}'..='\u{ff60}'
+        | '\u{ffe0}'..='\u{ffe6}'
+        | '\u{16fe0}'..='\u{16fe4}'
+        | '\u{17000}'..='\u{18cff}'
+        | '\u{1b000}'..='\u{1b2ff}'
+        | '\u{1f004}'
+        | '\u{1f0cf}'
+        | '\u{1f18e}'
+        | '\u{1f191}'..='\u{1f19a}'
+        | '\u{1f200}'..='\u{1f251}'
+        | '\u{1f300}'..='\u{1f64f}'
+        | '\u{1f680}'..='\u{1f6ff}'
+        | '\u{1f7e0}'..='\u{1f7eb}'
+        | '\u{1f90c}'..='\u{1f9ff}'
+        | '\u{1fa70}'..='\u{1faff}'
+        | '\u{20000}'..='\u{2fffd}'
+        | '\u{30000}'..='\u{3fffd}' => 2,
+        _ => 1,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// A letter pressed with Ctrl, lowercase
+    Ctrl(char),
+    /// A key pressed with Alt (or Meta), which terminals send as an escape
+    /// before the key
+    Alt(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+/// Decodes keys from what stdin delivers. UTF-8 and escape sequences can be
+/// split across reads, so incomplete ones wait for the next read. An escape
+/// at the end of a read is the Escape key, terminals send sequences whole.
+#[derive(Debug, Default)]
+pub struct Keys {
+    pending: Vec<u8>,
+}
+
+impl Keys {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+        let mut keys = Vec::new();
+        let mut at = 0;
+        while at < self.pending.len() {
+            match decode(&self.pending[at..]) {
+                Some((key, used)) => {
+                    keys.extend(key);
+                    at += used;
+                }
+                None => break,
+            }
+        }
+        self.pending.drain(..at);
+        keys
+    }
+}
+
+/// One key from the start of `bytes`, if it is one we know, and how many
+/// bytes it took. `None` if more bytes are needed.
+fn decode(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let key = match bytes[0] {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => return escape(bytes),
+        byte @ 0x01..=0x1a => Key::Ctrl(char::from(b'a' + byte - 1)),
+        byte if byte < 0x20 => return Some((None, 1)),
+        byte => {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            if bytes.len() < len {
+                return None;
+            }
+            return match std::str::from_utf8(&bytes[..len]) {
+                Ok(s) => Some((s.chars().next().map(Key::Char), len)),
+                Err(_) => Some((None, 1)),
+            };
+        }
+    };
+    Some((Some(key), 1))
+}
+
+/// `ESC [ X`, `ESC O X` and `ESC [ n ~`, or Alt with a printable key. A
+/// lone escape is the Escape key.
+fn escape(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    match bytes.get(1) {
+        Some(b'[' | b'O') => {}
+        Some(&byte @ 0x20..=0x7e) => return Some((Some(Key::Alt(char::from(byte))), 2)),
+        _ => return Some((Some(Key::Escape), 1)),
+    }
+    // Parameters, then one final byte in @..~
+    let end = bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b));
+    let end = end? + 2;
+    let key = match (&bytes[2..end], bytes[end]) {
+        (_, b'A') => Some(Key::Up),
+        (_, b'B') => Some(Key::Down),
+        (_, b'C') => Some(Key::Right),
+        (_, b'D') => Some(Key::Left),
+        (_, b'H') | (b"1" | b"7", b'~') => Some(Key::Home),
+        (_, b'F') | (b"4" | b"8", b'~') => Some(Key::End),
+        (b"3", b'~') => Some(Key::Delete),
+        (b"5", b'~') => Some(Key::PageUp),
+        (b"6", b'~') => Some(Key::PageDown),
+        _ => None,
+    };
+    Some((key, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_shown() {
+        assert!(matches!(printable("plain text, ünïcode"), Cow::Borrowed(_)));
+        assert_eq!(printable("\x1b[2J\x1b]0;owned\x07"), "^[[2J^[]0;owned^G");
+        assert_eq!(printable("a\r\nb\tc\0"), "a^M^Jb^Ic^@");
+        assert_eq!(printable("del\x7f"), "del^?");
+        assert_eq!(printable("\u{9b}31m \u{85}"), "\u{fffd}31m \u{fffd}");
+    }
+
+    #[test]
+    fn measures_columns() {
+        assert_eq!(width("hello"), 5);
+        assert_eq!(width("日本語"), 6);
+        assert_eq!(width("e\u{301}"), 1);
+        assert_eq!(width("👍 ok"), 5);
+        assert_eq!(width("한국어"), 6);
+        assert_eq!(width("ＡＢ"), 4);
+        for text in ["a\x1b[1mb\u{9b}c", "tab\there", "日本\u{7f}"] {
+            assert_eq!(width(text), width(&printable(text)), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let mut keys = Keys::default();
+        assert_eq!(
+            keys.feed(b"a\x1b[A\x03\xc3"),
+            [Key::Char('a'), Key::Up, Key::Ctrl('c')]
+        );
+        assert_eq!(
+            keys.feed(b"\xa9\x1b[3~\x1b"),
+            [Key::Char('é'), Key::Delete, Key::Escape]
+        );
+    }
+}
@@ -0,0 +1,941 @@
+//! `chat-client --tui`: windows for the server, each room and each private
+//! conversation listed on the left, the active one in the middle, who is in
+//! it on the right and the input line at the bottom. Drawn with plain ANSI
+//! sequences on the alternate screen, rewriting only the rows that changed,
+//! so it stays light over SSH.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use rust_tokio_chat_server::client::{Client, ClientFrame, Event, ServerFrame, Stamp, Update};
+use tokio::{
+    io::AsyncReadExt,
+    signal::unix::{signal, SignalKind},
+};
+
+use crate::{
+    chat::{Chat, COMMANDS},
+    clock,
+    editor::{Action, Editor},
+    nick_color, stamp,
+    term::{self, printable, Key, Keys},
+    Input,
+};
+
+/// Widths of the room list and the user list
+const ROOMS_WIDTH: usize = 18;
+const USERS_WIDTH: usize = 16;
+
+/// Narrower terminals drop the user list, then the room list too
+const USERS_MIN_WIDTH: usize = 80;
+const ROOMS_MIN_WIDTH: usize = 50;
+
+/// Lines kept per window
+const SCROLLBACK: usize = 2000;
+
+/// Name of the window for everything that isn't about a room
+const SERVER_WINDOW: &str = "server";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Plain,
+    Dim,
+    Bold,
+    /// A color from `nick_color`
+    Nick(&'static str),
+    Mention,
+    Private,
+    Ack,
+    Error,
+    /// The active window in the room list, and the title bar
+    Reverse,
+}
+
+impl Style {
+    fn sgr(self, color: bool) -> &'static str {
+        match (self, color) {
+            (Style::Plain, _) => "",
+            (Style::Dim, _) => "2",
+            (Style::Bold, _) => "1",
+            (Style::Nick(code), true) => code,
+            (Style::Mention, true) => "1;33",
+            (Style::Mention, false) => "1;7",
+            (Style::Private, true) => "35",
+            (Style::Ack, true) => "32",
+            (Style::Error, true) => "31",
+            (Style::Reverse, _) => "7",
+            (Style::Nick(_) | Style::Private | Style::Ack | Style::Error, false) => "",
+        }
+    }
+}
+
+/// One line in a window: when it happened and styled pieces of text.
+struct Line {
+    ts: Option<u64>,
+    /// Without control characters, see `term::printable`
+    spans: Vec<(Style, String)>,
+}
+
+impl Line {
+    fn new(ts: Option<u64>, spans: Vec<(Style, String)>) -> Line {
+        let spans = (spans.into_iter())
+            .map(|(style, text)| match printable(&text) {
+                Cow::Borrowed(_) => (style, text),
+                Cow::Owned(shown) => (style, shown),
+            })
+            .collect();
+        Line { ts, spans }
+    }
+
+    fn plain(style: Style, text: impl Into<String>) -> Line {
+        Line::new(None, vec![(style, text.into())])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Server,
+    Room,
+    Private,
+}
+
+struct Window {
+    /// `server`, `#room`, or the nick of a private conversation
+    name: String,
+    kind: Kind,
+    lines: VecDeque<Line>,
+    /// Messages that came in while another window was active
+    unread: usize,
+    /// Some of them mention us, or are private
+    mentioned: bool,
+    /// How many lines up from the bottom the view is, 0 follows new lines
+    scroll: usize,
+    /// Room members, sorted
+    members: Vec<String>,
+    topic: Option<String>,
+}
+
+impl Window {
+    fn new(name: &str, kind: Kind) -> Window {
+        Window {
+            name: name.to_string(),
+            kind,
+            lines: VecDeque::new(),
+            unread: 0,
+            mentioned: false,
+            scroll: 0,
+            members: Vec::new(),
+            topic: None,
+        }
+    }
+
+    fn title(&self) -> String {
+        match self.kind {
+            Kind::Private => format!("@{}", self.name),
+            _ => self.name.clone(),
+        }
+    }
+
+    fn add_member(&mut self, nick: &str) {
+        if !self.has_member(nick) {
+            self.members.push(nick.to_string());
+            self.members.sort_by_key(|nick| nick.to_ascii_lowercase());
+        }
+    }
+
+    fn remove_member(&mut self, nick: &str) -> bool {
+        let before = self.members.len();
+        self.members
+            .retain(|member| !member.eq_ignore_ascii_case(nick));
+        self.members.len() < before
+    }
+
+    fn has_member(&self, nick: &str) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.eq_ignore_ascii_case(nick))
+    }
+}
+
+/// Leaves the alternate screen when dropped, also when unwinding.
+struct AltScreen;
+
+impl AltScreen {
+    fn enter() -> AltScreen {
+        write("\x1b[?1049h\x1b[2J");
+        AltScreen
+    }
+}
+
+impl Drop for AltScreen {
+    fn drop(&mut self) {
+        write("\x1b[0m\x1b[?25h\x1b[?1049l");
+    }
+}
+
+fn write(text: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+pub async fn run(mut client: Client, addr: &str, color: bool) {
+    let mut tui = Tui {
+        windows: vec![Window::new(SERVER_WINDOW, Kind::Server)],
+        active: 0,
+        editor: Editor::default(),
+        chat: Chat::default(),
+        status: format!("connecting to {addr}"),
+        connected: false,
+        color,
+        drawn: Vec::new(),
+    };
+    let _screen = AltScreen::enter();
+    let mut resized =
+        signal(SignalKind::window_change()).expect("signal handlers can be installed");
+    let mut stdin = tokio::io::stdin();
+    let mut keys = Keys::default();
+    let mut buf = [0; 1024];
+    tui.draw(true);
+    'session: loop {
+        let mut force = false;
+        tokio::select! {
+            read = stdin.read(&mut buf) => {
+                let n = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                for key in keys.feed(&buf[..n]) {
+                    match tui.key(key) {
+                        Some(Input::Send(frame)) => client.send(frame).await,
+                        Some(Input::Quit) => break 'session,
+                        None => {}
+                    }
+                    force |= key == Key::Ctrl('l');
+                }
+            }
+            update = client.next() => {
+                let Some(update) = update else { break };
+                for frame in tui.update(update) {
+                    client.send(frame).await;
+                }
+            }
+            _ = resized.recv() => force = true,
+        }
+        tui.draw(force);
+    }
+    client.close().await;
+}
+
+struct Tui {
+    /// The server window first, then rooms and conversations as they come
+    windows: Vec<Window>,
+    active: usize,
+    editor: Editor,
+    chat: Chat,
+    /// Connection state for the title bar
+    status: String,
+    connected: bool,
+    color: bool,
+    /// Rows on the screen right now
+    drawn: Vec<String>,
+}
+
+impl Tui {
+    fn find(&self, name: &str, kind: Kind) -> Option<usize> {
+        (self.windows.iter())
+            .position(|window| window.kind == kind && window.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The window for `name`, opened if needed.
+    fn open(&mut self, name: &str, kind: Kind) -> usize {
+        self.find(name, kind).unwrap_or_else(|| {
+            self.windows.push(Window::new(name, kind));
+            self.windows.len() - 1
+        })
+    }
+
+    fn close(&mut self, index: usize) {
+        if index == 0 {
+            return;
+        }
+        self.windows.remove(index);
+        if self.active >= index {
+            self.activate(self.active - 1);
+        }
+    }
+
+    fn activate(&mut self, index: usize) {
+        if let Some(window) = self.windows.get_mut(index) {
+            window.unread = 0;
+            window.mentioned = false;
+            self.active = index;
+        }
+    }
+
+    fn active(&mut self) -> &mut Window {
+        &mut self.windows[self.active]
+    }
+
+    /// Add `line` to a window. `message` counts it as unread elsewhere,
+    /// `mention` makes the window stand out.
+    fn push(&mut self, index: usize, line: Line, message: bool, mention: bool) {
+        let active = index == self.active;
+        let window = &mut self.windows[index];
+        if window.lines.len() == SCROLLBACK {
+            window.lines.pop_front();
+        }
+        window.lines.push_back(line);
+        if window.scroll > 0 {
+            // Keep the view where it is
+            window.scroll += 1;
+        }
+        if !active && message {
+            window.unread += 1;
+            window.mentioned |= mention;
+        }
+    }
+
+    fn note(&mut self, style: Style, text: impl Into<String>) {
+        self.push(self.active, Line::plain(style, text), false, false);
+    }
+
+    fn key(&mut self, key: Key) -> Option<Input> {
+        let page = (term::size().1 / 2).max(1);
+        match key {
+            Key::Alt(digit @ '1'..='9') => {
+                self.activate(usize::from(digit as u8 - b'1'));
+                return None;
+            }
+            Key::Alt('n') => {
+                self.activate((self.active + 1) % self.windows.len());
+                return None;
+            }
+            Key::Alt('p') => {
+                let count = self.windows.len();
+                self.activate((self.active + count - 1) % count);
+                return None;
+            }
+            Key::PageUp => {
+                let window = self.active();
+                window.scroll = (window.scroll + page).min(window.lines.len().saturating_sub(1));
+                return None;
+            }
+            Key::PageDown => {
+                let window = self.active();
+                window.scroll = window.scroll.saturating_sub(page);
+                return None;
+            }
+            _ => {}
+        }
+        let chat = &self.chat;
+        match self
+            .editor
+            .key(key, |word, first| chat.completions(word, first))
+        {
+            Action::Submit(line) => self.submit(&line),
+            Action::Quit => Some(Input::Quit),
+            Action::Candidates(candidates) => {
+                self.note(Style::Dim, candidates.join("  "));
+                None
+            }
+            Action::Edited | Action::ClearScreen => None,
+        }
+    }
+
+    /// Run the client's own commands, send the rest to the active window.
+    fn submit(&mut self, line: &str) -> Option<Input> {
+        let command = line.split_whitespace().next().unwrap_or_default();
+        let (name, kind) = (self.active().name.clone(), self.active().kind);
+        match command {
+            "/quit" | "/exit" => return Some(Input::Quit),
+            "/clear" => {
+                self.active().lines.clear();
+                self.active().scroll = 0;
+                return None;
+            }
+            "/close" => {
+                return match kind {
+                    Kind::Server => {
+                        self.note(Style::Error, "the server window stays open");
+                        None
+                    }
+                    Kind::Private => {
+                        self.close(self.active);
+                        None
+                    }
+                    Kind::Room => Some(Input::Send(ClientFrame::Part(Some(name)))),
+                };
+            }
+            "/help" => {
+                for (name, arguments) in COMMANDS {
+                    self.note(Style::Dim, format!("{name} {arguments}"));
+                }
+                self.note(
+                    Style::Dim,
+                    "/close leaves the room or closes the conversation",
+                );
+                self.note(
+                    Style::Dim,
+                    "Alt-1..9 or Alt-n/Alt-p switch windows, PgUp/PgDn scroll, Tab completes",
+                );
+                return None;
+            }
+            "/hello" => {
+                self.note(Style::Error, "chat-client always speaks JSON mode");
+                return None;
+            }
+            _ => {}
+        }
+        let frame = match ClientFrame::parse(line) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return None,
+            Err(err) => {
+                self.note(Style::Error, err.to_string());
+                return None;
+            }
+        };
+        // The server's idea of the current room may not be the window we
+        // are looking at, so name it
+        let room = (kind == Kind::Room).then_some(name.clone());
+        let frame = match frame {
+            ClientFrame::Message { room: None, text } => match kind {
+                Kind::Room => ClientFrame::Message { room, text },
+                Kind::Private => ClientFrame::Msg { to: name, text },
+                Kind::Server => {
+                    self.note(
+                        Style::Error,
+                        "this is the server window, /join a room to talk",
+                    );
+                    return None;
+                }
+            },
+            ClientFrame::Part(None) if room.is_some() => ClientFrame::Part(room),
+            ClientFrame::Names(None) if room.is_some() => ClientFrame::Names(room),
+            ClientFrame::Topic { room: None, topic } if room.is_some() => {
+                ClientFrame::Topic { room, topic }
+            }
+            ClientFrame::History { room: None, count } if room.is_some() => {
+                ClientFrame::History { room, count }
+            }
+            frame => frame,
+        };
+        // The server doesn't echo our own messages
+        let me = self.chat.me.clone().unwrap_or_default();
+        let said = |text: &str| {
+            let now = Stamp::new().ts;
+            let nick = format!("<{me}> ");
+            Line::new(
+                Some(now),
+                vec![(Style::Bold, nick), (Style::Plain, text.to_string())],
+            )
+        };
+        match &frame {
+            ClientFrame::Message {
+                room: Some(room),
+                text,
+            } => {
+                let index = self.open(room, Kind::Room);
+                self.push(index, said(text), false, false);
+            }
+            ClientFrame::Msg { to, text } => {
+                let index = self.open(to, Kind::Private);
+                self.push(index, said(text), false, false);
+                self.activate(index);
+            }
+            _ => {}
+        }
+        Some(Input::Send(frame))
+    }
+
+    /// Follow the connection. Returns frames to send, to fetch the members
+    /// of rooms we just joined.
+    fn update(&mut self, update: Update) -> Vec<ClientFrame> {
+        match update {
+            Update::Connected => {
+                self.chat.reconnected();
+                self.connected = true;
+                self.status = "connected".to_string();
+                self.push(0, Line::plain(Style::Dim, "connected"), false, false);
+            }
+            Update::Frame(frame) => return self.frame(frame),
+            Update::Disconnected { reason, retry_in } => {
+                self.connected = false;
+                self.status = format!("reconnecting in {}s", retry_in.as_secs());
+                for window in &mut self.windows {
+                    window.members.clear();
+                }
+                let text = format!("{reason}, reconnecting in {}s", retry_in.as_secs());
+                self.push(0, Line::plain(Style::Error, text), false, false);
+            }
+            Update::Unsent(_) => self.note(Style::Error, "not connected, that wasn't sent"),
+        }
+        Vec::new()
+    }
+
+    fn frame(&mut self, frame: ServerFrame) -> Vec<ClientFrame> {
+        let joined = self.chat.joined.clone();
+        let current = self.chat.room.clone();
+        self.chat.learn(&frame);
+        let left: Vec<_> = joined.difference(&self.chat.joined).cloned().collect();
+        for room in left {
+            if let Some(index) = self.find(&room, Kind::Room) {
+                self.close(index);
+            }
+        }
+        // Ask who is in rooms we just joined
+        let entered: Vec<_> = self.chat.joined.difference(&joined).cloned().collect();
+        let mut requests = Vec::new();
+        for room in entered {
+            self.open(&room, Kind::Room);
+            requests.push(ClientFrame::Names(Some(room)));
+        }
+        if self.chat.room != current {
+            if let Some(index) =
+                (self.chat.room.clone()).and_then(|room| self.find(&room, Kind::Room))
+            {
+                self.activate(index);
+            }
+        }
+
+        let ts = stamp(&frame).map(|stamp| stamp.ts);
+        match frame {
+            ServerFrame::Message {
+                room, from, text, ..
+            } => {
+                let mention = self.chat.mentions_me(&text);
+                let style = if mention {
+                    Style::Mention
+                } else {
+                    Style::Plain
+                };
+                let nick = (Style::Nick(nick_color(&from)), format!("<{from}> "));
+                let index = self.open(&room, Kind::Room);
+                self.push(
+                    index,
+                    Line::new(ts, vec![nick, (style, text)]),
+                    true,
+                    mention,
+                );
+            }
+            ServerFrame::Private { from, text, .. } => {
+                let nick = (Style::Nick(nick_color(&from)), format!("<{from}> "));
+                let index = self.open(&from, Kind::Private);
+                let line = Line::new(ts, vec![nick, (Style::Private, text)]);
+                self.push(index, line, true, true);
+            }
+            ServerFrame::Event {
+                room: Some(ref room),
+                ref nick,
+                ref event,
+                ..
+            } => {
+                let index = self.open(room, Kind::Room);
+                let window = &mut self.windows[index];
+                match event {
+                    Event::Join => window.add_member(nick),
+                    Event::Part(_) => {
+                        window.remove_member(nick);
+                    }
+                    Event::Nick(_) => {}
+                    Event::Topic(topic) => {
+                        window.topic = (!topic.is_empty()).then(|| topic.clone());
+                    }
+                }
+                let line = Line::new(ts, vec![(Style::Dim, format!("*** {}", text_of(&frame)))]);
+                self.push(index, line, false, false);
+            }
+            ServerFrame::Event {
+                room: None,
+                ref nick,
+                ref event,
+                ..
+            } => {
+                let line = || Line::new(ts, vec![(Style::Dim, format!("*** {}", text_of(&frame)))]);
+                // Show quits and renames where the nick was seen
+                let mut shown = false;
+                for index in 0..self.windows.len() {
+                    let window = &mut self.windows[index];
+                    let seen = match event {
+                        Event::Part(_) => window.remove_member(nick),
+                        Event::Nick(new_nick) => {
+                            let seen = window.remove_member(nick);
+                            if seen {
+                                window.add_member(new_nick);
+                            }
+                            let talking = window.kind == Kind::Private
+                                && window.name.eq_ignore_ascii_case(nick);
+                            if talking {
+                                window.name = new_nick.clone();
+                            }
+                            seen || talking
+                        }
+                        Event::Join | Event::Topic(_) => false,
+                    };
+                    if seen {
+                        self.push(index, line(), false, false);
+                        shown = true;
+                    }
+                }
+                if !shown {
+                    self.push(0, line(), false, false);
+                }
+            }
+            ServerFrame::Notice {
+                room: Some(ref room),
+                ref text,
+                ..
+            } => {
+                let index = self.open(room, Kind::Room);
+                let line = Line::new(ts, vec![(Style::Dim, format!("*** {text}"))]);
+                self.push(index, line, false, false);
+            }
+            ServerFrame::Notice {
+                room: None,
+                ref text,
+                ..
+            } => {
+                let line = Line::new(ts, vec![(Style::Dim, format!("*** {text}"))]);
+                self.push(0, line, false, false);
+            }
+            ServerFrame::Names { room, nicks } => {
+                if let Some(index) = self.find(&room, Kind::Room) {
+                    self.windows[index].members = nicks;
+                }
+            }
+            ServerFrame::Hello { text, .. } => {
+                self.push(0, Line::plain(Style::Dim, text), false, false)
+            }
+            ServerFrame::Ack(text) => self.note(Style::Ack, text),
+            ServerFrame::Error(text) => self.note(Style::Error, text),
+            ServerFrame::Ping(_) => {}
+        }
+        requests
+    }
+
+    /// Bring the screen up to date, rewriting only rows that changed unless
+    /// `force`d to redraw everything.
+    fn draw(&mut self, force: bool) {
+        let (width, height) = term::size();
+        let rows = self.render(width, height.max(3));
+        let mut out = String::from("\x1b[?25l");
+        if force || rows.len() != self.drawn.len() {
+            out.push_str("\x1b[0m\x1b[2J");
+            self.drawn.clear();
+        }
+        for (index, row) in rows.iter().enumerate() {
+            if self.drawn.get(index) != Some(row) {
+                out.push_str(&format!("\x1b[{};1H{row}\x1b[0m\x1b[K", index + 1));
+            }
+        }
+        let (input, cursor) = self.editor.view(&self.prompt(), width);
+        out.push_str(&format!("\x1b[{};1H{input}\x1b[K", rows.len() + 1));
+        out.push_str(&format!("\x1b[{};{}H\x1b[?25h", rows.len() + 1, cursor + 1));
+        write(&out);
+        self.drawn = rows;
+    }
+
+    fn prompt(&self) -> String {
+        format!("{}> ", printable(&self.windows[self.active].title()))
+    }
+
+    /// Every row but the input line: the title bar, then the panes.
+    fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let rooms_width = if width >= ROOMS_MIN_WIDTH {
+            ROOMS_WIDTH
+        } else {
+            0
+        };
+        let users_width = if width >= USERS_MIN_WIDTH {
+            USERS_WIDTH
+        } else {
+            0
+        };
+        let separators = usize::from(rooms_width > 0) + usize::from(users_width > 0);
+        let messages_width = width
+            .saturating_sub(rooms_width + users_width + separators)
+            .max(1);
+        let body = height - 2;
+
+        let window = &self.windows[self.active];
+        let mut title = format!(" {}", window.title());
+        if let Some(topic) = &window.topic {
+            title.push_str(&format!(": {topic}"));
+        }
+        let status = match &self.chat.me {
+            Some(me) if self.connected => format!("{} | {} ", printable(me), self.status),
+            _ => format!("{} ", self.status),
+        };
+        let status_style = if self.connected {
+            Style::Reverse
+        } else {
+            Style::Error
+        };
+        let title_width = width.saturating_sub(term::width(&status));
+        let mut rows = vec![format!(
+            "{}{}",
+            self.paint(Style::Reverse, &fit(&title, title_width)),
+            self.paint(status_style, &status),
+        )];
+
+        let rooms = self.rooms_pane(rooms_width, body);
+        let messages = self.messages_pane(messages_width, body);
+        let users = self.users_pane(users_width, body);
+        let separator = self.paint(Style::Dim, "│");
+        for row in 0..body {
+            let mut line = String::new();
+            if rooms_width > 0 {
+                line.push_str(&rooms[row]);
+                line.push_str(&separator);
+            }
+            line.push_str(&messages[row]);
+            if users_width > 0 {
+                line.push_str(&separator);
+                line.push_str(&users[row]);
+            }
+            rows.push(line);
+        }
+        rows
+    }
+
+    fn rooms_pane(&self, width: usize, height: usize) -> Vec<String> {
+        let mut rows: Vec<_> = (self.windows.iter().enumerate())
+            .map(|(index, window)| {
+                let number = match index {
+                    0..=8 => (index + 1).to_string(),
+                    _ => " ".to_string(),
+                };
+                let unread = match window.unread {
+                    0 => String::new(),
+                    n => format!(" {n}"),
+                };
+                let name_width = width.saturating_sub(2 + unread.len());
+                let text = format!("{number} {}{unread}", fit(&window.title(), name_width));
+                let style = if index == self.active {
+                    Style::Reverse
+                } else if window.mentioned {
+                    Style::Mention
+                } else if window.unread > 0 {
+                    Style::Bold
+                } else {
+                    Style::Plain
+                };
+                self.paint(style, &text)
+            })
+            .collect();
+        rows.truncate(height);
+        rows.resize(height, " ".repeat(width));
+        rows
+    }
+
+    fn users_pane(&self, width: usize, height: usize) -> Vec<String> {
+        let window = &self.windows[self.active];
+        let mut rows = Vec::new();
+        match window.kind {
+            Kind::Room => {
+                let count = format!("{} here", window.members.len());
+                rows.push(self.paint(Style::Dim, &fit(&count, width)));
+                for nick in &window.members {
+                    let style = match &self.chat.me {
+                        Some(me) if me.eq_ignore_ascii_case(nick) => Style::Bold,
+                        _ => Style::Nick(nick_color(nick)),
+                    };
+                    rows.push(self.paint(style, &fit(nick, width)));
+                }
+            }
+            Kind::Private => rows.push(self.paint(Style::Bold, &fit(&window.name, width))),
+            Kind::Server => {}
+        }
+        if rows.len() > height {
+            // Say how many didn't fit instead of the last one
+            let hidden = rows.len() - height + 1;
+            rows.truncate(height - 1);
+            rows.push(self.paint(Style::Dim, &fit(&format!("+{hidden} more"), width)));
+        }
+        rows.resize(height, " ".repeat(width));
+        rows
+    }
+
+    /// The end of the active window's lines, wrapped to `width`, from
+    /// `scroll` lines up.
+    fn messages_pane(&self, width: usize, height: usize) -> Vec<String> {
+        let window = &self.windows[self.active];
+        let mut rows = Vec::new();
+        let mut height = height;
+        if window.scroll > 0 {
+            // Last row says there is more
+            height -= 1;
+        }
+        for line in window.lines.iter().rev().skip(window.scroll) {
+            let wrapped = self.wrap(line, width);
+            rows.extend(wrapped.into_iter().rev());
+            if rows.len() >= height {
+                break;
+            }
+        }
+        rows.truncate(height);
+        rows.reverse();
+        let blank = " ".repeat(width);
+        let mut pane = vec![blank; height - rows.len()];
+        pane.append(&mut rows);
+        if window.scroll > 0 {
+            let more = format!("-- {} more below, PgDn --", window.scroll);
+            pane.push(self.paint(Style::Reverse, &fit(&more, width)));
+        }
+        pane
+    }
+
+    /// `line` in rows of `width` columns, the time in front of the first and
+    /// the others indented to match.
+    fn wrap(&self, line: &Line, width: usize) -> Vec<String> {
+        let clock = line.ts.map(clock).map(|time| format!("{time} "));
+        let indent = clock.as_deref().map_or(0, term::width);
+        let mut chars: Vec<(Style, char)> = Vec::new();
+        if let Some(time) = &clock {
+            chars.extend(time.chars().map(|c| (Style::Dim, c)));
+        }
+        for (style, text) in &line.spans {
+            chars.extend(text.chars().map(|c| (*style, c)));
+        }
+        let mut rows = Vec::new();
+        let mut rest = chars.as_slice();
+        let mut first = true;
+        while first || !rest.is_empty() {
+            let indent = if first { 0 } else { indent.min(width / 2) };
+            let room = width - indent;
+            let mut take = 0;
+            let mut used = 0;
+            for &(_, c) in rest {
+                if used + term::char_width(c) > room {
+                    break;
+                }
+                used += term::char_width(c);
+                take += 1;
+            }
+            if take < rest.len() {
+                // Break after the last space that fits, unless the word is
+                // longer than the row
+                let space = rest[..take].iter().rposition(|&(_, c)| c == ' ');
+                take = space.map_or(take, |space| space + 1);
+                // A wide char in a one column row has to go somewhere
+                take = take.max(1);
+                used = rest[..take].iter().map(|&(_, c)| term::char_width(c)).sum();
+            }
+            let mut row = " ".repeat(indent);
+            row.push_str(&self.spans(&rest[..take]));
+            row.push_str(&" ".repeat(room.saturating_sub(used)));
+            rows.push(row);
+            rest = &rest[take..];
+            first = false;
+        }
+        rows
+    }
+
+    /// Styled chars as text with SGR sequences between style changes.
+    fn spans(&self, chars: &[(Style, char)]) -> String {
+        let mut out = String::new();
+        let mut current = Style::Plain;
+        for &(style, c) in chars {
+            if style != current {
+                out.push_str("\x1b[0m");
+                let sgr = style.sgr(self.color);
+                if !sgr.is_empty() {
+                    out.push_str(&format!("\x1b[{sgr}m"));
+                }
+                current = style;
+            }
+            out.push(c);
+        }
+        if current != Style::Plain {
+            out.push_str("\x1b[0m");
+        }
+        out
+    }
+
+    fn paint(&self, style: Style, text: &str) -> String {
+        match style.sgr(self.color) {
+            "" => text.to_string(),
+            sgr => format!("\x1b[{sgr}m{text}\x1b[0m"),
+        }
+    }
+}
+
+/// `text` cut or padded to exactly `width` columns, with its control
+/// characters shown.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut used = 0;
+    for c in printable(text).chars() {
+        if used + term::char_width(c) > width {
+            break;
+        }
+        used += term::char_width(c);
+        fitted.push(c);
+    }
+    fitted.push_str(&" ".repeat(width - used));
+    fitted
+}
+
+/// What an event says, without the room in front.
+fn text_of(frame: &ServerFrame) -> String {
+    let text = frame.to_string();
+    match text.split_once("*** ") {
+        Some((_, text)) => text.to_string(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tui() -> Tui {
+        Tui {
+            windows: vec![Window::new(SERVER_WINDOW, Kind::Server)],
+            active: 0,
+            editor: Editor::default(),
+            chat: Chat::default(),
+            status: String::new(),
+            connected: false,
+            color: false,
+            drawn: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fits_columns() {
+        assert_eq!(fit("abc", 5), "abc  ");
+        assert_eq!(fit("abcdef", 4), "abcd");
+        assert_eq!(fit("日本語", 5), "日本 ");
+        assert_eq!(fit("e\u{301}t\u{e9}", 2), "e\u{301}t");
+        assert_eq!(fit("#a\x1b[2Jb", 8), "#a^[[2Jb");
+    }
+
+    #[test]
+    fn lines_show_control_characters() {
+        let line = Line::new(
+            None,
+            vec![(Style::Plain, "hi\x1b]0;x\x07\u{9b}".to_string())],
+        );
+        assert_eq!(line.spans[0].1, "hi^[]0;x^G\u{fffd}");
+        let line = Line::plain(Style::Dim, "\r\n");
+        assert_eq!(line.spans[0].1, "^M^J");
+    }
+
+    #[test]
+    fn wraps_by_columns() {
+        let tui = tui();
+        let line = Line::plain(Style::Plain, "日本語のテキスト ok");
+        let rows = tui.wrap(&line, 7);
+        assert_eq!(rows, ["日本語 ", "のテキ ", "スト ok"]);
+        assert!(rows.iter().all(|row| term::width(row) == 7));
+
+        let line = Line::plain(Style::Plain, "one two three");
+        assert_eq!(tui.wrap(&line, 8), ["one two ", "three   "]);
+        let line = Line::plain(Style::Plain, "宽");
+        assert_eq!(tui.wrap(&line, 1), ["宽"]);
+    }
+}
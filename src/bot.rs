@@ -0,0 +1,517 @@
+//! Bots: users that live inside the server instead of behind a socket. A bot
+//! takes a nickname, sits in rooms like everyone else and answers `!commands`
+//! said in its rooms or sent to it privately:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use rust_tokio_chat_server::{Bot, ServerHandle};
+//!
+//! # async fn example(handle: ServerHandle) -> Result<(), rust_tokio_chat_server::BotError> {
+//! let bot = Bot::new("deploybot")
+//!     .join("#ops")
+//!     .command("deploy", "!deploy <service>: ship it", |cmd| async move {
+//!         Some(format!("{}: deploying {}", cmd.from, cmd.args))
+//!     })
+//!     .every(Duration::from_secs(24 * 3600), "#ops", "reminder: no deploys on Fridays");
+//! let bot = handle.spawn_bot(bot).await?;
+//! // Bots can also be told what to say from outside, e.g. by a CI hook
+//! bot.say("#ops", "build 1234 passed").await?;
+//! # Ok(())
+//! # }
+//! ```
+//! `!help` is built in and lists the commands with their help lines.
+
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time,
+};
+
+use crate::{
+    connection::{publish, MAILBOX_CAPACITY},
+    plugin::{BoxFuture, Flow},
+    protocol::{ServerFrame, Stamp},
+    rooms::RoomError,
+    state::State,
+    users::{NickError, Registration},
+};
+
+/// What a line has to start with to be a command
+pub const COMMAND_PREFIX: char = '!';
+
+/// Lines waiting to be said by a bot before `BotHandle::say` waits
+const REQUESTS_CAPACITY: usize = 32;
+
+/// Bots get addresses in 0.0.0.0/8, which no client can connect from. The
+/// low 16 bits are the port, the rest go into the address. Port 0 on
+/// 0.0.0.0 is the server's own, so numbers ending in port 0 are skipped.
+static NEXT_BOT: AtomicU32 = AtomicU32::new(1);
+
+type Handler = Arc<dyn Fn(Command) -> BoxFuture<'static, Option<String>> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum BotError {
+    Nick(NickError),
+    Room(RoomError),
+    /// A plugin dropped the bot's connect or one of its joins
+    Refused,
+    /// Every bot address was handed out already
+    Exhausted,
+    /// The bot or its server isn't running anymore
+    Stopped,
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Nick(err) => write!(f, "{err}"),
+            BotError::Room(err) => write!(f, "{err}"),
+            BotError::Refused => write!(f, "refused by a plugin"),
+            BotError::Exhausted => write!(f, "no addresses left for bots"),
+            BotError::Stopped => write!(f, "bot is not running"),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+/// A `!command` someone sent to a bot.
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// Nick of whoever sent it
+    pub from: String,
+    /// Where it was said, `None` for private messages. Replies go back there.
+    pub room: Option<String>,
+    /// Lowercase, without the `!`
+    pub name: String,
+    /// The rest of the line, trimmed
+    pub args: String,
+}
+
+impl Command {
+    /// `!name args`, `None` for lines that aren't commands.
+    pub fn parse(from: &str, room: Option<&str>, text: &str) -> Option<Command> {
+        let line = text.trim_start().strip_prefix(COMMAND_PREFIX)?;
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if name.is_empty() {
+            return None;
+        }
+        Some(Command {
+            from: from.to_string(),
+            room: room.map(str::to_string),
+            name: name.to_ascii_lowercase(),
+            args: args.trim().to_string(),
+        })
+    }
+}
+
+struct CommandSpec {
+    name: String,
+    help: String,
+    handler: Handler,
+}
+
+struct Schedule {
+    delay: Duration,
+    /// `None` to say it only once
+    every: Option<Duration>,
+    room: String,
+    text: String,
+}
+
+/// Describes a bot, `ServerHandle::spawn_bot` brings it to life.
+pub struct Bot {
+    nick: String,
+    rooms: Vec<String>,
+    commands: Vec<CommandSpec>,
+    schedules: Vec<Schedule>,
+}
+
+impl Bot {
+    pub fn new(nick: impl Into<String>) -> Bot {
+        Bot {
+            nick: nick.into(),
+            rooms: Vec::new(),
+            commands: Vec::new(),
+            schedules: Vec::new(),
+        }
+    }
+
+    /// Join `room` when the bot starts.
+    pub fn join(mut self, room: impl Into<String>) -> Self {
+        self.rooms.push(room.into());
+        self
+    }
+
+    /// Answer `!name` with `handler`. A reply goes to where the command was
+    /// said, each line as its own message. `help` is what `!help` shows.
+    /// Handlers run in their own task, a slow one doesn't hold up the bot.
+    pub fn command<F, Fut>(mut self, name: &str, help: &str, handler: F) -> Self
+    where
+        F: Fn(Command) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<String>> + Send + 'static,
+    {
+        self.commands.push(CommandSpec {
+            name: name.trim_start_matches(COMMAND_PREFIX).to_ascii_lowercase(),
+            help: help.to_string(),
+            handler: Arc::new(move |command| Box::pin(handler(command))),
+        });
+        self
+    }
+
+    /// Say `text` in `room` every `period`, the first time one period after
+    /// the bot started.
+    pub fn every(mut self, period: Duration, room: &str, text: &str) -> Self {
+        self.schedules.push(Schedule {
+            delay: period,
+            every: Some(period),
+            room: room.to_string(),
+            text: text.to_string(),
+        });
+        self
+    }
+
+    /// Say `text` in `room` once, `delay` after the bot started.
+    pub fn after(mut self, delay: Duration, room: &str, text: &str) -> Self {
+        self.schedules.push(Schedule {
+            delay,
+            every: None,
+            room: room.to_string(),
+            text: text.to_string(),
+        });
+        self
+    }
+}
+
+/// Where a bot's line goes.
+enum Target {
+    Room(String),
+    Nick(String),
+}
+
+enum Request {
+    Say(Target, String),
+    Stop,
+}
+
+/// Talks to a running bot. Dropping it leaves the bot running, it stops
+/// with the server or on `stop`.
+#[derive(Clone)]
+pub struct BotHandle {
+    requests: mpsc::Sender<Request>,
+}
+
+impl BotHandle {
+    /// Say `text` in `room`, joining it first if the bot isn't there.
+    pub async fn say(&self, room: &str, text: &str) -> Result<(), BotError> {
+        self.send(Request::Say(
+            Target::Room(room.to_string()),
+            text.to_string(),
+        ))
+        .await
+    }
+
+    /// Send `text` to `nick` privately.
+    pub async fn tell(&self, nick: &str, text: &str) -> Result<(), BotError> {
+        self.send(Request::Say(
+            Target::Nick(nick.to_string()),
+            text.to_string(),
+        ))
+        .await
+    }
+
+    /// Leave the server. Lines said before are still sent.
+    pub async fn stop(&self) -> Result<(), BotError> {
+        self.send(Request::Stop).await
+    }
+
+    async fn send(&self, request: Request) -> Result<(), BotError> {
+        (self.requests.send(request).await).map_err(|_| BotError::Stopped)
+    }
+}
+
+/// A fresh address for a bot, never the server's.
+fn next_addr() -> Result<SocketAddr, BotError> {
+    loop {
+        let next = NEXT_BOT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1))
+            .map_err(|_| BotError::Exhausted)?;
+        let port = next as u16;
+        if port != 0 {
+            let ip = Ipv4Addr::from(next >> 16);
+            return Ok(SocketAddr::new(IpAddr::V4(ip), port));
+        }
+    }
+}
+
+/// Register the bot and join its rooms, then run it in the background. The
+/// plugins see the bot connect and join like any other user, their replies
+/// are dropped since nobody reads them.
+pub async fn spawn(bot: Bot, state: Arc<State>) -> Result<BotHandle, BotError> {
+    let addr = next_addr()?;
+    let (mailbox_send, mailbox) = mpsc::channel(MAILBOX_CAPACITY);
+    let mut user =
+        Registration::register(&bot.nick, addr, mailbox_send, &state).map_err(BotError::Nick)?;
+    if state.plugins.connect(&user, &state).await.flow == Flow::Drop {
+        user.leave("refused");
+        return Err(BotError::Refused);
+    }
+    for room in &bot.rooms {
+        if !state.rooms.is_member(room, user.addr)
+            && state.plugins.join(&user, room, &state).await.flow == Flow::Drop
+        {
+            user.leave("refused");
+            return Err(BotError::Refused);
+        }
+        user.join(room).map_err(BotError::Room)?;
+    }
+    let (requests_send, requests) = mpsc::channel(REQUESTS_CAPACITY);
+    for schedule in bot.schedules {
+        tokio::spawn(schedule.run(requests_send.clone()));
+    }
+    info!("bot {} started", user.nick);
+    let running = Running {
+        commands: bot.commands,
+        user,
+        requests_send: requests_send.clone(),
+        state,
+    };
+    tokio::spawn(running.run(mailbox, requests));
+    Ok(BotHandle {
+        requests: requests_send,
+    })
+}
+
+impl Schedule {
+    async fn run(self, requests: mpsc::Sender<Request>) {
+        let mut delay = self.delay;
+        loop {
+            time::sleep(delay).await;
+            let say = Request::Say(Target::Room(self.room.clone()), self.text.clone());
+            // Closed once the bot stopped
+            if requests.send(say).await.is_err() {
+                return;
+            }
+            match self.every {
+                Some(every) => delay = every,
+                None => return,
+            }
+        }
+    }
+}
+
+struct Running {
+    commands: Vec<CommandSpec>,
+    user: Registration,
+    /// For command handlers to send their replies back
+    requests_send: mpsc::Sender<Request>,
+    state: Arc<State>,
+}
+
+impl Running {
+    async fn run(
+        mut self,
+        mut mailbox: mpsc::Receiver<ServerFrame>,
+        mut requests: mpsc::Receiver<Request>,
+    ) {
+        let state = self.state.clone();
+        let mut bus = state.channel_send.subscribe();
+        loop {
+            tokio::select! {
+                received = bus.recv() => match received {
+                    Ok(msg) if msg.is_for(self.user.addr, &state.rooms) => {
+                        if let ServerFrame::Message { room, from, text, .. } = msg.frame {
+                            self.heard(&from, Some(&room), &text).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("bot {} missed {missed} messages", self.user.nick);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                Some(frame) = mailbox.recv() => {
+                    if let ServerFrame::Private { from, text, .. } = frame {
+                        self.heard(&from, None, &text).await;
+                    }
+                }
+                // Never `None`, we hold a sender ourselves
+                Some(request) = requests.recv() => match request {
+                    Request::Say(target, text) => self.say(target, &text).await,
+                    Request::Stop => break,
+                },
+                () = state.shutting_down() => break,
+            }
+        }
+        info!("bot {} stopped", self.user.nick);
+    }
+
+    /// Someone said `text` where the bot could hear it.
+    async fn heard(&mut self, from: &str, room: Option<&str>, text: &str) {
+        let Some(command) = Command::parse(from, room, text) else {
+            return;
+        };
+        let target = match &command.room {
+            Some(room) => Target::Room(room.clone()),
+            None => Target::Nick(command.from.clone()),
+        };
+        if command.name == "help" {
+            let help = self.help(&command.args);
+            self.say(target, &help).await;
+            return;
+        }
+        let Some(spec) = self.commands.iter().find(|spec| spec.name == command.name) else {
+            // Other bots in the room may know it, only answer in private
+            if command.room.is_none() {
+                let unknown = format!("unknown command !{}, try !help", command.name);
+                self.say(target, &unknown).await;
+            }
+            return;
+        };
+        let reply = (spec.handler)(command);
+        let requests = self.requests_send.clone();
+        tokio::spawn(async move {
+            if let Some(text) = reply.await {
+                let _ = requests.send(Request::Say(target, text)).await;
+            }
+        });
+    }
+
+    /// `!help` lists all commands, `!help name` shows one.
+    fn help(&self, topic: &str) -> String {
+        let topic = topic
+            .trim_start_matches(COMMAND_PREFIX)
+            .to_ascii_lowercase();
+        if !topic.is_empty() {
+            return match self.commands.iter().find(|spec| spec.name == topic) {
+                Some(spec) => spec.help.clone(),
+                None => format!("unknown command !{topic}"),
+            };
+        }
+        let mut lines = vec![format!("{} knows:", self.user.nick)];
+        lines.extend(self.commands.iter().map(|spec| spec.help.clone()));
+        lines.push("!help [command]: this list".to_string());
+        lines.join("\n")
+    }
+
+    /// Send each line of `text` as its own message.
+    async fn say(&mut self, target: Target, text: &str) {
+        for line in text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+        {
+            let said = match &target {
+                Target::Room(room) => self.say_in(room, line).await,
+                Target::Nick(nick) => {
+                    let frame = ServerFrame::Private {
+                        stamp: Stamp::new(),
+                        from: self.user.nick.clone(),
+                        text: line.to_string(),
+                    };
+                    (self.state.users.deliver(nick, frame))
+                        .map(drop)
+                        .map_err(|err| err.to_string())
+                }
+            };
+            if let Err(err) = said {
+                warn!("bot {} cannot send a message: {err}", self.user.nick);
+                return;
+            }
+        }
+    }
+
+    async fn say_in(&mut self, room: &str, line: &str) -> Result<(), String> {
+        let joined = self.state.rooms.joined(self.user.addr);
+        let room = match joined.into_iter().find(|r| r.eq_ignore_ascii_case(room)) {
+            Some(room) => room,
+            None => {
+                let hooked = self.state.plugins.join(&self.user, room, &self.state).await;
+                if hooked.flow == Flow::Drop {
+                    return Err(BotError::Refused.to_string());
+                }
+                self.user.join(room).map_err(|err| err.to_string())?;
+                self.user.room.clone().unwrap_or_default()
+            }
+        };
+        // Plugin replies are for a person reading along, bots don't
+        publish(room, line.to_string(), &self.user, &self.state)
+            .await
+            .map(drop)
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{
+        access::Access,
+        config::Config,
+        plugin::{ChatPlugin, Context, Plugins},
+        state::SERVER_ADDR,
+    };
+
+    #[test]
+    fn addresses_skip_port_zero() {
+        NEXT_BOT.store(0x2_fffe, Ordering::Relaxed);
+        let addrs: Vec<_> = (0..4).map(|_| next_addr().unwrap()).collect();
+        for addr in &addrs {
+            assert_ne!(addr.port(), 0);
+            assert_ne!(*addr, SERVER_ADDR);
+            assert!(matches!(addr.ip(), IpAddr::V4(ip) if ip.octets()[0] == 0));
+        }
+        assert!(addrs.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[derive(Default)]
+    struct Doorman {
+        connects: AtomicUsize,
+    }
+
+    impl ChatPlugin for Arc<Doorman> {
+        fn on_connect<'a>(&'a self, _: &'a mut Context) -> BoxFuture<'a, Flow> {
+            self.connects.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Flow::Continue })
+        }
+
+        fn on_join<'a>(&'a self, _: &'a mut Context, room: &'a str) -> BoxFuture<'a, Flow> {
+            Box::pin(async move {
+                match room {
+                    "#secret" => Flow::Drop,
+                    _ => Flow::Continue,
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn plugins_see_bots() {
+        let doorman = Arc::new(Doorman::default());
+        let mut plugins = Plugins::default();
+        plugins.push(Box::new(doorman.clone()));
+        let access = Access::load(None).unwrap();
+        let state = Arc::new(State::new(Config::default(), None, access, plugins));
+
+        let refused = spawn(Bot::new("spy").join("#secret"), state.clone()).await;
+        assert!(matches!(refused, Err(BotError::Refused)));
+        assert_eq!(doorman.connects.load(Ordering::Relaxed), 1);
+
+        let bot = spawn(Bot::new("spy").join("#lobby"), state.clone()).await;
+        assert!(bot.is_ok(), "the nick was released");
+        assert_eq!(doorman.connects.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,312 @@
+//! The client side of the protocol, for `chat-client` and anything else that
+//! wants to talk to a server: connects, switches to JSON mode and hands out
+//! typed frames. When the connection drops it connects again with growing
+//! pauses and restores the session: the nick (or login) and the rooms.
+//! ```no_run
+//! use rust_tokio_chat_server::client::{Client, ClientFrame, ServerFrame, Update};
+//!
+//! # async fn example() {
+//! let mut client = Client::connect("127.0.0.1:8080");
+//! client.send(ClientFrame::Nick("alice".to_string())).await;
+//! while let Some(update) = client.next().await {
+//!     if let Update::Frame(ServerFrame::Message { room, from, text, .. }) = update {
+//!         println!("[{room}] <{from}> {text}");
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time,
+};
+
+use crate::json::Value;
+pub use crate::protocol::{ClientFrame, Event, Mode, ServerFrame, Stamp};
+
+/// Pause before the first reconnect, doubled after every failed attempt up
+/// to the maximum
+pub const RECONNECT_MIN: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// Frames typed ahead, and updates not picked up yet
+const QUEUE_CAPACITY: usize = 64;
+
+/// How long `Client::close` waits for what was sent to be written
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What happened on the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    /// Connected, the nick and rooms are being restored
+    Connected,
+    /// Anything the server said. Pings are answered and not passed on.
+    Frame(ServerFrame),
+    /// The connection was lost or couldn't be made, next try after
+    /// `retry_in`
+    Disconnected { reason: String, retry_in: Duration },
+    /// Sent while disconnected, so the server never got it. Nick, login and
+    /// room changes are kept for the next connection instead.
+    Unsent(ClientFrame),
+}
+
+/// A connection to a server that keeps reconnecting until it is dropped.
+pub struct Client {
+    frames: mpsc::Sender<ClientFrame>,
+    updates: mpsc::Receiver<Update>,
+}
+
+impl Client {
+    /// Start connecting to `addr` (`host:port`) in the background.
+    pub fn connect(addr: impl Into<String>) -> Client {
+        let (frames_send, frames) = mpsc::channel(QUEUE_CAPACITY);
+        let (updates_send, updates) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(addr.into(), frames, updates_send));
+        Client {
+            frames: frames_send,
+            updates,
+        }
+    }
+
+    /// Send `frame` once connected. `Hello` is ignored, the client has to
+    /// stay in JSON mode.
+    pub async fn send(&self, frame: ClientFrame) {
+        if !matches!(frame, ClientFrame::Hello(_)) {
+            // The task only stops when we are dropped
+            let _ = self.frames.send(frame).await;
+        }
+    }
+
+    /// The next thing that happened. Never `None` while the client exists.
+    pub async fn next(&mut self) -> Option<Update> {
+        self.updates.recv().await
+    }
+
+    /// Disconnect once the frames sent so far are written. Dropping the
+    /// client instead may lose them. Frames sent while disconnected are
+    /// lost either way, and a stuck connection gets a few seconds.
+    pub async fn close(self) {
+        let Client {
+            frames,
+            mut updates,
+        } = self;
+        drop(frames);
+        // The task ends once the queue is empty. Keep taking its updates so
+        // it never waits for room in the meantime.
+        let drained = async { while updates.recv().await.is_some() {} };
+        let _ = time::timeout(CLOSE_TIMEOUT, drained).await;
+    }
+}
+
+/// What to send again after reconnecting.
+#[derive(Debug, Default)]
+struct Session {
+    /// The last `Nick` or `Login`
+    identity: Option<ClientFrame>,
+    /// Joined rooms, the current one last
+    rooms: Vec<String>,
+}
+
+impl Session {
+    /// Remember what `frame` changes about the session.
+    fn follow(&mut self, frame: &ClientFrame) {
+        match frame {
+            ClientFrame::Nick(_) | ClientFrame::Login { .. } => {
+                self.identity = Some(frame.clone());
+            }
+            // The account exists after this, so log in to it next time
+            ClientFrame::Register { nick, password } => {
+                self.identity = Some(ClientFrame::Login {
+                    nick: nick.clone(),
+                    password: password.clone(),
+                });
+            }
+            ClientFrame::Join(room) => {
+                self.rooms.retain(|r| !r.eq_ignore_ascii_case(room));
+                self.rooms.push(room.clone());
+            }
+            ClientFrame::Part(Some(room)) => self.rooms.retain(|r| !r.eq_ignore_ascii_case(room)),
+            ClientFrame::Part(None) => {
+                self.rooms.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Worth keeping for the next connection rather than reporting unsent.
+    fn keeps(frame: &ClientFrame) -> bool {
+        matches!(
+            frame,
+            ClientFrame::Nick(_)
+                | ClientFrame::Login { .. }
+                | ClientFrame::Join(_)
+                | ClientFrame::Part(_)
+        )
+    }
+
+    /// Frames that bring a new connection to where the old one was.
+    fn restore(&self) -> Vec<ClientFrame> {
+        let rooms = self.rooms.iter().cloned().map(ClientFrame::Join);
+        self.identity.clone().into_iter().chain(rooms).collect()
+    }
+}
+
+/// Why a connection ended.
+enum Ended {
+    /// `Client` was dropped
+    Dropped,
+    Lost {
+        reason: String,
+        /// The server greeted us, so it wasn't a refusal
+        greeted: bool,
+    },
+}
+
+async fn run(addr: String, mut frames: mpsc::Receiver<ClientFrame>, updates: mpsc::Sender<Update>) {
+    let mut session = Session::default();
+    let mut backoff = RECONNECT_MIN;
+    loop {
+        let ended = match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                if updates.send(Update::Connected).await.is_err() {
+                    return;
+                }
+                connection(stream, &mut session, &mut frames, &updates).await
+            }
+            Err(err) => Ended::Lost {
+                reason: format!("cannot connect to {addr}: {err}"),
+                greeted: false,
+            },
+        };
+        let (reason, greeted) = match ended {
+            Ended::Dropped => return,
+            Ended::Lost { reason, greeted } => (reason, greeted),
+        };
+        if greeted {
+            backoff = RECONNECT_MIN;
+        }
+        let lost = Update::Disconnected {
+            reason,
+            retry_in: backoff,
+        };
+        if updates.send(lost).await.is_err() {
+            return;
+        }
+        let retry = time::sleep(backoff);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                () = &mut retry => break,
+                frame = frames.recv() => {
+                    let Some(frame) = frame else { return };
+                    // Only what is sent on reconnecting may change the
+                    // session: a `Register` that never went out made no account
+                    if Session::keeps(&frame) {
+                        session.follow(&frame);
+                    } else if updates.send(Update::Unsent(frame)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    }
+}
+
+/// Speak JSON lines on `stream` until it closes or the client is dropped.
+async fn connection(
+    stream: TcpStream,
+    session: &mut Session,
+    frames: &mut mpsc::Receiver<ClientFrame>,
+    updates: &mpsc::Sender<Update>,
+) -> Ended {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut greeted = false;
+    let lost = |err: io::Error, greeted| Ended::Lost {
+        reason: err.to_string(),
+        greeted,
+    };
+    let hello = std::iter::once(ClientFrame::Hello(Mode::Json));
+    for frame in hello.chain(session.restore()) {
+        if let Err(err) = write(&mut writer, &frame).await {
+            return lost(err, greeted);
+        }
+    }
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let frame = match line {
+                    Ok(Some(line)) => parse(&line),
+                    Ok(None) => return Ended::Lost {
+                        reason: "server closed the connection".to_string(),
+                        greeted,
+                    },
+                    Err(err) => return lost(err, greeted),
+                };
+                match frame {
+                    Some(ServerFrame::Ping(token)) => {
+                        if let Err(err) = write(&mut writer, &ClientFrame::Pong(token)).await {
+                            return lost(err, greeted);
+                        }
+                        continue;
+                    }
+                    Some(ServerFrame::Hello { .. }) => greeted = true,
+                    // Our own `/hello json` being confirmed
+                    Some(ServerFrame::Ack(ref text)) if text.starts_with("switched to") => continue,
+                    _ => {}
+                }
+                let Some(frame) = frame else { continue };
+                if updates.send(Update::Frame(frame)).await.is_err() {
+                    return Ended::Dropped;
+                }
+            }
+            frame = frames.recv() => {
+                let Some(frame) = frame else {
+                    // Closed: tell the server we are done and let it hang up,
+                    // so nothing we wrote is cut off by a reset
+                    let _ = writer.shutdown().await;
+                    while let Ok(Some(_)) = lines.next_line().await {}
+                    return Ended::Dropped;
+                };
+                session.follow(&frame);
+                if let Err(err) = write(&mut writer, &frame).await {
+                    return lost(err, greeted);
+                }
+            }
+        }
+    }
+}
+
+async fn write(writer: &mut (impl AsyncWriteExt + Unpin), frame: &ClientFrame) -> io::Result<()> {
+    let line = match frame {
+        // Sent before the server knows we want JSON
+        ClientFrame::Hello(mode) => format!("/hello {mode}\n"),
+        frame => format!("{}\n", frame.to_json()),
+    };
+    writer.write_all(line.as_bytes()).await
+}
+
+/// A JSON line, or one of the few text lines the server sends before the
+/// switch: the greeting, and the error it refuses connections with.
+fn parse(line: &str) -> Option<ServerFrame> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    if let Ok(value) = Value::parse(line) {
+        return ServerFrame::from_json(&value);
+    }
+    if let Some(rest) = line.strip_prefix("CHAT/") {
+        let (version, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        return Some(ServerFrame::Hello {
+            version: version.parse().ok()?,
+            text: text.to_string(),
+        });
+    }
+    Some(match line.strip_prefix("-ERR ") {
+        Some(text) => ServerFrame::Error(text.to_string()),
+        None => ServerFrame::notice(line.trim_start_matches("*** ")),
+    })
+}
@@ -0,0 +1,321 @@
+//! The small part of `tokio_util::codec` this server needs: `Decoder` and
+//! `Encoder` traits with the same shape, plus framed readers and writers
+//! that drive them over tokio's `AsyncRead` / `AsyncWrite`.
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::ProtocolError;
+
+/// Turns bytes from the peer into frames.
+pub trait Decoder {
+    type Item;
+    type Error: From<io::Error>;
+
+    /// Decode one frame from the front of `src`, or return `Ok(None)` if more
+    /// bytes are needed.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Called once the peer closed its side, with whatever is left over.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+/// Turns frames into bytes for the peer.
+pub trait Encoder<Item> {
+    type Error: From<io::Error>;
+
+    fn encode(&mut self, item: Item, dst: &mut BytesMut) -> Result<(), Self::Error>;
+}
+
+const INITIAL_CAPACITY: usize = 8 * 1024;
+
+/// Splits bytes into `\n` terminated lines for line based decoders, like
+/// the framing half of `tokio_util`'s `LinesCodec`.
+///
+/// A line longer than `max_length` is reported once with
+/// `ProtocolError::LineTooLong` and the rest of it is skipped, so memory use
+/// stays bounded even if the peer never sends a newline.
+#[derive(Debug)]
+pub struct Lines {
+    max_length: usize,
+    /// How far `src` was already searched for a newline
+    next_index: usize,
+    /// Skipping the tail of an over-long line
+    discarding: bool,
+}
+
+impl Lines {
+    pub fn new(max_length: usize) -> Lines {
+        Lines {
+            max_length,
+            next_index: 0,
+            discarding: false,
+        }
+    }
+
+    /// Next complete line from the front of `src`, without its `\n`.
+    pub fn next_line(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ProtocolError> {
+        loop {
+            let read_to = src.len().min(self.max_length.saturating_add(1));
+            let newline = src[self.next_index..read_to]
+                .iter()
+                .position(|b| *b == b'\n');
+            match (self.discarding, newline) {
+                (true, Some(offset)) => {
+                    src.advance(self.next_index + offset + 1);
+                    self.discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    src.advance(read_to);
+                    self.next_index = 0;
+                    if src.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(offset)) => {
+                    let mut line = src.split_to(self.next_index + offset + 1);
+                    line.truncate(line.len() - 1);
+                    self.next_index = 0;
+                    return Ok(Some(line));
+                }
+                (false, None) if src.len() > self.max_length => {
+                    self.discarding = true;
+                    return Err(ProtocolError::LineTooLong(self.max_length));
+                }
+                (false, None) => {
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// At the end of the stream: whatever is left, unless it is empty or the
+    /// tail of a line that was too long. Call `next_line` until it returns
+    /// `None` first.
+    pub fn last_line(&mut self, src: &mut BytesMut) -> Option<BytesMut> {
+        let rest = src.split();
+        self.next_index = 0;
+        (!self.discarding && !rest.is_empty()).then_some(rest)
+    }
+}
+
+/// Reads frames from `reader` with `decoder`.
+pub struct FramedRead<R, D> {
+    reader: R,
+    decoder: D,
+    buffer: BytesMut,
+    /// Most bytes kept waiting for the decoder
+    max_buffer: usize,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin, D: Decoder> FramedRead<R, D> {
+    /// `max_buffer` caps the bytes read ahead of the decoder. A decoder that
+    /// still wants more once that many are buffered gets the stream ended
+    /// with an `OutOfMemory` error.
+    pub fn new(reader: R, decoder: D, max_buffer: usize) -> Self {
+        FramedRead {
+            reader,
+            decoder,
+            buffer: BytesMut::with_capacity(INITIAL_CAPACITY.min(max_buffer)),
+            max_buffer,
+            eof: false,
+        }
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    /// Next frame, or `None` once the peer is gone and everything it sent
+    /// was decoded. Unlike `tokio_util`, a decode error doesn't end the
+    /// stream: it's up to the caller to decide whether it is fatal.
+    ///
+    /// Cancel safe, so it can be used in `select!`.
+    pub async fn next(&mut self) -> Option<Result<D::Item, D::Error>> {
+        loop {
+            if self.eof {
+                return match self.decoder.decode_eof(&mut self.buffer) {
+                    Ok(Some(frame)) => Some(Ok(frame)),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err)),
+                };
+            }
+            match self.decoder.decode(&mut self.buffer) {
+                Ok(Some(frame)) => return Some(Ok(frame)),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+            let spare = self.max_buffer.saturating_sub(self.buffer.len());
+            if spare == 0 {
+                let full = io::Error::new(io::ErrorKind::OutOfMemory, "read buffer is full");
+                return Some(Err(full.into()));
+            }
+            self.buffer.reserve(spare.min(INITIAL_CAPACITY));
+            let mut reader = (&mut self.reader).take(spare as u64);
+            match reader.read_buf(&mut self.buffer).await {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+/// Writes frames to `writer` with `encoder`.
+pub struct FramedWrite<W, E> {
+    writer: W,
+    encoder: E,
+    buffer: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin, E> FramedWrite<W, E> {
+    pub fn new(writer: W, encoder: E) -> Self {
+        FramedWrite {
+            writer,
+            encoder,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// Encode `item` and write it out in full.
+    pub async fn send<Item>(&mut self, item: Item) -> Result<(), E::Error>
+    where
+        E: Encoder<Item>,
+    {
+        self.encoder.encode(item, &mut self.buffer)?;
+        let written = self.writer.write_all(&self.buffer).await;
+        self.buffer.clear();
+        Ok(written?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &mut Lines, src: &mut BytesMut) -> Vec<Result<String, String>> {
+        let mut found = Vec::new();
+        loop {
+            match lines.next_line(src) {
+                Ok(Some(line)) => found.push(Ok(String::from_utf8(line.to_vec()).unwrap())),
+                Ok(None) => return found,
+                Err(err) => found.push(Err(err.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn splits_lines_and_waits_for_the_rest() {
+        let mut codec = Lines::new(16);
+        let mut src = BytesMut::from("one\ntwo\r\nthr");
+        assert_eq!(
+            lines(&mut codec, &mut src),
+            [Ok("one".into()), Ok("two\r".into())]
+        );
+        assert_eq!(&src[..], b"thr");
+        src.extend_from_slice(b"ee\n");
+        assert_eq!(lines(&mut codec, &mut src), [Ok("three".into())]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn skips_the_rest_of_an_over_long_line() {
+        let mut codec = Lines::new(4);
+        let mut src = BytesMut::from("0123456");
+        let too_long = Err("line is longer than 4 bytes".to_string());
+        assert_eq!(lines(&mut codec, &mut src), [too_long]);
+        // Whatever arrives before the newline belongs to the same line
+        src.extend_from_slice(b"789");
+        assert_eq!(lines(&mut codec, &mut src), []);
+        assert!(src.is_empty());
+        src.extend_from_slice(b"9\nok\n");
+        assert_eq!(lines(&mut codec, &mut src), [Ok("ok".into())]);
+    }
+
+    #[test]
+    fn a_line_of_exactly_max_length_fits() {
+        let mut codec = Lines::new(4);
+        let mut src = BytesMut::from("1234\n12345\n");
+        let too_long = Err("line is longer than 4 bytes".to_string());
+        assert_eq!(lines(&mut codec, &mut src), [Ok("1234".into()), too_long]);
+    }
+
+    #[test]
+    fn last_line_is_the_unterminated_tail() {
+        let mut codec = Lines::new(8);
+        let mut src = BytesMut::from("done\ntail");
+        assert_eq!(lines(&mut codec, &mut src), [Ok("done".into())]);
+        assert_eq!(codec.last_line(&mut src).as_deref(), Some(&b"tail"[..]));
+        assert_eq!(codec.last_line(&mut src), None);
+
+        // Nothing for the tail of a line that was already reported
+        let mut src = BytesMut::from("0123456789");
+        assert_eq!(lines(&mut codec, &mut src).len(), 1);
+        src.extend_from_slice(b"tail");
+        assert_eq!(lines(&mut codec, &mut src), []);
+        assert_eq!(codec.last_line(&mut src), None);
+    }
+
+    struct LineDecoder(Lines);
+
+    impl Decoder for LineDecoder {
+        type Item = BytesMut;
+        type Error = ProtocolError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ProtocolError> {
+            self.0.next_line(src)
+        }
+
+        fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ProtocolError> {
+            match self.0.next_line(src)? {
+                Some(line) => Ok(Some(line)),
+                None => Ok(self.0.last_line(src)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn framed_read_decodes_until_eof() {
+        let input: &[u8] = b"a\nbb\ntoo long\nc";
+        let mut framed = FramedRead::new(input, LineDecoder(Lines::new(4)), 64);
+        let mut found = Vec::new();
+        while let Some(line) = framed.next().await {
+            found.push(
+                line.map(|line| line.to_vec())
+                    .map_err(|err| err.to_string()),
+            );
+        }
+        let too_long = Err("line is longer than 4 bytes".to_string());
+        assert_eq!(
+            found,
+            [
+                Ok(b"a".to_vec()),
+                Ok(b"bb".to_vec()),
+                too_long,
+                Ok(b"c".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn framed_read_caps_its_buffer() {
+        // The decoder never finds a frame, so the buffer fills up
+        let input: &[u8] = &[b'x'; 100];
+        let mut framed = FramedRead::new(input, LineDecoder(Lines::new(1000)), 16);
+        match framed.next().await {
+            Some(Err(ProtocolError::Io(err))) => assert_eq!(err.kind(), io::ErrorKind::OutOfMemory),
+            other => panic!("expected a full buffer, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,775 @@
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use crate::{protocol::DEFAULT_MAX_LINE_LENGTH, rooms::validate_room, users::validate_nick};
+
+pub use crate::{lag::LagPolicy, ratelimit::RateLimit};
+
+pub const USAGE: &str = "\
+Usage: rust_tokio_chat_server [OPTIONS]
+
+Options:
+  -c, --config <PATH>        Read settings from a TOML config file
+  -b, --bind <ADDR>          Address to listen on [default: 0.0.0.0]
+  -p, --port <PORT>          Port to listen on [default: 8080]
+      --json-port <PORT>     Also listen on this port with JSON lines from the start
+      --ws-port <PORT>       Also accept WebSocket connections on this port
+      --irc-port <PORT>      Also accept IRC clients on this port
+      --tls-cert <PATH>      PEM certificate chain to serve TLS with [default: none]
+      --tls-key <PATH>       PEM private key for the certificate [default: none]
+      --tls-port <PORT>      Serve TLS on this port and keep plain text on --port;
+                             without it --port speaks TLS once there is a certificate
+      --tls-client-ca <PATH> Only let in TLS clients with a certificate from one of
+                             these PEM CAs, named after it [default: none]
+      --capacity <N>         Broadcast channel capacity, at most 65536 [default: 10]
+      --max-clients <N>      Maximum number of connected clients [default: 1000]
+      --max-per-ip <N>       Maximum number of clients from one IP address (or
+                             IPv6 /64), 0 for no limit [default: 10]
+      --accept-rate <N>      Connections one IP address may open per minute,
+                             0 for no limit [default: 30]
+      --access-file <PATH>   Allow and deny rules for client addresses, reloaded
+                             on SIGHUP, \"\" for none [default: none]
+      --max-line-length <SIZE>
+                             Longest line (or WebSocket message) a client may
+                             send, e.g. 4096 or 16K [default: 4096]
+      --max-long-lines <N>   Disconnect clients after this many over-long lines,
+                             0 to never [default: 3]
+      --log-level <LEVEL>    off, error, warn, info, debug or trace [default: info]
+      --lag-policy <POLICY>  What to do with clients that fall behind:
+                             notify, disconnect or spool [default: notify]
+      --spool-dir <PATH>     Where the spool policy keeps backlogs [default: system temp dir]
+      --spool-max-size <SIZE>
+                             Disconnect clients whose backlog file grows past
+                             this, e.g. 16M [default: 16M]
+      --default-room <ROOM>  Room new users are put in, \"\" for none [default: #lobby]
+      --offline-messages <N> Private messages kept per offline nick, 0 to turn off [default: 0]
+      --history-dir <PATH>   Keep room history in this directory, \"\" for none [default: none]
+      --history-replay <N>   Messages replayed to clients joining a room [default: 20]
+      --history-max-age <AGE>
+                             Forget messages older than this, e.g. 30d or 12h,
+                             0 to keep them [default: 0]
+      --history-max-size <SIZE>
+                             Trim each room's history to this size, e.g. 10M,
+                             0 for no limit [default: 0]
+      --accounts-file <PATH> Keep registered nicks and password hashes here,
+                             \"\" to turn accounts off [default: none]
+      --auth-rooms <ROOMS>   Comma separated rooms only logged in users can join
+      --admins <NICKS>       Comma separated accounts that may /ban addresses
+      --rate-limit <MSGS,BYTES>
+                             Messages and bytes per second a guest may send,
+                             0 for no limit [default: 5,4K]
+      --member-rate-limit <MSGS,BYTES>
+                             The same for logged in users [default: 10,8K]
+      --room-rate-limits <LIMITS>
+                             Extra limits for some rooms, e.g. \"#news=1,1K #bots=20,64K\"
+      --rate-kick-after <N>  Disconnect clients that hit the limit this many
+                             times, without 10s of calm in between,
+                             0 to never [default: 20]
+      --ping-interval <DURATION>
+                             Ping clients that were quiet this long, 0 to never
+                             [default: 60s]
+      --idle-timeout <DURATION>
+                             Disconnect clients that were quiet this long, even
+                             after a ping, 0 to never [default: 3m]
+      --tcp-keepalive <DURATION>
+                             Idle time before TCP keepalive probes, 0 to turn
+                             them off [default: 60s]
+      --shutdown-timeout <DURATION>
+                             How long sessions get to say goodbye on SIGINT or
+                             SIGTERM, e.g. 5s [default: 5s]
+  -h, --help                 Print this help
+
+Values given on the command line override the ones from the config file.";
+
+/// Settings the server runs with, after merging defaults, the config file and
+/// command line flags (in that order).
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Clients on this port skip `/hello json`
+    pub json_port: Option<u16>,
+    /// WebSocket gateway for browsers
+    pub ws_port: Option<u16>,
+    /// IRC front-end for standard IRC clients
+    pub irc_port: Option<u16>,
+    /// TLS is off without a certificate, `tls_key` must be set with it
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// `None` serves TLS on `port` instead, once there is a certificate
+    pub tls_port: Option<u16>,
+    /// Clients need a certificate from one of these CAs, and are logged in
+    /// under the nick it names
+    pub tls_client_ca: Option<PathBuf>,
+    pub channel_capacity: usize,
+    pub max_clients: usize,
+    /// `None` for no limit, the same for `accept_rate`
+    pub max_per_ip: Option<usize>,
+    /// Connections per minute from one address
+    pub accept_rate: Option<u32>,
+    /// `allow`/`deny` rules, everyone may connect without one
+    pub access_file: Option<PathBuf>,
+    /// Bytes, also the most a session buffers from its client
+    pub max_line_length: usize,
+    pub max_long_lines: u32,
+    pub log_level: LevelFilter,
+    pub lag_policy: LagPolicy,
+    pub spool_dir: PathBuf,
+    /// Bytes per client
+    pub spool_max_size: u64,
+    pub default_room: Option<String>,
+    pub offline_messages: usize,
+    pub history_dir: Option<PathBuf>,
+    pub history_replay: usize,
+    pub history_max_age: Option<Duration>,
+    /// Bytes per room
+    pub history_max_size: Option<u64>,
+    /// `/register` and `/login` are refused without one
+    pub accounts_file: Option<PathBuf>,
+    /// Rooms only users logged in to an account can join
+    pub auth_rooms: Vec<String>,
+    /// Accounts with admin commands, once logged in
+    pub admins: Vec<String>,
+    /// `None` for no limit
+    pub rate_limit: Option<RateLimit>,
+    pub member_rate_limit: Option<RateLimit>,
+    /// Applied on top of the guest or member limit
+    pub room_rate_limits: Vec<(String, RateLimit)>,
+    pub rate_kick_after: u32,
+    pub shutdown_timeout: Duration,
+    /// `None` turns it off, the same for the two below
+    pub ping_interval: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            json_port: None,
+            ws_port: None,
+            irc_port: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_port: None,
+            tls_client_ca: None,
+            channel_capacity: 10,
+            max_clients: 1000,
+            max_per_ip: Some(10),
+            accept_rate: Some(30),
+            access_file: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            max_long_lines: 3,
+            log_level: LevelFilter::Info,
+            lag_policy: LagPolicy::Notify,
+            spool_dir: std::env::temp_dir(),
+            spool_max_size: 16 << 20,
+            default_room: Some("#lobby".to_string()),
+            offline_messages: 0,
+            history_dir: None,
+            history_replay: 20,
+            history_max_age: None,
+            history_max_size: None,
+            accounts_file: None,
+            auth_rooms: Vec::new(),
+            admins: Vec::new(),
+            rate_limit: Some(RateLimit {
+                messages: 5,
+                bytes: 4 << 10,
+            }),
+            member_rate_limit: Some(RateLimit {
+                messages: 10,
+                bytes: 8 << 10,
+            }),
+            room_rate_limits: Vec::new(),
+            rate_kick_after: 20,
+            shutdown_timeout: Duration::from_secs(5),
+            ping_interval: Some(Duration::from_secs(60)),
+            idle_timeout: Some(Duration::from_secs(180)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    MissingValue(String),
+    UnknownFlag(String),
+    InvalidValue {
+        source: String,
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+    Read(PathBuf, std::io::Error),
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    UnknownKey {
+        path: PathBuf,
+        line: usize,
+        key: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingValue(flag) => write!(f, "missing value for `{flag}`"),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown option `{flag}`"),
+            ConfigError::InvalidValue {
+                source,
+                key,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{source}: invalid value `{value}` for `{key}` (expected {expected})"
+            ),
+            ConfigError::Read(path, err) => write!(f, "cannot read {}: {err}", path.display()),
+            ConfigError::Syntax {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{line}: {message}", path.display()),
+            ConfigError::UnknownKey { path, line, key } => {
+                write!(f, "{}:{line}: unknown key `{key}`", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Command line flags. Every setting is optional so we can tell which ones
+/// should override the config file.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub help: bool,
+    pub config: Option<PathBuf>,
+    /// `(flag, config key, value)` in the order they were given
+    settings: Vec<(String, &'static str, String)>,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both `--port 80` and `--port=80`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let key = match flag.as_str() {
+                "-h" | "--help" => {
+                    parsed.help = true;
+                    continue;
+                }
+                "-c" | "--config" => "config",
+                "-b" | "--bind" => "bind",
+                "-p" | "--port" => "port",
+                "--json-port" => "json_port",
+                "--ws-port" => "ws_port",
+                "--irc-port" => "irc_port",
+                "--tls-cert" => "tls_cert",
+                "--tls-key" => "tls_key",
+                "--tls-port" => "tls_port",
+                "--tls-client-ca" => "tls_client_ca",
+                "--capacity" => "channel_capacity",
+                "--max-clients" => "max_clients",
+                "--max-per-ip" => "max_per_ip",
+                "--accept-rate" => "accept_rate",
+                "--access-file" => "access_file",
+                "--max-line-length" => "max_line_length",
+                "--max-long-lines" => "max_long_lines",
+                "--log-level" => "log_level",
+                "--lag-policy" => "lag_policy",
+                "--spool-dir" => "spool_dir",
+                "--spool-max-size" => "spool_max_size",
+                "--default-room" => "default_room",
+                "--offline-messages" => "offline_messages",
+                "--history-dir" => "history_dir",
+                "--history-replay" => "history_replay",
+                "--history-max-age" => "history_max_age",
+                "--history-max-size" => "history_max_size",
+                "--accounts-file" => "accounts_file",
+                "--auth-rooms" => "auth_rooms",
+                "--admins" => "admins",
+                "--rate-limit" => "rate_limit",
+                "--member-rate-limit" => "member_rate_limit",
+                "--room-rate-limits" => "room_rate_limits",
+                "--rate-kick-after" => "rate_kick_after",
+                "--shutdown-timeout" => "shutdown_timeout",
+                "--ping-interval" => "ping_interval",
+                "--idle-timeout" => "idle_timeout",
+                "--tcp-keepalive" => "tcp_keepalive",
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            };
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue(flag)),
+            };
+            if key == "config" {
+                parsed.config = Some(PathBuf::from(value));
+            } else {
+                parsed.settings.push((flag, key, value));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Config {
+    /// Build the final config: defaults, then the config file (if any), then flags.
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        if let Some(path) = &args.config {
+            config.apply_file(path)?;
+        }
+        for (flag, key, value) in &args.settings {
+            config.set(key, value, || format!("option {flag}"))?;
+        }
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let source = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        for entry in parse_toml(path, &source)? {
+            let location = || format!("{}:{}", path.display(), entry.line);
+            if !self.set(&entry.key, &entry.value, location)? {
+                return Err(ConfigError::UnknownKey {
+                    path: path.into(),
+                    line: entry.line,
+                    key: entry.key,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Set a single value by its config file key. Returns `false` if the key
+    /// is not a known setting.
+    fn set(
+        &mut self,
+        key: &str,
+        value: &str,
+        source: impl Fn() -> String,
+    ) -> Result<bool, ConfigError> {
+        let invalid = |expected| ConfigError::InvalidValue {
+            source: source(),
+            key: key.to_string(),
+            value: value.to_string(),
+            expected,
+        };
+        match key {
+            "bind" => self.bind = value.parse().map_err(|_| invalid("an IP address"))?,
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| invalid("an integer between 0 and 65535"))?
+            }
+            "json_port" | "ws_port" | "irc_port" | "tls_port" => {
+                let port = value
+                    .parse()
+                    .map_err(|_| invalid("an integer between 0 and 65535"))?;
+                match key {
+                    "json_port" => self.json_port = Some(port),
+                    "ws_port" => self.ws_port = Some(port),
+                    "irc_port" => self.irc_port = Some(port),
+                    _ => self.tls_port = Some(port),
+                }
+            }
+            "tls_cert" if value.is_empty() => self.tls_cert = None,
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls_key" if value.is_empty() => self.tls_key = None,
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            "tls_client_ca" if value.is_empty() => self.tls_client_ca = None,
+            "tls_client_ca" => self.tls_client_ca = Some(PathBuf::from(value)),
+            "channel_capacity" => {
+                // The channel allocates all of its slots up front
+                self.channel_capacity = parse_positive(value, MAX_CHANNEL_CAPACITY)
+                    .ok_or_else(|| invalid("an integer between 1 and 65536"))?
+            }
+            "max_clients" => {
+                self.max_clients = parse_positive(value, usize::MAX)
+                    .ok_or_else(|| invalid("a positive integer"))?
+            }
+            "max_per_ip" => {
+                let max = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?;
+                self.max_per_ip = (max > 0).then_some(max);
+            }
+            "accept_rate" => {
+                let rate = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?;
+                self.accept_rate = (rate > 0).then_some(rate);
+            }
+            "access_file" if value.is_empty() => self.access_file = None,
+            "access_file" => self.access_file = Some(PathBuf::from(value)),
+            "max_line_length" => {
+                self.max_line_length = parse_with_unit(value, &[("K", 1 << 10), ("M", 1 << 20)])
+                    .filter(|size| (MIN_LINE_LENGTH..=MAX_LINE_LENGTH).contains(size))
+                    .ok_or_else(|| invalid("a size between 256 and 1M, like 4096 or 16K"))?
+                    as usize
+            }
+            "max_long_lines" => {
+                self.max_long_lines = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?
+            }
+            "log_level" => {
+                self.log_level = value
+                    .parse()
+                    .map_err(|_| invalid("one of off, error, warn, info, debug, trace"))?
+            }
+            "lag_policy" => {
+                self.lag_policy = value
+                    .parse()
+                    .map_err(|_| invalid("one of notify, disconnect, spool"))?
+            }
+            "spool_dir" => self.spool_dir = PathBuf::from(value),
+            "spool_max_size" => {
+                self.spool_max_size =
+                    parse_with_unit(value, &[("K", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)])
+                        .filter(|size| *size > 0)
+                        .ok_or_else(|| invalid("a size in bytes like 512K or 16M"))?
+            }
+            "default_room" if value.is_empty() => self.default_room = None,
+            "default_room" => {
+                validate_room(value).map_err(|_| invalid("a room name like #lobby"))?;
+                self.default_room = Some(value.to_string());
+            }
+            "offline_messages" => {
+                self.offline_messages = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?
+            }
+            "history_dir" if value.is_empty() => self.history_dir = None,
+            "history_dir" => self.history_dir = Some(PathBuf::from(value)),
+            "history_replay" => {
+                self.history_replay = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?
+            }
+            "history_max_age" => {
+                let age = parse_with_unit(value, &[("s", 1), ("m", 60), ("h", 3600), ("d", 86400)])
+                    .ok_or_else(|| invalid("a duration like 90s, 15m, 12h or 30d"))?;
+                self.history_max_age = (age > 0).then(|| Duration::from_secs(age));
+            }
+            "history_max_size" => {
+                let size =
+                    parse_with_unit(value, &[("K", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)])
+                        .ok_or_else(|| invalid("a size in bytes like 65536, 512K or 10M"))?;
+                self.history_max_size = (size > 0).then_some(size);
+            }
+            "accounts_file" if value.is_empty() => self.accounts_file = None,
+            "accounts_file" => self.accounts_file = Some(PathBuf::from(value)),
+            "auth_rooms" => {
+                self.auth_rooms = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|room| !room.is_empty())
+                    .map(|room| validate_room(room).map(|()| room.to_string()))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid("comma separated room names like #staff,#ops"))?
+            }
+            "admins" => {
+                self.admins = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|nick| !nick.is_empty())
+                    .map(|nick| validate_nick(nick).map(|()| nick.to_string()))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid("comma separated nicknames like alice,bob"))?
+            }
+            "rate_limit" | "member_rate_limit" => {
+                let limit = parse_rate_limit(value)
+                    .ok_or_else(|| invalid("messages and bytes per second like 5,4K, or 0"))?;
+                match key {
+                    "rate_limit" => self.rate_limit = limit,
+                    _ => self.member_rate_limit = limit,
+                }
+            }
+            "room_rate_limits" => {
+                self.room_rate_limits = value
+                    .split_whitespace()
+                    .map(|entry| {
+                        let (room, limit) = entry.split_once('=')?;
+                        validate_room(room).ok()?;
+                        Some((room.to_string(), parse_rate_limit(limit)??))
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid("space separated limits like #news=1,1K"))?
+            }
+            "shutdown_timeout" => {
+                let timeout = parse_with_unit(value, &[("s", 1), ("m", 60)])
+                    .ok_or_else(|| invalid("a duration like 5s or 1m"))?;
+                self.shutdown_timeout = Duration::from_secs(timeout);
+            }
+            "ping_interval" | "idle_timeout" | "tcp_keepalive" => {
+                let secs = parse_with_unit(value, &[("s", 1), ("m", 60), ("h", 3600)])
+                    .ok_or_else(|| invalid("a duration like 30s or 5m, or 0"))?;
+                let duration = (secs > 0).then(|| Duration::from_secs(secs));
+                match key {
+                    "ping_interval" => self.ping_interval = duration,
+                    "idle_timeout" => self.idle_timeout = duration,
+                    _ => self.tcp_keepalive = duration,
+                }
+            }
+            "rate_kick_after" => {
+                self.rate_kick_after = value
+                    .parse()
+                    .map_err(|_| invalid("a non-negative integer"))?
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+const MAX_CHANNEL_CAPACITY: usize = 1 << 16;
+
+/// Shorter lines would cut off ordinary commands
+const MIN_LINE_LENGTH: u64 = 256;
+const MAX_LINE_LENGTH: u64 = 1 << 20;
+
+fn parse_positive(value: &str, max: usize) -> Option<usize> {
+    value.parse().ok().filter(|n| *n > 0 && *n <= max)
+}
+
+/// A number with an optional unit suffix from `units`, e.g. `30d`.
+fn parse_with_unit(value: &str, units: &[(&str, u64)]) -> Option<u64> {
+    let (number, scale) = units
+        .iter()
+        .find_map(|(suffix, scale)| Some((value.strip_suffix(suffix)?, *scale)))
+        .unwrap_or((value, 1));
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+/// `messages,bytes` per second, `Some(None)` for `0` (no limit).
+fn parse_rate_limit(value: &str) -> Option<Option<RateLimit>> {
+    if value == "0" {
+        return Some(None);
+    }
+    let (messages, bytes) = value.split_once(',')?;
+    let limit = RateLimit {
+        messages: messages.trim().parse().ok().filter(|n| *n > 0)?,
+        bytes: parse_with_unit(bytes.trim(), &[("K", 1 << 10), ("M", 1 << 20)])
+            .filter(|n| *n > 0)?,
+    };
+    Some(Some(limit))
+}
+
+struct Entry {
+    line: usize,
+    key: String,
+    value: String,
+}
+
+/// Just enough TOML for flat config files: `key = value` pairs with string,
+/// integer or boolean values, `[section]` headers and `#` comments. Keys inside
+/// a section are returned as `section.key`.
+fn parse_toml(path: &Path, source: &str) -> Result<Vec<Entry>, ConfigError> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let syntax = |message: &str| ConfigError::Syntax {
+            path: path.into(),
+            line,
+            message: message.to_string(),
+        };
+        let text = strip_comment(raw).trim();
+        if text.is_empty() {
+            continue;
+        }
+        if let Some(header) = text.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .ok_or_else(|| syntax("unterminated section header"))?
+                .trim();
+            if name.is_empty() {
+                return Err(syntax("empty section name"));
+            }
+            section = name.to_string();
+            continue;
+        }
+        let (key, value) = text
+            .split_once('=')
+            .ok_or_else(|| syntax("expected `key = value`"))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(syntax("missing key before `=`"));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            quoted
+                .strip_suffix('"')
+                .ok_or_else(|| syntax("unterminated string"))?
+                .to_string()
+        } else if value.is_empty() {
+            return Err(syntax("missing value after `=`"));
+        } else {
+            value.to_string()
+        };
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{section}.{key}")
+        };
+        entries.push(Entry { line, key, value });
+    }
+    Ok(entries)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn toml(source: &str) -> Result<Vec<(String, String)>, ConfigError> {
+        let entries = parse_toml(Path::new("chat.toml"), source)?;
+        Ok(entries.into_iter().map(|e| (e.key, e.value)).collect())
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config = Config::load(&args(&["--port", "9000", "--bind=127.0.0.1"])).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.channel_capacity, 10);
+    }
+
+    #[test]
+    fn flags_need_values_and_known_names() {
+        let missing = Args::parse(["--port".to_string()]).unwrap_err();
+        assert_eq!(missing.to_string(), "missing value for `--port`");
+        let unknown = Args::parse(["--nope".to_string()]).unwrap_err();
+        assert_eq!(unknown.to_string(), "unknown option `--nope`");
+    }
+
+    #[test]
+    fn channel_capacity_is_bounded() {
+        let config = Config::load(&args(&["--capacity", "65536"])).unwrap();
+        assert_eq!(config.channel_capacity, 65536);
+        for capacity in ["0", "65537", "100000000000", "-1"] {
+            let err = Config::load(&args(&["--capacity", capacity])).unwrap_err();
+            assert!(
+                matches!(err, ConfigError::InvalidValue { .. }),
+                "{capacity}"
+            );
+        }
+    }
+
+    #[test]
+    fn tls_settings() {
+        let config = Config::load(&args(&[
+            "--tls-cert=chain.pem",
+            "--tls-key=key.pem",
+            "--tls-port=8443",
+            "--tls-client-ca=ca.pem",
+        ]))
+        .unwrap();
+        assert_eq!(config.tls_cert, Some(PathBuf::from("chain.pem")));
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+        assert_eq!(config.tls_port, Some(8443));
+        assert_eq!(config.tls_client_ca, Some(PathBuf::from("ca.pem")));
+        let config = Config::load(&args(&["--tls-cert=chain.pem", "--tls-cert="])).unwrap();
+        assert_eq!(config.tls_cert, None);
+    }
+
+    #[test]
+    fn invalid_values_say_what_was_expected() {
+        let err = Config::load(&args(&["--port", "http"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "option --port: invalid value `http` for `port` (expected an integer between 0 and 65535)"
+        );
+    }
+
+    #[test]
+    fn units() {
+        let units = [("K", 1 << 10), ("M", 1 << 20)];
+        assert_eq!(parse_with_unit("16K", &units), Some(16 << 10));
+        assert_eq!(parse_with_unit("4096", &units), Some(4096));
+        assert_eq!(parse_with_unit("1.5M", &units), None);
+        assert_eq!(parse_with_unit("99999999999999999M", &units), None);
+        assert_eq!(parse_rate_limit("0"), Some(None));
+        assert_eq!(
+            parse_rate_limit("5, 4K"),
+            Some(Some(RateLimit {
+                messages: 5,
+                bytes: 4 << 10
+            }))
+        );
+        assert_eq!(parse_rate_limit("0,4K"), None);
+    }
+
+    #[test]
+    fn toml_subset() {
+        let source = "\
+# comment
+port = 9000   # trailing comment
+bind = \"127.0.0.1\"
+motd = \"a # inside a string\"
+
+[limits]
+max = 5
+";
+        assert_eq!(
+            toml(source).unwrap(),
+            [
+                ("port".to_string(), "9000".to_string()),
+                ("bind".to_string(), "127.0.0.1".to_string()),
+                ("motd".to_string(), "a # inside a string".to_string()),
+                ("limits.max".to_string(), "5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_errors_have_line_numbers() {
+        let cases = [
+            ("port 9000", "chat.toml:1: expected `key = value`"),
+            ("\n[limits", "chat.toml:2: unterminated section header"),
+            ("[ ]", "chat.toml:1: empty section name"),
+            ("= 1", "chat.toml:1: missing key before `=`"),
+            ("port =", "chat.toml:1: missing value after `=`"),
+            ("bind = \"127.0.0.1", "chat.toml:1: unterminated string"),
+        ];
+        for (source, message) in cases {
+            let err = toml(source).map(drop).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,683 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use crate::{
+    access::Cidr,
+    accounts::AccountError,
+    codec::{FramedRead, FramedWrite},
+    error::ChatError,
+    heartbeat::{Beat, Heartbeat},
+    history::MAX_HISTORY,
+    lag::Inbox,
+    plugin::{Flow, Message},
+    protocol::{ChatCodec, ClientFrame, Mode, ProtocolError, ServerFrame, Stamp, PROTOCOL_VERSION},
+    ratelimit::{Limiter, Verdict},
+    rooms::{RoomError, Rooms},
+    state::State,
+    users::{Delivery, NickError, Registration},
+};
+
+/// One frame on the broadcast channel.
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub frame: ServerFrame,
+    pub from: SocketAddr,
+}
+
+impl ChatMessage {
+    /// Whether the session on `addr` should get this message.
+    pub fn is_for(&self, addr: SocketAddr, rooms: &Rooms) -> bool {
+        self.from != addr
+            && self
+                .frame
+                .room()
+                .is_none_or(|room| rooms.is_member(room, addr))
+    }
+}
+
+/// Private messages a session can have waiting before senders get an error
+pub const MAILBOX_CAPACITY: usize = 32;
+
+const PICK_NICK: &str = "pick a nickname first with /nick <name>";
+
+pub const SHUTDOWN_NOTICE: &str = "server is shutting down, bye";
+
+const ADMINS_ONLY: &str = "only admins can do that";
+
+const BANNED: &str = "disconnected: your address was banned";
+
+/// Relay frames between one client and the broadcast channel until the client
+/// disconnects (`Ok`) or something goes wrong with this session (`Err`).
+///
+/// `stream` can be anything byte oriented (a `TcpStream`, a TLS stream on top
+/// of one, ...), the session doesn't care how the bytes get there.
+///
+/// `certified` is a nick the client proved it owns with a TLS client
+/// certificate. The session is logged in under it right away, no `/nick`.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    addr: SocketAddr,
+    state: Arc<State>,
+    mode: Mode,
+    certified: Option<String>,
+) -> Result<(), ChatError> {
+    let (stream_reader, stream_writer) = io::split(stream);
+
+    let max_length = state.config.max_line_length;
+    let decoder = ChatCodec::with_max_length(mode, max_length);
+    // Room for one full line and its newline
+    let mut frames = FramedRead::new(stream_reader, decoder, max_length + 1);
+    let mut out = FramedWrite::new(stream_writer, ChatCodec::new(mode));
+
+    let hello = ServerFrame::Hello {
+        version: PROTOCOL_VERSION,
+        text: match certified {
+            Some(_) => "welcome!".to_string(),
+            None => "welcome! pick a nickname with /nick <name>".to_string(),
+        },
+    };
+    send(&mut out, hello).await?;
+    let (mailbox_send, mut mailbox) = mpsc::channel(MAILBOX_CAPACITY);
+    let mut limiter = Limiter::new(&state.config);
+    let mut long_lines = 0;
+    let mut heartbeat = Heartbeat::new(&state.config);
+    // A frame over the rate limit waits here until `resume_at`, and nothing
+    // more is read from the client in the meantime
+    let mut held = None;
+    let mut resume_at = Instant::now();
+
+    // Nothing gets broadcast (or delivered) until the client has a nickname
+    let mut user = match certified {
+        Some(nick) => match Registration::authenticated(nick, addr, mailbox_send.clone(), &state) {
+            Ok(user) => user,
+            Err(err) => {
+                // Best effort, the client is about to be dropped anyway
+                let _ = send(&mut out, ServerFrame::Error(err.to_string())).await;
+                return Err(ChatError::Certificate(err));
+            }
+        },
+        None => loop {
+            let frame = tokio::select! {
+                frame = next_frame(&mut frames), if held.is_none() => {
+                    heartbeat.heard();
+                    match frame? {
+                        Some(Ok(frame)) => {
+                            let verdict = limiter.check(false, None, 0);
+                            if let Some(wait) = throttle(verdict, &mut out).await? {
+                                resume_at = Instant::now() + wait;
+                                held = Some(frame);
+                                continue;
+                            }
+                            Some(Ok(frame))
+                        }
+                        frame => frame,
+                    }
+                }
+                () = time::sleep_until(resume_at), if held.is_some() => held.take().map(Ok),
+                beat = heartbeat.next() => match beat {
+                    Beat::Ping(token) => {
+                        send(&mut out, ServerFrame::Ping(token)).await?;
+                        continue;
+                    }
+                    Beat::TimedOut => return Err(ChatError::IdleTimeout),
+                },
+                () = state.access.banned(addr.ip()) => {
+                    // Best effort, the client is about to be dropped anyway
+                    let _ = send(&mut out, ServerFrame::Error(BANNED.to_string())).await;
+                    return Err(ChatError::Banned);
+                }
+                () = state.shutting_down() => {
+                    return send(&mut out, ServerFrame::notice(SHUTDOWN_NOTICE)).await;
+                }
+            };
+            let reply = match frame {
+                None => return Ok(()),
+                Some(Ok(ClientFrame::Hello(mode))) => {
+                    frames.decoder_mut().set_mode(mode);
+                    out.encoder_mut().set_mode(mode);
+                    ServerFrame::Ack(format!("switched to {mode} mode"))
+                }
+                Some(Ok(ClientFrame::Nick(nick))) => {
+                    match Registration::register(&nick, addr, mailbox_send.clone(), &state) {
+                        Ok(user) => break user,
+                        Err(err) => ServerFrame::Error(err.to_string()),
+                    }
+                }
+                Some(Ok(ClientFrame::Register { nick, password })) => {
+                    let account = create_account(&nick, &password, None, addr, &state).await;
+                    match account.and_then(|account| {
+                        Registration::authenticated(account, addr, mailbox_send.clone(), &state)
+                            .map_err(AccountError::Nick)
+                    }) {
+                        Ok(user) => break user,
+                        Err(err) => ServerFrame::Error(err.to_string()),
+                    }
+                }
+                Some(Ok(ClientFrame::Login { nick, password })) => {
+                    let account = check_password(&nick, &password, addr, &state).await;
+                    match account.and_then(|account| {
+                        Registration::authenticated(account, addr, mailbox_send.clone(), &state)
+                            .map_err(AccountError::Nick)
+                    }) {
+                        Ok(user) => break user,
+                        Err(err) => ServerFrame::Error(err.to_string()),
+                    }
+                }
+                Some(Ok(ClientFrame::Pong(_))) => continue,
+                Some(Ok(_)) => ServerFrame::Error(PICK_NICK.to_string()),
+                Some(Err(err)) => {
+                    reject(err, &mut long_lines, &state, &mut out).await?;
+                    continue;
+                }
+            };
+            send(&mut out, reply).await?;
+        },
+    };
+    let welcome = match user.account {
+        Some(_) => format!("logged in as {}", user.nick),
+        None => format!("you are now known as {}", user.nick),
+    };
+    send(&mut out, ServerFrame::Ack(welcome)).await?;
+    let hooked = state.plugins.connect(&user, &state).await;
+    for reply in hooked.replies {
+        send(&mut out, reply).await?;
+    }
+    if hooked.flow == Flow::Drop {
+        user.leave("refused");
+        return Ok(());
+    }
+    for private in state.users.take_offline(&user.nick) {
+        send(&mut out, private).await?;
+    }
+    if let Some(room) = &state.config.default_room {
+        for reply in join(&mut user, room, &state).await {
+            send(&mut out, reply).await?;
+        }
+    }
+
+    let mut inbox = Inbox::new(addr, &state);
+
+    loop {
+        tokio::select! {
+            frame = next_frame(&mut frames), if held.is_none() => {
+                heartbeat.heard();
+                let replies = match frame? {
+                    None => return Ok(()),
+                    Some(Ok(frame)) => {
+                        let (room, bytes) = rate_cost(&frame, &user);
+                        let verdict = limiter.check(user.account.is_some(), room, bytes);
+                        match throttle(verdict, &mut out).await? {
+                            Some(wait) => {
+                                resume_at = Instant::now() + wait;
+                                held = Some(frame);
+                                Vec::new()
+                            }
+                            None => handle_frame(frame, &mut user, &state).await?,
+                        }
+                    }
+                    Some(Err(err)) => {
+                        reject(err, &mut long_lines, &state, &mut out).await?;
+                        Vec::new()
+                    }
+                };
+                for reply in replies {
+                    send(&mut out, reply).await?;
+                }
+            }
+            () = time::sleep_until(resume_at), if held.is_some() => {
+                let frame = held.take().expect("only runs with a held frame");
+                for reply in handle_frame(frame, &mut user, &state).await? {
+                    send(&mut out, reply).await?;
+                }
+            }
+            recv_msg = inbox.recv() => {
+                let recv_msg = match recv_msg {
+                    Ok(msg) => msg,
+                    Err(ChatError::Lagged(n)) => {
+                        // Best effort, the client is about to be dropped anyway
+                        let reason = format!("disconnected: fell {n} messages behind");
+                        let _ = send(&mut out, ServerFrame::Error(reason)).await;
+                        return Err(ChatError::Lagged(n));
+                    }
+                    Err(err) => return Err(err),
+                };
+                send(&mut out, recv_msg).await?;
+            }
+            // The registry holds a sender for as long as `user` is alive
+            Some(private) = mailbox.recv() => {
+                send(&mut out, private).await?;
+            }
+            beat = heartbeat.next() => match beat {
+                Beat::Ping(token) => send(&mut out, ServerFrame::Ping(token)).await?,
+                Beat::TimedOut => {
+                    // Best effort, the client is likely gone
+                    let reason = "disconnected: timed out".to_string();
+                    let _ = send(&mut out, ServerFrame::Error(reason)).await;
+                    user.leave("timeout");
+                    return Err(ChatError::IdleTimeout);
+                }
+            },
+            () = state.access.banned(addr.ip()) => {
+                // Best effort, the client is about to be dropped anyway
+                let _ = send(&mut out, ServerFrame::Error(BANNED.to_string())).await;
+                user.leave("banned");
+                return Err(ChatError::Banned);
+            }
+            () = state.shutting_down() => {
+                return send(&mut out, ServerFrame::notice(SHUTDOWN_NOTICE)).await;
+            }
+        }
+    }
+}
+
+/// Next frame from the client. Client mistakes come back as `Some(Err(_))`
+/// so they can be answered, only errors that end the session are `Err`.
+async fn next_frame<R: AsyncRead + Unpin>(
+    frames: &mut FramedRead<R, ChatCodec>,
+) -> Result<Option<Result<ClientFrame, ProtocolError>>, ChatError> {
+    match frames.next().await {
+        Some(Err(ProtocolError::Io(err))) => Err(ChatError::Read(err)),
+        frame => Ok(frame),
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(
+    out: &mut FramedWrite<W, ChatCodec>,
+    frame: ServerFrame,
+) -> Result<(), ChatError> {
+    out.send(frame).await.map_err(|err| match err {
+        ProtocolError::Io(err) => ChatError::Write(err),
+        err => ChatError::Protocol(err),
+    })
+}
+
+/// Answer a line the client got wrong. Too many over-long lines end the
+/// session, the client is likely not speaking the protocol at all.
+async fn reject<W: AsyncWrite + Unpin>(
+    err: ProtocolError,
+    long_lines: &mut u32,
+    state: &State,
+    out: &mut FramedWrite<W, ChatCodec>,
+) -> Result<(), ChatError> {
+    let limit = state.config.max_long_lines;
+    if let ProtocolError::LineTooLong(_) = err {
+        *long_lines += 1;
+        if limit > 0 && *long_lines >= limit {
+            // Best effort, the client is about to be dropped anyway
+            let reason = format!("disconnected: {err} ({limit} times)");
+            let _ = send(out, ServerFrame::Error(reason)).await;
+            return Err(ChatError::Protocol(err));
+        }
+    }
+    send(out, ServerFrame::Error(err.to_string())).await
+}
+
+/// Room and size of a frame, as far as rate limits are concerned. Commands
+/// cost one message and no bytes.
+fn rate_cost<'a>(frame: &'a ClientFrame, user: &'a Registration) -> (Option<&'a str>, usize) {
+    match frame {
+        ClientFrame::Message { room, text } => {
+            (room.as_deref().or(user.room.as_deref()), text.len())
+        }
+        ClientFrame::Msg { text, .. } => (None, text.len()),
+        ClientFrame::Topic {
+            topic: Some(topic), ..
+        } => (None, topic.len()),
+        _ => (None, 0),
+    }
+}
+
+/// How long to hold back a frame that is over the rate limit, `None` if it
+/// can go through now. Ends the session of a client that keeps flooding.
+/// The caller does the waiting, so the session keeps delivering messages
+/// (and noticing shutdown) while the client is held back.
+async fn throttle<W: AsyncWrite + Unpin>(
+    verdict: Verdict,
+    out: &mut FramedWrite<W, ChatCodec>,
+) -> Result<Option<Duration>, ChatError> {
+    match verdict {
+        Verdict::Pass => Ok(None),
+        Verdict::Throttle { wait, warn } => {
+            if warn {
+                let warning = "you are sending too fast, slow down".to_string();
+                send(out, ServerFrame::Error(warning)).await?;
+            }
+            Ok(Some(wait))
+        }
+        Verdict::Kick => {
+            // Best effort, the client is about to be dropped anyway
+            let reason = "disconnected: sending too fast".to_string();
+            let _ = send(out, ServerFrame::Error(reason)).await;
+            Err(ChatError::Flooding)
+        }
+    }
+}
+
+/// Broadcast a message from a registered user or run their command. Returns
+/// what should be sent back to the user, if anything.
+async fn handle_frame(
+    frame: ClientFrame,
+    user: &mut Registration,
+    state: &State,
+) -> Result<Vec<ServerFrame>, ChatError> {
+    let result = match frame {
+        ClientFrame::Message { room, text } => {
+            let room = match target_room(room, user, state) {
+                Ok(room) => room,
+                Err(err) => return Ok(vec![ServerFrame::Error(err.to_string())]),
+            };
+            return publish(room, text, user, state).await;
+        }
+        ClientFrame::Join(room) => return Ok(join(user, &room, state).await),
+        ClientFrame::History { room, count } => {
+            let room = match target_room(room, user, state) {
+                Ok(room) => room,
+                Err(err) => return Ok(vec![ServerFrame::Error(err.to_string())]),
+            };
+            if state.history.is_none() {
+                let reason = "history is turned off on this server".to_string();
+                return Ok(vec![ServerFrame::Error(reason)]);
+            }
+            let mut replies = replay(&room, count.min(MAX_HISTORY), state).await;
+            if replies.is_empty() {
+                replies.push(ServerFrame::Ack(format!("no history for {room}")));
+            }
+            return Ok(replies);
+        }
+        ClientFrame::Nick(nick) => user
+            .rename(&nick)
+            .map(|()| format!("you are now known as {}", user.nick))
+            .map_err(|err| err.to_string()),
+        ClientFrame::Part(Some(room)) => user.part(&room).map_err(|err| err.to_string()),
+        ClientFrame::Part(None) => match user.room.clone() {
+            Some(room) => user.part(&room).map_err(|err| err.to_string()),
+            None => Err(RoomError::NoCurrentRoom.to_string()),
+        },
+        ClientFrame::Topic { room, topic } => topic_command(room, topic, user, state),
+        ClientFrame::Hello(_) => Err("/hello has to come before /nick".to_string()),
+        // Hearing from the client was all the pong was for
+        ClientFrame::Pong(_) => return Ok(Vec::new()),
+        ClientFrame::Rooms => Ok(list_rooms(state)),
+        ClientFrame::Names(room) => {
+            let room = match room {
+                Some(room) => Ok(room),
+                None => user.room.clone().ok_or(RoomError::NoCurrentRoom),
+            };
+            return Ok(vec![match room.map(|room| names(&room, state)) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => ServerFrame::Error("there is no such room".to_string()),
+                Err(err) => ServerFrame::Error(err.to_string()),
+            }]);
+        }
+        ClientFrame::Msg { to, text } => private_message(&to, text, user, state),
+        ClientFrame::Register { nick, password } => {
+            match create_account(&nick, &password, Some(user), user.addr, state).await {
+                Ok(account) => log_in(user, account),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        ClientFrame::Login { nick, password } => {
+            match check_password(&nick, &password, user.addr, state).await {
+                Ok(account) => log_in(user, account),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        ClientFrame::Ban(range) => ban_command(&range, true, user, state),
+        ClientFrame::Unban(range) => ban_command(&range, false, user, state),
+        ClientFrame::Bans if !is_admin(user, state) => Err(ADMINS_ONLY.to_string()),
+        ClientFrame::Bans => Ok(list_bans(state)),
+    };
+    Ok(vec![match result {
+        Ok(text) => ServerFrame::Ack(text),
+        Err(text) => ServerFrame::Error(text),
+    }])
+}
+
+/// Send a message to everyone in `room`, after the plugins had their say,
+/// and keep it in the room's history. Returns the plugins' replies.
+pub async fn publish(
+    room: String,
+    text: String,
+    user: &Registration,
+    state: &State,
+) -> Result<Vec<ServerFrame>, ChatError> {
+    let mut message = Message::new(room.clone(), text);
+    let hooked = state.plugins.message(user, &mut message, state).await;
+    if hooked.flow == Flow::Drop {
+        return Ok(hooked.replies);
+    }
+    let frame = ServerFrame::Message {
+        stamp: Stamp::new(),
+        room,
+        from: user.nick.clone(),
+        text: message.text,
+    };
+    state
+        .channel_send
+        .send(ChatMessage {
+            frame: frame.clone(),
+            from: user.addr,
+        })
+        .map_err(|_| ChatError::ChannelClosed)?;
+    if let Some(history) = &state.history {
+        if let Err(err) = history.append(&frame).await {
+            let room = frame.room().unwrap_or_default();
+            warn!("cannot write history of {room}: {err}");
+        }
+    }
+    Ok(hooked.replies)
+}
+
+/// Canonical name of the room a frame is for: `room` if the user is in it,
+/// otherwise their current room.
+fn target_room(
+    room: Option<String>,
+    user: &Registration,
+    state: &State,
+) -> Result<String, RoomError> {
+    match room {
+        Some(room) => state
+            .rooms
+            .joined(user.addr)
+            .into_iter()
+            .find(|joined| joined.eq_ignore_ascii_case(&room))
+            .ok_or(RoomError::NotMember(room)),
+        None => user.room.clone().ok_or(RoomError::NoCurrentRoom),
+    }
+}
+
+/// Join `room` and, if the user wasn't in it yet, replay what was said there
+/// recently.
+async fn join(user: &mut Registration, room: &str, state: &State) -> Vec<ServerFrame> {
+    let new_member = !state.rooms.is_member(room, user.addr);
+    let mut hooked = Vec::new();
+    if new_member {
+        let plugins = state.plugins.join(user, room, state).await;
+        if plugins.flow == Flow::Drop {
+            return plugins.replies;
+        }
+        hooked = plugins.replies;
+    }
+    let reply = match user.join(room) {
+        Ok(reply) => reply,
+        Err(err) => return vec![ServerFrame::Error(err.to_string())],
+    };
+    let mut replies = vec![ServerFrame::Ack(reply)];
+    replies.append(&mut hooked);
+    if let Some(room) = user.room.clone().filter(|_| new_member) {
+        replies.extend(replay(&room, state.config.history_replay, state).await);
+    }
+    replies
+}
+
+/// Up to `count` of the latest messages in `room` with a notice in front,
+/// nothing if there are none or history is turned off.
+pub async fn replay(room: &str, count: usize, state: &State) -> Vec<ServerFrame> {
+    let Some(history) = &state.history else {
+        return Vec::new();
+    };
+    let messages = match history.recent(room, count).await {
+        Ok(messages) => messages,
+        Err(err) => {
+            warn!("cannot read history of {room}: {err}");
+            return Vec::new();
+        }
+    };
+    if messages.is_empty() {
+        return messages;
+    }
+    let notice = ServerFrame::Notice {
+        stamp: Stamp::new(),
+        room: Some(room.to_string()),
+        text: format!("last {} messages:", messages.len()),
+    };
+    std::iter::once(notice).chain(messages).collect()
+}
+
+fn private_message(
+    to: &str,
+    text: String,
+    user: &Registration,
+    state: &State,
+) -> Result<String, String> {
+    let frame = ServerFrame::Private {
+        stamp: Stamp::new(),
+        from: user.nick.clone(),
+        text,
+    };
+    match state.users.deliver(to, frame) {
+        Ok(Delivery::Sent(to)) => Ok(format!("message sent to {to}")),
+        Ok(Delivery::Queued) => Ok(format!(
+            "{to} is offline, they will get it when they connect"
+        )),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// `/register`: create an account for `nick`. Returns the account's nick.
+async fn create_account(
+    nick: &str,
+    password: &str,
+    user: Option<&Registration>,
+    addr: SocketAddr,
+    state: &State,
+) -> Result<String, AccountError> {
+    let accounts = state.accounts.as_ref().ok_or(AccountError::Disabled)?;
+    // Whoever has the nick without an account keeps it until they leave
+    let own = user.is_some_and(|user| user.nick.eq_ignore_ascii_case(nick));
+    if !own && state.users.is_online(nick) {
+        return Err(AccountError::Nick(NickError::Taken(nick.to_string())));
+    }
+    accounts.register(nick, password, addr.ip()).await?;
+    Ok(nick.to_string())
+}
+
+/// `/login`: check the password of `nick`'s account. Returns the account's
+/// nick as it was registered.
+async fn check_password(
+    nick: &str,
+    password: &str,
+    addr: SocketAddr,
+    state: &State,
+) -> Result<String, AccountError> {
+    let accounts = state.accounts.as_ref().ok_or(AccountError::Disabled)?;
+    accounts.verify(nick, password, addr.ip()).await
+}
+
+fn log_in(user: &mut Registration, account: String) -> Result<String, String> {
+    user.log_in(account)
+        .map(|()| format!("logged in as {}", user.nick))
+        .map_err(|err| err.to_string())
+}
+
+/// Admins are the accounts listed in the config, only once logged in.
+fn is_admin(user: &Registration, state: &State) -> bool {
+    (user.account.as_deref()).is_some_and(|account| {
+        (state.config.admins.iter()).any(|admin| admin.eq_ignore_ascii_case(account))
+    })
+}
+
+/// `/ban` or `/unban` a range. New bans also end the sessions of clients
+/// already connected from the range.
+fn ban_command(
+    range: &str,
+    ban: bool,
+    user: &Registration,
+    state: &State,
+) -> Result<String, String> {
+    if !is_admin(user, state) {
+        return Err(ADMINS_ONLY.to_string());
+    }
+    let range: Cidr = range
+        .parse()
+        .map_err(|()| format!("`{range}` is not an address or range like 192.0.2.0/24"))?;
+    if ban {
+        if !state.access.ban(range) {
+            return Err(format!("{range} is banned already"));
+        }
+        info!("{} banned {range}", user.nick);
+        Ok(format!("banned {range}"))
+    } else {
+        if !state.access.unban(range) {
+            return Err(format!("{range} is not banned"));
+        }
+        info!("{} unbanned {range}", user.nick);
+        Ok(format!("unbanned {range}"))
+    }
+}
+
+fn list_bans(state: &State) -> String {
+    let bans = state.access.bans();
+    if bans.is_empty() {
+        return "nobody is banned".to_string();
+    }
+    let bans: Vec<_> = bans.iter().map(Cidr::to_string).collect();
+    format!("banned: {}", bans.join(", "))
+}
+
+fn topic_command(
+    room: Option<String>,
+    topic: Option<String>,
+    user: &mut Registration,
+    state: &State,
+) -> Result<String, String> {
+    let room = target_room(room, user, state).map_err(|err| err.to_string())?;
+    match topic {
+        Some(topic) => user
+            .set_topic(&room, &topic)
+            .map(|room| format!("topic of {room} changed"))
+            .map_err(|err| err.to_string()),
+        None => Ok(match state.rooms.topic(&room) {
+            Some(topic) => format!("topic of {room}: {topic}"),
+            None => format!("{room} has no topic"),
+        }),
+    }
+}
+
+/// The members of `room`, under the room's own spelling. `None` if nobody
+/// is in it.
+fn names(room: &str, state: &State) -> Option<ServerFrame> {
+    let nicks = state.rooms.members(room)?;
+    let (room, _) = (state.rooms.list())
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(room))?;
+    Some(ServerFrame::Names { room, nicks })
+}
+
+fn list_rooms(state: &State) -> String {
+    let rooms = state.rooms.list();
+    if rooms.is_empty() {
+        return "no rooms yet, create one with /join #name".to_string();
+    }
+    let rooms: Vec<_> = rooms
+        .iter()
+        .map(|(name, members)| format!("{name} ({members})"))
+        .collect();
+    format!("rooms: {}", rooms.join(", "))
+}
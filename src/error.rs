@@ -0,0 +1,64 @@
+use std::{fmt, io};
+
+use crate::{protocol::ProtocolError, users::NickError};
+
+/// Everything that can go wrong while serving clients. Accept errors are
+/// retried by the listener loop; the others end only the affected session.
+#[derive(Debug)]
+pub enum ChatError {
+    Accept(io::Error),
+    Read(io::Error),
+    Write(io::Error),
+    Protocol(ProtocolError),
+    ChannelClosed,
+    Lagged(u64),
+    Spool(io::Error),
+    /// The WebSocket upgrade failed
+    Handshake(io::Error),
+    /// The TLS handshake failed
+    Tls(io::Error),
+    /// The nick in the client's certificate is taken or invalid
+    Certificate(NickError),
+    /// Kept sending faster than the rate limit
+    Flooding,
+    /// Didn't send anything, not even a pong, for the idle timeout
+    IdleTimeout,
+    /// An admin banned the client's address
+    Banned,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Accept(err) => write!(f, "failed to accept connection: {err}"),
+            ChatError::Read(err) => write!(f, "failed to read from client: {err}"),
+            ChatError::Write(err) => write!(f, "failed to write to client: {err}"),
+            ChatError::Protocol(err) => write!(f, "protocol error: {err}"),
+            ChatError::ChannelClosed => write!(f, "broadcast channel closed"),
+            ChatError::Lagged(n) => write!(f, "client fell {n} messages behind"),
+            ChatError::Spool(err) => write!(f, "lag spool failed: {err}"),
+            ChatError::Handshake(err) => write!(f, "WebSocket handshake failed: {err}"),
+            ChatError::Tls(err) => write!(f, "TLS handshake failed: {err}"),
+            ChatError::Certificate(err) => write!(f, "cannot use the client certificate: {err}"),
+            ChatError::Flooding => write!(f, "client kept sending too fast"),
+            ChatError::IdleTimeout => write!(f, "client went quiet, timed out"),
+            ChatError::Banned => write!(f, "client's address was banned"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatError::Accept(err)
+            | ChatError::Read(err)
+            | ChatError::Write(err)
+            | ChatError::Spool(err)
+            | ChatError::Handshake(err)
+            | ChatError::Tls(err) => Some(err),
+            ChatError::Protocol(err) => Some(err),
+            ChatError::Certificate(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Noticing clients that went away without closing the connection.
+//!
+//! A session that hasn't heard from its client for `ping_interval` sends a
+//! ping, which any client that is still there answers. One that hasn't heard
+//! anything for `idle_timeout` gives up on the client.
+
+use std::{future, time::Duration};
+
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+
+/// What a session should do next.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Beat {
+    /// Send a ping with this token
+    Ping(String),
+    TimedOut,
+}
+
+pub struct Heartbeat {
+    ping_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_heard: Instant,
+    pinged: bool,
+    /// Pings sent so far, also the token of the last one
+    pings: u64,
+}
+
+impl Heartbeat {
+    pub fn new(config: &Config) -> Heartbeat {
+        Heartbeat {
+            ping_interval: config.ping_interval,
+            idle_timeout: config.idle_timeout,
+            last_heard: Instant::now(),
+            pinged: false,
+            pings: 0,
+        }
+    }
+
+    /// The client sent something, so it is still there.
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        self.pinged = false;
+    }
+
+    /// Resolves when it is time to ping the client or to give up on it.
+    /// Never resolves if both are turned off. Cancel safe, so it can be
+    /// used in `select!`.
+    pub async fn next(&mut self) -> Beat {
+        let ping_at = (self.ping_interval)
+            .filter(|_| !self.pinged)
+            .map(|interval| self.last_heard + interval);
+        let timeout_at = self.idle_timeout.map(|timeout| self.last_heard + timeout);
+        let Some(wake_at) = ping_at.into_iter().chain(timeout_at).min() else {
+            return future::pending().await;
+        };
+        time::sleep_until(wake_at).await;
+        if Some(wake_at) == timeout_at {
+            return Beat::TimedOut;
+        }
+        self.pinged = true;
+        self.pings += 1;
+        Beat::Ping(self.pings.to_string())
+    }
+}
@@ -0,0 +1,376 @@
+//! Room history: every message sent to a room is appended to that room's
+//! file in the history directory, one JSON line each (`ServerFrame::to_json`),
+//! so it survives restarts and can be replayed to clients that join later.
+
+use std::{
+    collections::HashMap,
+    fs as std_fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{
+    json::Value,
+    protocol::{ServerFrame, Stamp},
+};
+
+/// Most messages one `/history` can ask for
+pub const MAX_HISTORY: usize = 500;
+
+/// How far past a retention limit a file may grow before it is trimmed, as
+/// a fraction of the limit. Without it steady traffic would rewrite the file
+/// on every message.
+const SLACK_DIVISOR: u32 = 4;
+
+/// Bytes read at a time when looking for the last lines of a file
+const TAIL_CHUNK: u64 = 8 * 1024;
+
+/// Room files kept open at once, the least recently written one is closed
+/// to make room. Clients can create any number of rooms.
+const MAX_OPEN_LOGS: usize = 64;
+
+/// Enough of the end of a file to hold its last line, even a maximum length
+/// message made of escaped characters
+const LAST_LINE_WINDOW: u64 = 64 * 1024;
+
+pub struct History {
+    dir: PathBuf,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    /// lowercased room name -> its open file, for recently written rooms
+    logs: Mutex<HashMap<String, RoomLog>>,
+}
+
+struct RoomLog {
+    file: File,
+    size: u64,
+    /// `ts` of the first entry, `None` while the file is empty
+    oldest: Option<u64>,
+    /// Last append, to find the log to close when too many are open
+    used: Instant,
+}
+
+impl History {
+    /// Files are opened as rooms are written to, this only creates the
+    /// directory and makes sure new message ids continue after the stored ones.
+    pub fn new(dir: &Path, max_age: Option<Duration>, max_size: Option<u64>) -> History {
+        if let Err(err) = std_fs::create_dir_all(dir) {
+            warn!("cannot create history directory {}: {err}", dir.display());
+        }
+        match last_stored_id(dir) {
+            Ok(Some(id)) => Stamp::resume_after(id),
+            Ok(None) => {}
+            Err(err) => warn!("cannot read history in {}: {err}", dir.display()),
+        }
+        History {
+            dir: dir.to_path_buf(),
+            max_age,
+            max_size,
+            logs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        // Room names are `#` and then characters that are safe in file names
+        let name = room.trim_start_matches('#').to_ascii_lowercase();
+        self.dir.join(format!("{name}.jsonl"))
+    }
+
+    /// Store a room message. Other frames are not kept.
+    pub async fn append(&self, frame: &ServerFrame) -> io::Result<()> {
+        let ServerFrame::Message { stamp, room, .. } = frame else {
+            return Ok(());
+        };
+        let mut logs = self.logs.lock().await;
+        let key = room.to_ascii_lowercase();
+        let log = match logs.get_mut(&key) {
+            Some(log) => log,
+            None => {
+                if logs.len() >= MAX_OPEN_LOGS {
+                    close_least_recent(&mut logs).await?;
+                }
+                let log = RoomLog::open(&self.path(room)).await?;
+                logs.entry(key).or_insert(log)
+            }
+        };
+        let line = format!("{}\n", frame.to_json());
+        log.file.write_all(line.as_bytes()).await?;
+        // tokio writes in the background, the line has to be in the file
+        // before `trim` or `recent` read it
+        log.file.flush().await?;
+        log.used = Instant::now();
+        log.size += line.len() as u64;
+        log.oldest.get_or_insert(stamp.ts);
+        if self.over_limits(log) {
+            *log = self.trim(&self.path(room)).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for every append to reach its file, tokio's files write in the
+    /// background.
+    pub async fn flush(&self) -> io::Result<()> {
+        for log in self.logs.lock().await.values_mut() {
+            log.file.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Up to `count` of the latest messages in `room`, oldest first.
+    pub async fn recent(&self, room: &str, count: usize) -> io::Result<Vec<ServerFrame>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        // Keeps appends out while the file is read
+        let _logs = self.logs.lock().await;
+        let cutoff = self.cutoff();
+        let frames = read_tail(&self.path(room), count)
+            .await?
+            .iter()
+            .filter_map(|line| Value::parse(line).ok())
+            .filter(|value| entry_ts(value).is_some_and(|ts| ts >= cutoff))
+            .filter_map(|value| ServerFrame::from_json(&value))
+            .collect();
+        Ok(frames)
+    }
+
+    /// Oldest `ts` that is still kept, 0 without an age limit.
+    fn cutoff(&self) -> u64 {
+        self.max_age.map_or(0, |age| {
+            unix_millis().saturating_sub(age.as_millis() as u64)
+        })
+    }
+
+    fn over_limits(&self, log: &RoomLog) -> bool {
+        let too_big = self
+            .max_size
+            .is_some_and(|max| log.size > max + max / u64::from(SLACK_DIVISOR));
+        let too_old = self.max_age.is_some_and(|age| {
+            let age = age + age / SLACK_DIVISOR;
+            log.oldest
+                .is_some_and(|oldest| oldest < unix_millis().saturating_sub(age.as_millis() as u64))
+        });
+        too_big || too_old
+    }
+
+    /// Rewrite the file at `path` without the entries that are past the
+    /// retention limits and reopen it.
+    async fn trim(&self, path: &Path) -> io::Result<RoomLog> {
+        let contents = fs::read_to_string(path).await?;
+        let cutoff = self.cutoff();
+        let mut kept = Vec::new();
+        let mut size = 0;
+        // Newest first, so the size limit drops the oldest entries
+        for line in contents.lines().rev() {
+            let line_size = line.len() as u64 + 1;
+            if self.max_size.is_some_and(|max| size + line_size > max) {
+                break;
+            }
+            let ts = Value::parse(line).ok().as_ref().and_then(entry_ts);
+            if ts.is_none_or(|ts| ts < cutoff) {
+                continue;
+            }
+            kept.push(line);
+            size += line_size;
+        }
+        let mut trimmed = String::with_capacity(size as usize);
+        for line in kept.iter().rev() {
+            trimmed.push_str(line);
+            trimmed.push('\n');
+        }
+        // Write next to it and rename, so a crash never leaves half a file
+        let tmp = path.with_extension("jsonl.tmp");
+        fs::write(&tmp, trimmed).await?;
+        fs::rename(&tmp, path).await?;
+        RoomLog::open(path).await
+    }
+}
+
+impl RoomLog {
+    async fn open(path: &Path) -> io::Result<RoomLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let size = file.metadata().await?.len();
+        let oldest = first_ts(path).await?;
+        Ok(RoomLog {
+            file,
+            size,
+            oldest,
+            used: Instant::now(),
+        })
+    }
+}
+
+async fn close_least_recent(logs: &mut HashMap<String, RoomLog>) -> io::Result<()> {
+    let oldest = (logs.iter())
+        .min_by_key(|(_, log)| log.used)
+        .map(|(room, _)| room.clone());
+    if let Some(mut log) = oldest.and_then(|room| logs.remove(&room)) {
+        log.file.flush().await?;
+    }
+    Ok(())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn entry_ts(value: &Value) -> Option<u64> {
+    value.get("ts")?.as_u64()
+}
+
+async fn first_ts(path: &Path) -> io::Result<Option<u64>> {
+    let mut first = Vec::new();
+    BufReader::new(File::open(path).await?)
+        .read_until(b'\n', &mut first)
+        .await?;
+    let first = String::from_utf8_lossy(&first);
+    Ok(Value::parse(&first).ok().as_ref().and_then(entry_ts))
+}
+
+/// Last `count` lines of the file, without reading all of it. A missing file
+/// has no lines.
+async fn read_tail(path: &Path, count: usize) -> io::Result<Vec<String>> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut start = file.metadata().await?.len();
+    let mut tail = Vec::new();
+    let mut newlines = 0;
+    // The file ends with a newline, so `count` lines need one more before them
+    while start > 0 && newlines <= count {
+        let step = start.min(TAIL_CHUNK);
+        start -= step;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut chunk = vec![0; step as usize];
+        file.read_exact(&mut chunk).await?;
+        newlines += chunk.iter().filter(|b| **b == b'\n').count();
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+    }
+    let tail = String::from_utf8_lossy(&tail);
+    let lines: Vec<&str> = tail.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+/// Highest message id in any history file. Only called on startup, so it
+/// is fine to block.
+fn last_stored_id(dir: &Path) -> io::Result<Option<u64>> {
+    let mut last = None;
+    for entry in std_fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "jsonl") {
+            continue;
+        }
+        let mut file = std_fs::File::open(&path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(LAST_LINE_WINDOW)))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let id = String::from_utf8_lossy(&tail)
+            .lines()
+            .filter_map(|line| Value::parse(line).ok()?.get("id")?.as_u64())
+            .max();
+        last = last.max(id);
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory per test, tests run in parallel
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-history-{}-{name}", std::process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn message(room: &str, text: &str) -> ServerFrame {
+        ServerFrame::Message {
+            stamp: Stamp::new(),
+            room: room.to_string(),
+            from: "alice".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(frames: &[ServerFrame]) -> Vec<&str> {
+        (frames.iter())
+            .filter_map(|frame| match frame {
+                ServerFrame::Message { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn appended_messages_are_read_back_at_once() {
+        let dir = dir("read-back");
+        let history = History::new(&dir, None, None);
+        for text in ["one", "two", "three"] {
+            history.append(&message("#Rust", text)).await.unwrap();
+        }
+        history
+            .append(&ServerFrame::Ack("not kept".into()))
+            .await
+            .unwrap();
+        let recent = history.recent("#rust", 2).await.unwrap();
+        assert_eq!(texts(&recent), ["two", "three"]);
+        assert_eq!(history.recent("#other", 2).await.unwrap(), []);
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trimming_keeps_the_newest_messages() {
+        let dir = dir("trim");
+        // Ids are global, keep them the same width whatever other tests did
+        Stamp::resume_after(99_999);
+        let line = format!("{}\n", message("#rust", "0000").to_json()).len() as u64;
+        let history = History::new(&dir, None, Some(4 * line));
+        for i in 0..20 {
+            history
+                .append(&message("#rust", &format!("{i:04}")))
+                .await
+                .unwrap();
+        }
+        let recent = history.recent("#rust", MAX_HISTORY).await.unwrap();
+        let texts = texts(&recent);
+        assert!(texts.len() >= 4 && texts.len() <= 5, "{texts:?}");
+        assert_eq!(texts.last(), Some(&"0019"));
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_files_are_bounded() {
+        let dir = dir("open-files");
+        let history = History::new(&dir, None, None);
+        for i in 0..MAX_OPEN_LOGS + 10 {
+            let room = format!("#room{i}");
+            history.append(&message(&room, "first")).await.unwrap();
+        }
+        assert_eq!(history.logs.lock().await.len(), MAX_OPEN_LOGS);
+        // A closed room's file is reopened for the next message
+        history.append(&message("#room0", "second")).await.unwrap();
+        let recent = history.recent("#room0", 10).await.unwrap();
+        assert_eq!(texts(&recent), ["first", "second"]);
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+}
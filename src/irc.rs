@@ -0,0 +1,744 @@
+//! IRC front-end, so standard clients (irssi, weechat, ...) can connect on
+//! their own port.
+//!
+//! IRC channels are the server's rooms and IRC nicks its nicknames, so IRC
+//! users share the bus, rooms and private messages with everyone else. Only
+//! the subset of RFC 2812 clients need day to day is understood: NICK, USER,
+//! JOIN, PART, PRIVMSG, NOTICE, PING, QUIT, WHO, TOPIC, NAMES, LIST and MODE
+//! (read only), plus enough of CAP to let IRCv3 clients get past it.
+//!
+//! The server password (PASS) is taken as the password of the account
+//! matching the nick, so IRC users can use a nick registered with
+//! `/register`.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use crate::{
+    accounts::AccountError,
+    codec::{Decoder, Encoder, FramedRead, FramedWrite, Lines},
+    connection::{publish, replay, MAILBOX_CAPACITY, SHUTDOWN_NOTICE},
+    error::ChatError,
+    heartbeat::{Beat, Heartbeat},
+    lag::Inbox,
+    plugin::Flow,
+    protocol::{Event, ProtocolError, ServerFrame, Stamp},
+    ratelimit::{Limiter, Verdict},
+    rooms::RoomError,
+    state::State,
+    users::{Delivery, MsgError, NickError, Registration},
+};
+
+/// Name the server uses as the prefix of its own messages
+const SERVER_NAME: &str = "chat.server";
+
+/// One line from the client: `[:prefix] COMMAND param... [:trailing]`. The
+/// prefix is dropped, clients have no business sending one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrcMessage {
+    /// Uppercased
+    pub command: String,
+    /// Includes the trailing parameter, if any, as the last one
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    /// `None` for lines without a command.
+    pub fn parse(line: &str) -> Option<IrcMessage> {
+        let mut line = line.strip_suffix('\r').unwrap_or(line);
+        // IRCv3 tags and the prefix
+        for marker in ['@', ':'] {
+            if line.starts_with(marker) {
+                line = line.split_once(' ').map_or("", |(_, rest)| rest);
+            }
+        }
+        let (middle, trailing) = match line.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (line, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        let mut params: Vec<String> = words.map(str::to_string).collect();
+        params.extend(trailing.map(str::to_string));
+        Some(IrcMessage { command, params })
+    }
+
+    fn param(&self, index: usize) -> Option<&str> {
+        self.params.get(index).map(String::as_str)
+    }
+}
+
+/// Decodes `IrcMessage`s and writes lines with the `\r\n` IRC wants. Bytes
+/// that aren't UTF-8 are replaced rather than refused, many IRC clients still
+/// send Latin-1. Line breaks and NULs inside a line written are replaced
+/// with spaces, so text from other clients can't end it early.
+#[derive(Debug)]
+pub struct IrcCodec {
+    lines: Lines,
+}
+
+impl IrcCodec {
+    pub fn new(max_length: usize) -> IrcCodec {
+        IrcCodec {
+            lines: Lines::new(max_length),
+        }
+    }
+}
+
+impl Decoder for IrcCodec {
+    type Item = IrcMessage;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<IrcMessage>, ProtocolError> {
+        while let Some(line) = self.lines.next_line(src)? {
+            if let Some(message) = IrcMessage::parse(&String::from_utf8_lossy(&line)) {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<IrcMessage>, ProtocolError> {
+        if let Some(message) = self.decode(src)? {
+            return Ok(Some(message));
+        }
+        Ok(self
+            .lines
+            .last_line(src)
+            .and_then(|line| IrcMessage::parse(&String::from_utf8_lossy(&line))))
+    }
+}
+
+impl Encoder<String> for IrcCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, line: String, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        dst.reserve(line.len() + 2);
+        dst.extend(line.bytes().map(|b| match b {
+            b'\r' | b'\n' | b'\0' => b' ',
+            b => b,
+        }));
+        dst.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+type IrcReader<S> = FramedRead<io::ReadHalf<S>, IrcCodec>;
+type IrcWriter<S> = FramedWrite<io::WriteHalf<S>, IrcCodec>;
+
+/// Run one IRC client until it quits (`Ok`) or its session fails (`Err`).
+pub async fn handle_irc<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    addr: SocketAddr,
+    state: Arc<State>,
+) -> Result<(), ChatError> {
+    let (stream_reader, stream_writer) = io::split(stream);
+    let max_length = state.config.max_line_length;
+    let decoder = IrcCodec::new(max_length);
+    let mut lines: IrcReader<S> = FramedRead::new(stream_reader, decoder, max_length + 1);
+    let mut out: IrcWriter<S> = FramedWrite::new(stream_writer, IrcCodec::new(max_length));
+    let mut long_lines = 0;
+    let mut heartbeat = Heartbeat::new(&state.config);
+    let (mailbox_send, mut mailbox) = mpsc::channel(MAILBOX_CAPACITY);
+    let mut limiter = Limiter::new(&state.config);
+    // A command over the rate limit waits here until `resume_at`, and
+    // nothing more is read from the client in the meantime
+    let mut held = None;
+    let mut resume_at = Instant::now();
+
+    // Registration needs both NICK and USER, in either order
+    let mut nick: Option<String> = None;
+    let mut password: Option<String> = None;
+    let mut got_user = false;
+    let mut user = loop {
+        let message = tokio::select! {
+            message = next_message(&mut lines, &mut long_lines, &state), if held.is_none() => {
+                heartbeat.heard();
+                match message? {
+                    Some(message) => {
+                        let me = nick.as_deref().unwrap_or("*");
+                        let verdict = limiter.check(false, None, 0);
+                        if let Some(wait) = throttle(verdict, me, &mut out).await? {
+                            resume_at = Instant::now() + wait;
+                            held = Some(message);
+                            continue;
+                        }
+                        Some(message)
+                    }
+                    None => None,
+                }
+            }
+            () = time::sleep_until(resume_at), if held.is_some() => held.take(),
+            beat = heartbeat.next() => match beat {
+                Beat::Ping(_) => {
+                    send(&mut out, format!("PING :{SERVER_NAME}")).await?;
+                    continue;
+                }
+                Beat::TimedOut => return Err(ChatError::IdleTimeout),
+            },
+            () = state.access.banned(addr.ip()) => return banned(&mut out).await,
+            () = state.shutting_down() => return say_goodbye(&mut out, "*").await,
+        };
+        let Some(message) = message else {
+            return Ok(());
+        };
+        let me = nick.as_deref().unwrap_or("*");
+        match message.command.as_str() {
+            "NICK" => match message.param(0) {
+                Some(wanted) => nick = Some(wanted.to_string()),
+                None => send(&mut out, numeric("431", me, ":No nickname given")).await?,
+            },
+            "USER" if message.params.len() < 4 => {
+                send(&mut out, numeric("461", me, "USER :Not enough parameters")).await?
+            }
+            "USER" => got_user = true,
+            "CAP" if message.param(0) == Some("LS") => {
+                send(&mut out, format!(":{SERVER_NAME} CAP * LS :")).await?
+            }
+            "PASS" => password = message.param(0).map(str::to_string),
+            "CAP" | "PONG" => {}
+            "PING" => send(&mut out, pong(&message)).await?,
+            "QUIT" => return Ok(()),
+            _ => send(&mut out, numeric("451", me, ":You have not registered")).await?,
+        }
+        let (Some(wanted), true) = (&nick, got_user) else {
+            continue;
+        };
+        let registered = match (&password, &state.accounts) {
+            (Some(password), Some(accounts)) => {
+                match accounts.verify(wanted, password, addr.ip()).await {
+                    Ok(account) => {
+                        Registration::authenticated(account, addr, mailbox_send.clone(), &state)
+                            .map_err(AccountError::Nick)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            _ => Registration::register(wanted, addr, mailbox_send.clone(), &state)
+                .map_err(AccountError::Nick),
+        };
+        match registered {
+            Ok(user) => break user,
+            Err(AccountError::Nick(err)) => {
+                send(&mut out, nick_error(&err, "*", wanted)).await?;
+                nick = None;
+            }
+            Err(err) => {
+                send(&mut out, numeric("464", "*", &format!(":{err}"))).await?;
+                nick = None;
+            }
+        }
+    };
+
+    let me = user.nick.clone();
+    for welcome in [
+        numeric("001", &me, &format!(":Welcome to the chat server {me}")),
+        numeric("002", &me, &format!(":Your host is {SERVER_NAME}")),
+        numeric("003", &me, ":This server speaks a small part of IRC"),
+        numeric("004", &me, &format!("{SERVER_NAME} rust-tokio-chat o o")),
+        numeric("422", &me, ":MOTD File is missing"),
+    ] {
+        send(&mut out, welcome).await?;
+    }
+    let hooked = state.plugins.connect(&user, &state).await;
+    for reply in hooked.replies {
+        send_frame(&mut out, reply, &me).await?;
+    }
+    if hooked.flow == Flow::Drop {
+        send(&mut out, "ERROR :Closing link".to_string()).await?;
+        user.leave("refused");
+        return Ok(());
+    }
+    for private in state.users.take_offline(&user.nick) {
+        send_frame(&mut out, private, &user.nick).await?;
+    }
+    if let Some(room) = state.config.default_room.clone() {
+        join(&mut out, &room, &mut user, &state).await?;
+    }
+
+    let mut inbox = Inbox::new(addr, &state);
+
+    loop {
+        tokio::select! {
+            message = next_message(&mut lines, &mut long_lines, &state), if held.is_none() => {
+                heartbeat.heard();
+                let Some(message) = message? else {
+                    return Ok(());
+                };
+                let (room, bytes) = match (message.command.as_str(), message.param(0)) {
+                    ("PRIVMSG" | "NOTICE", Some(target)) => {
+                        let bytes = message.param(1).map_or(0, str::len);
+                        (Some(target).filter(|target| target.starts_with('#')), bytes)
+                    }
+                    _ => (None, 0),
+                };
+                let verdict = limiter.check(user.account.is_some(), room, bytes);
+                if let Some(wait) = throttle(verdict, &user.nick, &mut out).await? {
+                    resume_at = Instant::now() + wait;
+                    held = Some(message);
+                    continue;
+                }
+                if !handle_message(&mut out, message, &mut user, &state).await? {
+                    send(&mut out, "ERROR :Closing link".to_string()).await?;
+                    return Ok(());
+                }
+            }
+            () = time::sleep_until(resume_at), if held.is_some() => {
+                let message = held.take().expect("only runs with a held message");
+                if !handle_message(&mut out, message, &mut user, &state).await? {
+                    send(&mut out, "ERROR :Closing link".to_string()).await?;
+                    return Ok(());
+                }
+            }
+            recv_msg = inbox.recv() => match recv_msg {
+                Ok(frame) => send_frame(&mut out, frame, &user.nick).await?,
+                Err(ChatError::Lagged(n)) => {
+                    // Best effort, the client is about to be dropped anyway
+                    let reason = format!("ERROR :Closing link (fell {n} messages behind)");
+                    let _ = send(&mut out, reason).await;
+                    return Err(ChatError::Lagged(n));
+                }
+                Err(err) => return Err(err),
+            },
+            // The registry holds a sender for as long as `user` is alive
+            Some(private) = mailbox.recv() => {
+                send_frame(&mut out, private, &user.nick).await?;
+            }
+            beat = heartbeat.next() => match beat {
+                Beat::Ping(_) => send(&mut out, format!("PING :{SERVER_NAME}")).await?,
+                Beat::TimedOut => {
+                    // Best effort, the client is likely gone
+                    let _ = send(&mut out, "ERROR :Closing link (Ping timeout)".to_string()).await;
+                    user.leave("timeout");
+                    return Err(ChatError::IdleTimeout);
+                }
+            },
+            () = state.access.banned(addr.ip()) => {
+                user.leave("banned");
+                return banned(&mut out).await;
+            }
+            () = state.shutting_down() => return say_goodbye(&mut out, &user.nick).await,
+        }
+    }
+}
+
+/// End the session of a client whose address was just banned.
+async fn banned<S: AsyncWrite>(out: &mut IrcWriter<S>) -> Result<(), ChatError> {
+    // Best effort, the client is about to be dropped anyway
+    let _ = send(out, "ERROR :Closing link (Banned)".to_string()).await;
+    Err(ChatError::Banned)
+}
+
+async fn say_goodbye<S: AsyncWrite>(out: &mut IrcWriter<S>, me: &str) -> Result<(), ChatError> {
+    send(
+        out,
+        format!(":{SERVER_NAME} NOTICE {me} :{SHUTDOWN_NOTICE}"),
+    )
+    .await?;
+    send(
+        out,
+        "ERROR :Closing link (Server shutting down)".to_string(),
+    )
+    .await
+}
+
+/// Next message, or `None` once the client is gone. Over-long lines are
+/// skipped, IRC has no way to answer a line it couldn't read, but too many
+/// of them end the session.
+async fn next_message<S: AsyncRead>(
+    lines: &mut IrcReader<S>,
+    long_lines: &mut u32,
+    state: &State,
+) -> Result<Option<IrcMessage>, ChatError> {
+    loop {
+        match lines.next().await {
+            None => return Ok(None),
+            Some(Ok(message)) => return Ok(Some(message)),
+            Some(Err(ProtocolError::Io(err))) => return Err(ChatError::Read(err)),
+            Some(Err(err @ ProtocolError::LineTooLong(_))) => {
+                *long_lines += 1;
+                let limit = state.config.max_long_lines;
+                if limit > 0 && *long_lines >= limit {
+                    return Err(ChatError::Protocol(err));
+                }
+            }
+            Some(Err(_)) => {}
+        }
+    }
+}
+
+async fn send<S: AsyncWrite>(out: &mut IrcWriter<S>, line: String) -> Result<(), ChatError> {
+    out.send(line).await.map_err(|err| match err {
+        ProtocolError::Io(err) => ChatError::Write(err),
+        err => ChatError::Protocol(err),
+    })
+}
+
+/// How long to hold back a command that is over the rate limit, `None` if
+/// it can go through now. Ends the session of a client that keeps flooding.
+async fn throttle<S: AsyncWrite>(
+    verdict: Verdict,
+    me: &str,
+    out: &mut IrcWriter<S>,
+) -> Result<Option<Duration>, ChatError> {
+    match verdict {
+        Verdict::Pass => Ok(None),
+        Verdict::Throttle { wait, warn } => {
+            if warn {
+                let warning =
+                    format!(":{SERVER_NAME} NOTICE {me} :You are sending too fast, slow down");
+                send(out, warning).await?;
+            }
+            Ok(Some(wait))
+        }
+        Verdict::Kick => {
+            // Best effort, the client is about to be dropped anyway
+            let _ = send(out, "ERROR :Closing link (Excess Flood)".to_string()).await;
+            Err(ChatError::Flooding)
+        }
+    }
+}
+
+/// Write a frame from the bus or the mailbox, if IRC has a way to show it.
+async fn send_frame<S: AsyncWrite>(
+    out: &mut IrcWriter<S>,
+    frame: ServerFrame,
+    me: &str,
+) -> Result<(), ChatError> {
+    match to_irc(frame, me) {
+        Some(line) => send(out, line).await,
+        None => Ok(()),
+    }
+}
+
+/// Run one command from a registered client. Returns `false` when the client
+/// quits.
+async fn handle_message<S: AsyncWrite>(
+    out: &mut IrcWriter<S>,
+    message: IrcMessage,
+    user: &mut Registration,
+    state: &State,
+) -> Result<bool, ChatError> {
+    let me = user.nick.clone();
+    let missing = || {
+        numeric(
+            "461",
+            &me,
+            &format!("{} :Not enough parameters", message.command),
+        )
+    };
+    match message.command.as_str() {
+        "PING" => send(out, pong(&message)).await?,
+        "PONG" | "CAP" => {}
+        "QUIT" => return Ok(false),
+        "USER" | "PASS" => send(out, numeric("462", &me, ":You may not reregister")).await?,
+        "NICK" => match message.param(0) {
+            None => send(out, numeric("431", &me, ":No nickname given")).await?,
+            Some(new_nick) => match user.rename(new_nick) {
+                Ok(()) => send(out, format!(":{} NICK :{new_nick}", mask(&me))).await?,
+                Err(err) => send(out, nick_error(&err, &me, new_nick)).await?,
+            },
+        },
+        "JOIN" => match message.param(0) {
+            None => send(out, missing()).await?,
+            Some(rooms) => {
+                for room in rooms.split(',') {
+                    join(out, room, user, state).await?;
+                }
+            }
+        },
+        "PART" => match message.param(0) {
+            None => send(out, missing()).await?,
+            Some(rooms) => {
+                for room in rooms.split(',') {
+                    let reply = match user.part(room) {
+                        Ok(_) => format!(":{} PART {room}", mask(&me)),
+                        Err(_) => {
+                            numeric("442", &me, &format!("{room} :You're not on that channel"))
+                        }
+                    };
+                    send(out, reply).await?;
+                }
+            }
+        },
+        command @ ("PRIVMSG" | "NOTICE") => {
+            let (Some(target), Some(text)) = (message.param(0), message.param(1)) else {
+                send(out, missing()).await?;
+                return Ok(true);
+            };
+            // NOTICE must never trigger an automatic reply
+            let replies = say(target, text, user, state).await?;
+            if command == "PRIVMSG" {
+                for reply in replies {
+                    send(out, reply).await?;
+                }
+            }
+        }
+        "TOPIC" => match (message.param(0), message.param(1)) {
+            (None, _) => send(out, missing()).await?,
+            (Some(room), None) => send(out, topic_reply(room, &me, state)).await?,
+            (Some(room), Some(topic)) => {
+                let reply = match user.set_topic(room, topic) {
+                    Ok(room) => format!(":{} TOPIC {room} :{topic}", mask(&me)),
+                    Err(RoomError::TopicTooLong) => {
+                        format!(":{SERVER_NAME} NOTICE {me} :{}", RoomError::TopicTooLong)
+                    }
+                    Err(_) => numeric("442", &me, &format!("{room} :You're not on that channel")),
+                };
+                send(out, reply).await?;
+            }
+        },
+        "NAMES" => match message.param(0) {
+            Some(room) => send_names(out, room, &me, state).await?,
+            None => send(out, numeric("366", &me, "* :End of /NAMES list")).await?,
+        },
+        "WHO" => {
+            let room = message.param(0).unwrap_or("*");
+            for nick in state.rooms.members(room).unwrap_or_default() {
+                let who = format!("{room} {nick} {SERVER_NAME} {SERVER_NAME} {nick} H :0 {nick}");
+                send(out, numeric("352", &me, &who)).await?;
+            }
+            send(
+                out,
+                numeric("315", &me, &format!("{room} :End of /WHO list")),
+            )
+            .await?;
+        }
+        "LIST" => {
+            send(out, numeric("321", &me, "Channel :Users  Name")).await?;
+            for (room, members) in state.rooms.list() {
+                let topic = state.rooms.topic(&room).unwrap_or_default();
+                let entry = format!("{room} {members} :{topic}");
+                send(out, numeric("322", &me, &entry)).await?;
+            }
+            send(out, numeric("323", &me, ":End of /LIST")).await?;
+        }
+        "MODE" => match message.param(0) {
+            None => send(out, missing()).await?,
+            Some(room) if room.starts_with('#') => {
+                send(out, numeric("324", &me, &format!("{room} +"))).await?
+            }
+            Some(_) => send(out, numeric("221", &me, "+")).await?,
+        },
+        command => {
+            let reply = numeric("421", &me, &format!("{command} :Unknown command"));
+            send(out, reply).await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Join `room` the IRC way: echo the JOIN, then the topic and the names.
+/// Replays recent history if the user wasn't in the room yet.
+async fn join<S: AsyncWrite>(
+    out: &mut IrcWriter<S>,
+    room: &str,
+    user: &mut Registration,
+    state: &State,
+) -> Result<(), ChatError> {
+    let me = user.nick.clone();
+    let new_member = !state.rooms.is_member(room, user.addr);
+    if new_member {
+        let hooked = state.plugins.join(user, room, state).await;
+        for reply in hooked.replies {
+            send_frame(out, reply, &me).await?;
+        }
+        if hooked.flow == Flow::Drop {
+            return Ok(());
+        }
+    }
+    if let Err(err) = user.join(room) {
+        let code = match err {
+            RoomError::AuthRequired(_) => "477",
+            _ => "403",
+        };
+        let reply = numeric(code, &me, &format!("{room} :{err}"));
+        return send(out, reply).await;
+    }
+    let Some(room) = user.room.clone().filter(|_| new_member) else {
+        return Ok(());
+    };
+    send(out, format!(":{} JOIN {room}", mask(&me))).await?;
+    send(out, topic_reply(&room, &me, state)).await?;
+    send_names(out, &room, &me, state).await?;
+    for frame in replay(&room, state.config.history_replay, state).await {
+        send_frame(out, frame, &me).await?;
+    }
+    Ok(())
+}
+
+/// PRIVMSG to a channel or a nick. Returns the replies: an error, or what
+/// plugins had to say.
+async fn say(
+    target: &str,
+    text: &str,
+    user: &Registration,
+    state: &State,
+) -> Result<Vec<String>, ChatError> {
+    let me = &user.nick;
+    if target.starts_with('#') {
+        let room = state
+            .rooms
+            .joined(user.addr)
+            .into_iter()
+            .find(|joined| joined.eq_ignore_ascii_case(target));
+        let Some(room) = room else {
+            let reply = numeric("404", me, &format!("{target} :Cannot send to channel"));
+            return Ok(vec![reply]);
+        };
+        let replies = publish(room, text.to_string(), user, state).await?;
+        return Ok(replies
+            .into_iter()
+            .filter_map(|frame| to_irc(frame, me))
+            .collect());
+    }
+    let frame = ServerFrame::Private {
+        stamp: Stamp::new(),
+        from: me.clone(),
+        text: text.to_string(),
+    };
+    let reply = match state.users.deliver(target, frame) {
+        Ok(Delivery::Sent(_)) => None,
+        Ok(Delivery::Queued) => Some(format!(
+            ":{SERVER_NAME} NOTICE {me} :{target} is offline, they will get it when they connect"
+        )),
+        Err(MsgError::Offline(_)) => Some(numeric("401", me, &format!("{target} :No such nick"))),
+        Err(err) => Some(format!(":{SERVER_NAME} NOTICE {me} :{err}")),
+    };
+    Ok(reply.into_iter().collect())
+}
+
+async fn send_names<S: AsyncWrite>(
+    out: &mut IrcWriter<S>,
+    room: &str,
+    me: &str,
+    state: &State,
+) -> Result<(), ChatError> {
+    if let Some(members) = state.rooms.members(room) {
+        let names = format!("= {room} :{}", members.join(" "));
+        send(out, numeric("353", me, &names)).await?;
+    }
+    send(
+        out,
+        numeric("366", me, &format!("{room} :End of /NAMES list")),
+    )
+    .await
+}
+
+fn topic_reply(room: &str, me: &str, state: &State) -> String {
+    match state.rooms.topic(room) {
+        Some(topic) => numeric("332", me, &format!("{room} :{topic}")),
+        None => numeric("331", me, &format!("{room} :No topic is set")),
+    }
+}
+
+/// How a frame from the bus or the mailbox looks to an IRC client, `None`
+/// for frames IRC has nothing for. Texts can come from plugins and bots as
+/// well, `IrcCodec` keeps line breaks in them from ending the line.
+fn to_irc(frame: ServerFrame, me: &str) -> Option<String> {
+    Some(match frame {
+        ServerFrame::Message {
+            room, from, text, ..
+        } => format!(":{} PRIVMSG {room} :{text}", mask(&from)),
+        ServerFrame::Private { from, text, .. } => {
+            format!(":{} PRIVMSG {me} :{text}", mask(&from))
+        }
+        ServerFrame::Notice { room, text, .. } => {
+            let target = room.as_deref().unwrap_or(me);
+            format!(":{SERVER_NAME} NOTICE {target} :{text}")
+        }
+        ServerFrame::Event {
+            room, nick, event, ..
+        } => match (event, room) {
+            // IRC doesn't announce connections, only channel joins
+            (Event::Join, None) => return None,
+            (Event::Join, Some(room)) => format!(":{} JOIN {room}", mask(&nick)),
+            (Event::Part(None), None) => format!(":{} QUIT :Quit", mask(&nick)),
+            (Event::Part(Some(reason)), None) => format!(":{} QUIT :{reason}", mask(&nick)),
+            (Event::Part(_), Some(room)) => format!(":{} PART {room}", mask(&nick)),
+            (Event::Nick(new_nick), _) => format!(":{} NICK :{new_nick}", mask(&nick)),
+            (Event::Topic(topic), room) => {
+                let room = room.unwrap_or_default();
+                format!(":{} TOPIC {room} :{topic}", mask(&nick))
+            }
+        },
+        ServerFrame::Hello { .. }
+        | ServerFrame::Names { .. }
+        | ServerFrame::Ack(_)
+        | ServerFrame::Error(_)
+        | ServerFrame::Ping(_) => return None,
+    })
+}
+
+/// `nick!user@host` for messages from a user. Addresses are nobody's business.
+fn mask(nick: &str) -> String {
+    format!("{nick}!{nick}@{SERVER_NAME}")
+}
+
+fn numeric(code: &str, me: &str, rest: &str) -> String {
+    format!(":{SERVER_NAME} {code} {me} {rest}")
+}
+
+fn pong(ping: &IrcMessage) -> String {
+    format!(
+        ":{SERVER_NAME} PONG {SERVER_NAME} :{}",
+        ping.param(0).unwrap_or(SERVER_NAME)
+    )
+}
+
+fn nick_error(err: &NickError, me: &str, wanted: &str) -> String {
+    match err {
+        NickError::Taken(_) => numeric("433", me, &format!("{wanted} :Nickname is already in use")),
+        NickError::Reserved(_) => numeric(
+            "433",
+            me,
+            &format!("{wanted} :Nickname is registered, connect with its password as PASS"),
+        ),
+        NickError::Empty => numeric("431", me, ":No nickname given"),
+        err => numeric("432", me, &format!("{wanted} :Erroneous nickname ({err})")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(line: &str) -> String {
+        let mut dst = BytesMut::new();
+        IrcCodec::new(512)
+            .encode(line.to_string(), &mut dst)
+            .unwrap();
+        String::from_utf8(dst.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn texts_cannot_end_the_line() {
+        let frame = ServerFrame::Message {
+            stamp: Stamp { id: 1, ts: 0 },
+            room: "#rust".to_string(),
+            from: "mallory".to_string(),
+            text: "hi\r:server 001 bob :spoofed\n\0".to_string(),
+        };
+        assert_eq!(
+            encode(&to_irc(frame, "bob").unwrap()),
+            ":mallory!mallory@chat.server PRIVMSG #rust :hi :server 001 bob :spoofed  \r\n"
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        let message = IrcMessage::parse(":bob PRIVMSG #rust :hello there").unwrap();
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, ["#rust", "hello there"]);
+        let message = IrcMessage::parse("nick  alice").unwrap();
+        assert_eq!(message.command, "NICK");
+        assert_eq!(message.params, ["alice"]);
+        assert!(IrcMessage::parse("   ").is_none());
+    }
+}
@@ -0,0 +1,433 @@
+//! Minimal JSON support for the JSON-lines protocol mode: a `Value` type, a
+//! parser and a compact writer. Numbers are kept as `f64`, which is exact
+//! for the ids and millisecond timestamps the protocol uses.
+
+use std::{fmt, str::Chars};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys keep their order so output is stable
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    /// String field of an object, `None` if missing, null or not a string.
+    pub fn str_field(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+
+    pub fn parse(src: &str) -> Result<Value, JsonError> {
+        let mut parser = Parser {
+            chars: src.chars(),
+            peeked: None,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.next() {
+            None => Ok(value),
+            Some(c) => Err(JsonError::Unexpected(c)),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) if n.is_finite() => write!(f, "{n}"),
+            // JSON has no NaN or infinity
+            Value::Number(_) => f.write_str("null"),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JsonError {
+    UnexpectedEnd,
+    Unexpected(char),
+    BadNumber,
+    BadEscape,
+    TooDeep,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnexpectedEnd => write!(f, "unexpected end of JSON"),
+            JsonError::Unexpected(c) => write!(f, "unexpected `{c}` in JSON"),
+            JsonError::BadNumber => write!(f, "invalid number in JSON"),
+            JsonError::BadEscape => write!(f, "invalid escape in JSON string"),
+            JsonError::TooDeep => write!(f, "JSON nested too deeply"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Protocol objects are flat, this only stops hostile input from blowing
+/// the stack.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    chars: Chars<'a>,
+    peeked: Option<char>,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        self.peeked.take().or_else(|| self.chars.next())
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        if self.peeked.is_none() {
+            self.peeked = self.chars.next();
+        }
+        self.peeked
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(JsonError::Unexpected(c)),
+            None => Err(JsonError::UnexpectedEnd),
+        }
+    }
+
+    fn literal(&mut self, rest: &str, value: Value) -> Result<Value, JsonError> {
+        for expected in rest.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        self.nested(0)
+    }
+
+    fn nested(&mut self, depth: usize) -> Result<Value, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        self.skip_whitespace();
+        match self.next().ok_or(JsonError::UnexpectedEnd)? {
+            'n' => self.literal("ull", Value::Null),
+            't' => self.literal("rue", Value::Bool(true)),
+            'f' => self.literal("alse", Value::Bool(false)),
+            '"' => self.string().map(Value::String),
+            '[' => {
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.next();
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.nested(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(items)),
+                        Some(c) => return Err(JsonError::Unexpected(c)),
+                        None => return Err(JsonError::UnexpectedEnd),
+                    }
+                }
+            }
+            '{' => {
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.next();
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    self.expect('"')?;
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(':')?;
+                    fields.push((key, self.nested(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some('}') => return Ok(Value::Object(fields)),
+                        Some(c) => return Err(JsonError::Unexpected(c)),
+                        None => return Err(JsonError::UnexpectedEnd),
+                    }
+                }
+            }
+            c @ ('-' | '0'..='9') => self.number(c),
+            c => Err(JsonError::Unexpected(c)),
+        }
+    }
+
+    fn number(&mut self, first: char) -> Result<Value, JsonError> {
+        let mut text = String::from(first);
+        while let Some(c @ ('0'..='9' | '.' | 'e' | 'E' | '+' | '-')) = self.peek() {
+            text.push(c);
+            self.next();
+        }
+        text.parse()
+            .map(Value::Number)
+            .map_err(|_| JsonError::BadNumber)
+    }
+
+    /// Rest of a string after its opening quote.
+    fn string(&mut self) -> Result<String, JsonError> {
+        let mut out = String::new();
+        loop {
+            match self.next().ok_or(JsonError::UnexpectedEnd)? {
+                '"' => return Ok(out),
+                '\\' => match self.next().ok_or(JsonError::UnexpectedEnd)? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => out.push(self.unicode_escape()?),
+                    _ => return Err(JsonError::BadEscape),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next().ok_or(JsonError::UnexpectedEnd)?;
+            code = code * 16 + digit.to_digit(16).ok_or(JsonError::BadEscape)?;
+        }
+        Ok(code)
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or(JsonError::BadEscape);
+        }
+        // Surrogate pair
+        self.expect('\\').map_err(|_| JsonError::BadEscape)?;
+        self.expect('u').map_err(|_| JsonError::BadEscape)?;
+        let low = self.hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(JsonError::BadEscape);
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .ok_or(JsonError::BadEscape)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(fields: &[(&str, Value)]) -> Value {
+        Value::Object(
+            (fields.iter())
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_values() {
+        let value = Value::parse(
+            r#" {"type":"msg", "n": -1.5e2, "ok": true, "no": false, "none": null,
+                "list": [1, [], {}], "to": "bob"} "#,
+        )
+        .unwrap();
+        let expected = object(&[
+            ("type", Value::from("msg")),
+            ("n", Value::Number(-150.0)),
+            ("ok", Value::Bool(true)),
+            ("no", Value::Bool(false)),
+            ("none", Value::Null),
+            (
+                "list",
+                Value::Array(vec![
+                    Value::Number(1.0),
+                    Value::Array(Vec::new()),
+                    Value::Object(Vec::new()),
+                ]),
+            ),
+            ("to", Value::from("bob")),
+        ]);
+        assert_eq!(value, expected);
+        assert_eq!(value.str_field("to"), Some("bob"));
+        assert_eq!(value.str_field("n"), None);
+        assert_eq!(value.str_field("none"), None);
+        assert_eq!(value.get("n").and_then(Value::as_u64), None);
+    }
+
+    #[test]
+    fn string_escapes() {
+        let value = Value::parse(r#""a\"b\\c\/d\n\r\t\b\f é 🦀""#).unwrap();
+        assert_eq!(
+            value.as_str(),
+            Some("a\"b\\c/d\n\r\t\u{8}\u{c} é \u{1F980}")
+        );
+        for bad in [
+            r#""\x""#,
+            r#""\u12""#,
+            r#""\ud83e""#,
+            r#""\ud83eA""#,
+            r#""\udc00""#,
+        ] {
+            assert!(Value::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("", JsonError::UnexpectedEnd),
+            ("[1,", JsonError::UnexpectedEnd),
+            (r#"{"a" 1}"#, JsonError::Unexpected('1')),
+            ("[1 2]", JsonError::Unexpected('2')),
+            ("{} x", JsonError::Unexpected('x')),
+            ("nul", JsonError::UnexpectedEnd),
+            ("trux", JsonError::Unexpected('x')),
+            ("1.2.3", JsonError::BadNumber),
+            ("--1", JsonError::BadNumber),
+            (r#""\q""#, JsonError::BadEscape),
+        ];
+        for (src, error) in cases {
+            assert_eq!(Value::parse(src), Err(error), "{src}");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deep = "[".repeat(MAX_DEPTH + 2);
+        assert_eq!(Value::parse(&deep), Err(JsonError::TooDeep));
+        let fine = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(Value::parse(&fine).is_ok());
+    }
+
+    #[test]
+    fn writes_compact_json() {
+        let value = object(&[
+            ("id", Value::from(1_700_000_000_000u64)),
+            ("half", Value::Number(0.5)),
+            ("nan", Value::Number(f64::NAN)),
+            ("text", Value::from("say \"hi\"\n\u{1}")),
+            ("room", Value::from(None::<String>)),
+            (
+                "nicks",
+                Value::Array(vec![Value::from("a"), Value::Bool(true)]),
+            ),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"id":1700000000000,"half":0.5,"nan":null,"text":"say \"hi\"\n\u0001","room":null,"nicks":["a",true]}"#
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = object(&[
+            ("text", Value::from("tab\tquote\" back\\slash \u{7f} ü")),
+            ("ts", Value::from(u64::from(u32::MAX) * 1000)),
+        ]);
+        assert_eq!(Value::parse(&value.to_string()), Ok(value));
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::{debug, warn};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+};
+
+use crate::{
+    connection::ChatMessage, error::ChatError, json::Value, protocol::ServerFrame, state::State,
+};
+
+/// What to do with a client that falls further behind the broadcast channel
+/// than its capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Tell the client how many messages it missed and keep going
+    Notify,
+    /// Tell the client why and close the connection
+    Disconnect,
+    /// Keep draining the channel for slow clients and park the backlog on disk
+    Spool,
+}
+
+impl FromStr for LagPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notify" => Ok(LagPolicy::Notify),
+            "disconnect" => Ok(LagPolicy::Disconnect),
+            "spool" => Ok(LagPolicy::Spool),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LagPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LagPolicy::Notify => "notify",
+            LagPolicy::Disconnect => "disconnect",
+            LagPolicy::Spool => "spool",
+        })
+    }
+}
+
+/// How many times each lag policy kicked in since the server started.
+#[derive(Debug, Default)]
+pub struct LagStats {
+    pub notified: AtomicU64,
+    pub disconnected: AtomicU64,
+    pub spooled: AtomicU64,
+}
+
+impl LagStats {
+    fn bump(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl fmt::Display for LagStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} notified, {} disconnected, {} spooled",
+            self.notified.load(Ordering::Relaxed),
+            self.disconnected.load(Ordering::Relaxed),
+            self.spooled.load(Ordering::Relaxed)
+        )
+    }
+}
+
+pub fn missed_notice(n: u64) -> ServerFrame {
+    ServerFrame::notice(format!("you missed {n} messages"))
+}
+
+/// Where a session gets the messages it should forward to its client.
+pub enum Inbox {
+    Direct {
+        addr: SocketAddr,
+        channel_read: broadcast::Receiver<ChatMessage>,
+        state: Arc<State>,
+    },
+    Spooled(mpsc::Receiver<Result<ServerFrame, ChatError>>),
+}
+
+impl Inbox {
+    pub fn new(addr: SocketAddr, state: &Arc<State>) -> Inbox {
+        let channel_read = state.channel_send.subscribe();
+        match state.config.lag_policy {
+            LagPolicy::Spool => {
+                let config = &state.config;
+                let spool = Spool::new(
+                    &config.spool_dir,
+                    addr,
+                    config.channel_capacity,
+                    config.spool_max_size,
+                );
+                let (tx, rx) = mpsc::channel(1);
+                tokio::spawn(run_spool(addr, channel_read, spool, tx, state.clone()));
+                Inbox::Spooled(rx)
+            }
+            _ => Inbox::Direct {
+                addr,
+                channel_read,
+                state: state.clone(),
+            },
+        }
+    }
+
+    /// Next frame for this client. Cancel safe, so it can be used in `select!`.
+    pub async fn recv(&mut self) -> Result<ServerFrame, ChatError> {
+        match self {
+            Inbox::Direct {
+                addr,
+                channel_read,
+                state,
+            } => loop {
+                let stats = &state.lag_stats;
+                match channel_read.recv().await {
+                    Ok(msg) if msg.is_for(*addr, &state.rooms) => return Ok(msg.frame),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) if state.config.lag_policy == LagPolicy::Notify => {
+                        let total = LagStats::bump(&stats.notified);
+                        debug!("{addr} missed {n} messages (notified {total} times)");
+                        return Ok(missed_notice(n));
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        let total = LagStats::bump(&stats.disconnected);
+                        debug!("{addr} missed {n} messages (disconnected {total} times)");
+                        return Err(ChatError::Lagged(n));
+                    }
+                    Err(RecvError::Closed) => return Err(ChatError::ChannelClosed),
+                }
+            },
+            Inbox::Spooled(rx) => rx.recv().await.unwrap_or(Err(ChatError::ChannelClosed)),
+        }
+    }
+}
+
+/// Moves messages from the broadcast channel into the spool as fast as they
+/// arrive and hands them to the session one at a time.
+async fn run_spool(
+    addr: SocketAddr,
+    mut channel_read: broadcast::Receiver<ChatMessage>,
+    mut spool: Spool,
+    out: mpsc::Sender<Result<ServerFrame, ChatError>>,
+    state: Arc<State>,
+) {
+    let stats = &state.lag_stats;
+    let result = async {
+        loop {
+            tokio::select! {
+                msg = channel_read.recv() => match msg {
+                    Ok(msg) if msg.is_for(addr, &state.rooms) => {
+                        if spool.is_full() {
+                            let total = LagStats::bump(&stats.disconnected);
+                            debug!("{addr} filled its spool (disconnected {total} times)");
+                            return Err(ChatError::Lagged(spool.len() as u64));
+                        }
+                        if spool.push(msg.frame).await? {
+                            let total = LagStats::bump(&stats.spooled);
+                            debug!("{addr} spilled to {} (spooled {total} times)", spool.path.display());
+                        }
+                    }
+                    Ok(_) => {}
+                    // Even the spool couldn't keep up, fall back to telling the client
+                    Err(RecvError::Lagged(n)) => {
+                        LagStats::bump(&stats.notified);
+                        spool.push(missed_notice(n)).await?;
+                    }
+                    Err(RecvError::Closed) => return Err(ChatError::ChannelClosed),
+                },
+                permit = out.reserve(), if !spool.is_empty() => {
+                    let Ok(permit) = permit else { return Ok(()) };
+                    permit.send(Ok(spool.pop().await?));
+                }
+                _ = out.closed() => return Ok(()),
+            }
+        }
+    }
+    .await;
+    spool.remove().await;
+    if let Err(err) = result {
+        let _ = out.send(Err(err)).await;
+    }
+}
+
+/// FIFO of frames that keeps up to `memory_limit` entries in memory and
+/// appends the rest to a file, one JSON line each, until the client catches up
+/// or the file reaches `disk_limit` bytes.
+struct Spool {
+    memory: VecDeque<ServerFrame>,
+    memory_limit: usize,
+    path: PathBuf,
+    disk: Option<DiskSpool>,
+    disk_limit: u64,
+}
+
+struct DiskSpool {
+    writer: File,
+    reader: BufReader<File>,
+    pending: usize,
+    /// Size of the file so far
+    written: u64,
+}
+
+impl Spool {
+    fn new(dir: &Path, addr: SocketAddr, memory_limit: usize, disk_limit: u64) -> Spool {
+        let name = format!(
+            "chat-{}-{}-{}.spool",
+            process::id(),
+            addr.ip().to_string().replace(':', "_"),
+            addr.port()
+        );
+        Spool {
+            memory: VecDeque::new(),
+            memory_limit,
+            path: dir.join(name),
+            disk: None,
+            disk_limit,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.disk.is_none()
+    }
+
+    /// Frames waiting for the client.
+    fn len(&self) -> usize {
+        self.memory.len() + self.disk.as_ref().map_or(0, |disk| disk.pending)
+    }
+
+    /// The file took up its share of the disk, the client isn't coming back
+    /// from this far behind.
+    fn is_full(&self) -> bool {
+        (self.disk.as_ref()).is_some_and(|disk| disk.written >= self.disk_limit)
+    }
+
+    /// Returns `true` if this push started a new spool file.
+    async fn push(&mut self, frame: ServerFrame) -> Result<bool, ChatError> {
+        if self.disk.is_none() && self.memory.len() < self.memory_limit {
+            self.memory.push_back(frame);
+            return Ok(false);
+        }
+        let started = self.disk.is_none();
+        if started {
+            let writer = match create_spool_file(&self.path).await {
+                // Left over from an earlier process with our pid, or planted.
+                // Removing a symlink doesn't touch what it points to.
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    warn!("replacing stale spool file {}", self.path.display());
+                    fs::remove_file(&self.path)
+                        .await
+                        .map_err(ChatError::Spool)?;
+                    create_spool_file(&self.path).await
+                }
+                created => created,
+            }
+            .map_err(ChatError::Spool)?;
+            let reader = BufReader::new(File::open(&self.path).await.map_err(ChatError::Spool)?);
+            self.disk = Some(DiskSpool {
+                writer,
+                reader,
+                pending: 0,
+                written: 0,
+            });
+        }
+        let disk = self.disk.as_mut().expect("spool file was just opened");
+        // JSON keeps the stamps, the text form would lose them
+        let line = format!("{}\n", frame.to_json());
+        disk.writer
+            .write_all(line.as_bytes())
+            .await
+            .map_err(ChatError::Spool)?;
+        disk.pending += 1;
+        disk.written += line.len() as u64;
+        Ok(started)
+    }
+
+    async fn pop(&mut self) -> Result<ServerFrame, ChatError> {
+        if let Some(frame) = self.memory.pop_front() {
+            return Ok(frame);
+        }
+        let disk = self.disk.as_mut().ok_or(ChatError::ChannelClosed)?;
+        disk.writer.flush().await.map_err(ChatError::Spool)?;
+        let mut line = String::new();
+        disk.reader
+            .read_line(&mut line)
+            .await
+            .map_err(ChatError::Spool)?;
+        disk.pending -= 1;
+        if disk.pending == 0 {
+            // Caught up, go back to keeping messages in memory
+            self.remove().await;
+        }
+        Value::parse(&line)
+            .ok()
+            .as_ref()
+            .and_then(ServerFrame::from_json)
+            .ok_or_else(|| {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "unreadable spool entry");
+                ChatError::Spool(err)
+            })
+    }
+
+    async fn remove(&mut self) {
+        if self.disk.take().is_some() {
+            if let Err(err) = fs::remove_file(&self.path).await {
+                warn!("cannot remove spool file {}: {err}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Create a spool file only we can read. The spool directory is usually
+/// the shared temp dir, so never open a file (or follow a symlink) that
+/// someone else put there.
+async fn create_spool_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn spool_files_dont_follow_symlinks() {
+        let dir = std::env::temp_dir();
+        let addr: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+        let mut spool = Spool::new(&dir, addr, 0, 1 << 20);
+        let target = dir.join(format!("chat-spool-target-{}", process::id()));
+        std::fs::write(&target, "keep me").unwrap();
+        let _ = std::fs::remove_file(&spool.path);
+        std::os::unix::fs::symlink(&target, &spool.path).unwrap();
+
+        assert!(spool.push(ServerFrame::notice("hi")).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep me");
+        let meta = std::fs::symlink_metadata(&spool.path).unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        match spool.pop().await.unwrap() {
+            ServerFrame::Notice { text, .. } => assert_eq!(text, "hi"),
+            frame => panic!("unexpected {frame:?}"),
+        }
+        assert!(!spool.path.exists());
+        std::fs::remove_file(&target).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+//! A chat server on tokio: rooms, private messages, accounts and history,
+//! spoken as plain text lines, JSON lines, WebSocket or IRC.
+//!
+//! The `rust_tokio_chat_server` binary is a thin wrapper around this crate,
+//! which can also run the server inside another program:
+//! ```no_run
+//! use rust_tokio_chat_server::{config::Config, ChatServer};
+//! use tokio::net::TcpListener;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let config = Config {
+//!     max_clients: 50,
+//!     ..Config::default()
+//! };
+//! let server = ChatServer::builder().config(config).build()?;
+//! let handle = server.handle();
+//! let listener = TcpListener::bind("127.0.0.1:0").await?;
+//! let running = tokio::spawn(server.run(listener));
+//!
+//! handle.broadcast(Some("#lobby"), "deploy finished");
+//! println!("online: {:?}", handle.users());
+//! handle.shutdown();
+//! running.await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+mod access;
+mod accounts;
+mod bot;
+pub mod client;
+mod codec;
+pub mod config;
+mod connection;
+mod crypto;
+mod error;
+mod heartbeat;
+mod history;
+mod irc;
+mod json;
+mod lag;
+mod limits;
+mod plugin;
+mod protocol;
+mod ratelimit;
+mod rooms;
+mod server;
+mod state;
+mod tls;
+mod users;
+mod websocket;
+
+pub use bot::{Bot, BotError, BotHandle, Command};
+pub use error::ChatError;
+pub use plugin::{BoxFuture, ChatPlugin, Context, Flow, Message};
+pub use rooms::RoomError;
+pub use server::{ChatServer, ChatServerBuilder, ServerHandle};
+pub use users::NickError;
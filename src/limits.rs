@@ -0,0 +1,203 @@
+//! Limits on connections, checked right after `accept()` and before a
+//! session is spawned: how many clients the server takes in total, how many
+//! from one address, and how often one address may connect.
+//!
+//! IPv6 clients usually get a whole /64, so all addresses in one count as
+//! the same host.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{config::Config, ratelimit::Bucket};
+
+/// Hosts are only forgotten once the table grows past this, and then only
+/// the ones with no connections and a full bucket
+const PRUNE_AT: usize = 4096;
+
+/// Why a connection was turned away. Sent to the client before closing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// By the access rules or a ban
+    Denied,
+    Full,
+    TooManyFromIp,
+    TooFast,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::Denied => "your address is not allowed to connect",
+            Refusal::Full => "server is full, try again later",
+            Refusal::TooManyFromIp => "too many connections from your address",
+            Refusal::TooFast => "connecting too often, try again later",
+        })
+    }
+}
+
+/// Counters since the server started, for monitoring.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    pub accepted: AtomicU64,
+    pub refused_full: AtomicU64,
+    pub refused_per_ip: AtomicU64,
+    pub refused_rate: AtomicU64,
+    pub refused_denied: AtomicU64,
+}
+
+impl ConnectionStats {
+    fn counter(&self, refusal: Refusal) -> &AtomicU64 {
+        match refusal {
+            Refusal::Denied => &self.refused_denied,
+            Refusal::Full => &self.refused_full,
+            Refusal::TooManyFromIp => &self.refused_per_ip,
+            Refusal::TooFast => &self.refused_rate,
+        }
+    }
+}
+
+struct Host {
+    connections: usize,
+    /// `None` without an accept rate
+    accepts: Option<Bucket>,
+}
+
+struct Table {
+    connected: usize,
+    hosts: HashMap<IpAddr, Host>,
+    prune_at: usize,
+}
+
+pub struct Connections {
+    max_clients: usize,
+    max_per_ip: Option<usize>,
+    /// Per minute
+    accept_rate: Option<u32>,
+    table: Mutex<Table>,
+    pub stats: ConnectionStats,
+}
+
+impl Connections {
+    pub fn new(config: &Config) -> Connections {
+        Connections {
+            max_clients: config.max_clients,
+            max_per_ip: config.max_per_ip,
+            accept_rate: config.accept_rate,
+            table: Mutex::new(Table {
+                connected: 0,
+                hosts: HashMap::new(),
+                prune_at: PRUNE_AT,
+            }),
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    /// Clients connected right now.
+    pub fn connected(&self) -> usize {
+        self.table.lock().unwrap().connected
+    }
+
+    /// Count a client that was turned away before it got to `admit`.
+    pub fn refused(&self, refusal: Refusal) {
+        self.stats.counter(refusal).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Let a client from `ip` in, or say why not. The connection counts
+    /// until the returned slot is dropped.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, Refusal> {
+        let host = host_of(ip);
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        if table.hosts.len() >= table.prune_at {
+            table.hosts.retain(|_, host| {
+                host.connections > 0 || host.accepts.as_mut().is_some_and(|b| !b.is_full(now))
+            });
+            table.prune_at = (table.hosts.len() * 2).max(PRUNE_AT);
+        }
+        let full = table.connected >= self.max_clients;
+        let entry = table.hosts.entry(host).or_insert_with(|| Host {
+            connections: 0,
+            accepts: self
+                .accept_rate
+                .map(|rate| Bucket::new(f64::from(rate) / 60.0, rate.into())),
+        });
+        // Attempts count against the rate even when refused for another
+        // reason, so hammering a full server doesn't pay off later
+        let too_fast = entry
+            .accepts
+            .as_mut()
+            .is_some_and(|accepts| !accepts.try_take(1.0, now));
+        let refusal = if too_fast {
+            Some(Refusal::TooFast)
+        } else if full {
+            Some(Refusal::Full)
+        } else if self.max_per_ip.is_some_and(|max| entry.connections >= max) {
+            Some(Refusal::TooManyFromIp)
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            self.refused(refusal);
+            return Err(refusal);
+        }
+        entry.connections += 1;
+        table.connected += 1;
+        self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+        Ok(Slot {
+            connections: self.clone(),
+            host,
+        })
+    }
+}
+
+impl fmt::Display for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        write!(
+            f,
+            "{} connected, {} accepted, refused {} (denied), {} (full), {} (per IP), {} (rate)",
+            self.connected(),
+            stats.accepted.load(Ordering::Relaxed),
+            stats.refused_denied.load(Ordering::Relaxed),
+            stats.refused_full.load(Ordering::Relaxed),
+            stats.refused_per_ip.load(Ordering::Relaxed),
+            stats.refused_rate.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A connection that was let in. Frees its place when dropped.
+pub struct Slot {
+    connections: Arc<Connections>,
+    host: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut table = self.connections.table.lock().unwrap();
+        table.connected -= 1;
+        if let Some(host) = table.hosts.get_mut(&self.host) {
+            host.connections -= 1;
+        }
+    }
+}
+
+/// The address limits are counted against: IPv4 addresses (also when
+/// mapped into IPv6) as they are, IPv6 addresses by their /64.
+pub fn host_of(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+        ip => ip,
+    }
+}
@@ -0,0 +1,26 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Minimal logger that writes `LEVEL message` lines to stderr.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{:<5} {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init(level: LevelFilter) {
+    // Only fails if a logger was already installed, which is fine to ignore
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -1,42 +1,103 @@
-use std::net::SocketAddr;
+mod logger;
 
+use std::process;
+
+use log::{error, info};
+use rust_tokio_chat_server::{
+    config::{self, Args, Config},
+    ChatServer, ServerHandle,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::TcpListener,
-    sync::broadcast,
+    signal::unix::{signal, SignalKind},
 };
 
 #[tokio::main]
 async fn main() {
-    let tcp_listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    let (channel_send, _) = broadcast::channel::<(String, SocketAddr)>(10);
-    loop {
-        let (mut socket, addr) = tcp_listener.accept().await.unwrap();
-        let channel_send = channel_send.clone();
-        let mut channel_read = channel_send.subscribe();
-        tokio::spawn(async move {
-            let (socket_reader, mut socket_writer) = socket.split();
-
-            let mut br = BufReader::new(socket_reader);
-            let mut message = String::new();
-
-            loop {
-                tokio::select! {
-                    num_of_bytes = br.read_line(&mut message) => {
-                        if num_of_bytes.unwrap() == 0 {
-                            break;
-                        }
-                        channel_send.send((message.clone(), addr)).unwrap();
-                        message.clear();
-                    }
-                    recv_msg = channel_read.recv() => {
-                        let (recv_msg, o_addr) = recv_msg.unwrap();
-                        if addr != o_addr {
-                            socket_writer.write_all(recv_msg.as_bytes()).await.unwrap();
-                        }
-                    }
-                }
-            }
-        });
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|err| usage_error(err));
+    if args.help {
+        println!("{}", config::USAGE);
+        return;
+    }
+    let config = Config::load(&args).unwrap_or_else(|err| usage_error(err));
+    logger::init(config.log_level);
+
+    let tcp_listener = bind(&config, config.port).await;
+    let mut builder = ChatServer::builder();
+    if let Some(port) = config.json_port {
+        builder = builder.json_listener(bind(&config, port).await);
+    }
+    if let Some(port) = config.ws_port {
+        builder = builder.ws_listener(bind(&config, port).await);
+    }
+    if let Some(port) = config.irc_port {
+        builder = builder.irc_listener(bind(&config, port).await);
+    }
+    if let Some(port) = config.tls_port {
+        builder = builder.tls_listener(bind(&config, port).await);
+    }
+    let server = builder.config(config).build().unwrap_or_else(|err| {
+        error!("{err}");
+        process::exit(1);
+    });
+    let handle = server.handle();
+    let running = tokio::spawn(server.run(tcp_listener));
+    tokio::spawn(report_stats(handle.clone()));
+    tokio::spawn(reload_on_hangup(handle.clone()));
+
+    let signal = shutdown_signal().await;
+    info!("{signal} received, shutting down");
+    handle.shutdown();
+    running.await.expect("the server doesn't panic");
+    info!("bye");
+}
+
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM with the signal's name.
+async fn shutdown_signal() -> &'static str {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("signal handlers can be installed");
+    let mut terminate = signal(SignalKind::terminate()).expect("signal handlers can be installed");
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
     }
 }
+
+/// Log the server's counters on every SIGUSR1.
+async fn report_stats(handle: ServerHandle) {
+    let mut usr1 = signal(SignalKind::user_defined1()).expect("signal handlers can be installed");
+    while usr1.recv().await.is_some() {
+        for line in handle.stats() {
+            info!("{line}");
+        }
+    }
+}
+
+/// Read the access file again on every SIGHUP.
+async fn reload_on_hangup(handle: ServerHandle) {
+    let mut hangup = signal(SignalKind::hangup()).expect("signal handlers can be installed");
+    while hangup.recv().await.is_some() {
+        match handle.reload_access() {
+            Ok(Some((allow, deny))) => info!("access rules reloaded: {allow} allow, {deny} deny"),
+            Ok(None) => info!("SIGHUP received, but there is no access file to reload"),
+            Err(err) => error!("cannot reload access rules, keeping the old ones: {err}"),
+        }
+    }
+}
+
+async fn bind(config: &Config, port: u16) -> TcpListener {
+    match TcpListener::bind((config.bind, port)).await {
+        Ok(listener) => {
+            info!("listening on {}:{port}", config.bind);
+            listener
+        }
+        Err(err) => {
+            error!("cannot listen on {}:{port}: {err}", config.bind);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage_error(err: config::ConfigError) -> ! {
+    eprintln!("error: {err}\nRun with --help to see the available options.");
+    process::exit(2);
+}
@@ -0,0 +1,239 @@
+//! Hooks for attaching behavior to the server without touching the session
+//! loops. Plugins are called in the order they were added to the builder,
+//! and any of them can stop an event from going further.
+//!
+//! Hooks return boxed futures so the trait can be used as `dyn ChatPlugin`:
+//! ```no_run
+//! use rust_tokio_chat_server::{BoxFuture, ChatPlugin, Context, Flow, Message};
+//!
+//! struct NoShouting;
+//!
+//! impl ChatPlugin for NoShouting {
+//!     fn on_message<'a>(
+//!         &'a self,
+//!         ctx: &'a mut Context,
+//!         message: &'a mut Message,
+//!     ) -> BoxFuture<'a, Flow> {
+//!         Box::pin(async move {
+//!             if message.text.len() > 8 && message.text == message.text.to_uppercase() {
+//!                 ctx.reply("no shouting please");
+//!                 message.text = message.text.to_lowercase();
+//!             }
+//!             Flow::Continue
+//!         })
+//!     }
+//! }
+//! ```
+
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use crate::{protocol::ServerFrame, state::State, users::Registration};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// What should happen to an event after a hook saw it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Hand it to the next plugin, and then to the server
+    Continue,
+    /// Stop it here. Nothing is sent on the user's behalf, so reply to say
+    /// why if the user should know.
+    Drop,
+}
+
+/// Who an event is about, and what the hook wants to say in return.
+pub struct Context {
+    nick: String,
+    addr: SocketAddr,
+    account: Option<String>,
+    replies: Vec<String>,
+    broadcasts: Vec<(Option<String>, String)>,
+}
+
+impl Context {
+    fn new(user: &Registration) -> Context {
+        Context {
+            nick: user.nick.clone(),
+            addr: user.addr,
+            account: user.account.clone(),
+            replies: Vec::new(),
+            broadcasts: Vec::new(),
+        }
+    }
+
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The account the user logged in to, if any.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Send the user a notice. Ignored for `on_disconnect`.
+    pub fn reply(&mut self, text: impl Into<String>) {
+        self.replies.push(single_line(text.into()));
+    }
+
+    /// Send a notice to the members of `room`, or to everyone.
+    pub fn broadcast(&mut self, room: Option<&str>, text: impl Into<String>) {
+        self.broadcasts
+            .push((room.map(str::to_string), text.into()));
+    }
+}
+
+/// A room message on its way to the room.
+#[derive(Debug, Clone)]
+pub struct Message {
+    room: String,
+    /// Can be rewritten, line breaks are turned into spaces afterwards
+    pub text: String,
+}
+
+impl Message {
+    pub(crate) fn new(room: String, text: String) -> Message {
+        Message { room, text }
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+/// Every hook does nothing by default, so plugins only implement the ones
+/// they need.
+pub trait ChatPlugin: Send + Sync + 'static {
+    /// A user picked a nickname (or logged in). `Flow::Drop` disconnects
+    /// them.
+    fn on_connect<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, Flow> {
+        let _ = ctx;
+        Box::pin(async { Flow::Continue })
+    }
+
+    /// A user sent a message to a room. The plugin can change its text, or
+    /// `Flow::Drop` it so nobody gets it.
+    fn on_message<'a>(
+        &'a self,
+        ctx: &'a mut Context,
+        message: &'a mut Message,
+    ) -> BoxFuture<'a, Flow> {
+        let _ = (ctx, message);
+        Box::pin(async { Flow::Continue })
+    }
+
+    /// A user is about to join a room they aren't in yet. `Flow::Drop`
+    /// keeps them out.
+    fn on_join<'a>(&'a self, ctx: &'a mut Context, room: &'a str) -> BoxFuture<'a, Flow> {
+        let _ = (ctx, room);
+        Box::pin(async { Flow::Continue })
+    }
+
+    /// A user left the server, with the reason if the server ended the
+    /// session. Everyone was already told.
+    fn on_disconnect<'a>(
+        &'a self,
+        ctx: &'a mut Context,
+        reason: Option<&'a str>,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (ctx, reason);
+        Box::pin(async {})
+    }
+}
+
+/// What the plugins decided about an event, and the notices for the user.
+pub struct Hooked {
+    pub flow: Flow,
+    pub replies: Vec<ServerFrame>,
+}
+
+/// The plugins of a server, in order.
+#[derive(Default)]
+pub struct Plugins(Vec<Box<dyn ChatPlugin>>);
+
+impl Plugins {
+    pub fn push(&mut self, plugin: Box<dyn ChatPlugin>) {
+        self.0.push(plugin);
+    }
+
+    pub async fn connect(&self, user: &Registration, state: &State) -> Hooked {
+        let mut ctx = Context::new(user);
+        let mut flow = Flow::Continue;
+        for plugin in &self.0 {
+            flow = plugin.on_connect(&mut ctx).await;
+            if flow == Flow::Drop {
+                break;
+            }
+        }
+        finish(ctx, flow, state)
+    }
+
+    /// Runs `on_message` on `message` in place.
+    pub async fn message(
+        &self,
+        user: &Registration,
+        message: &mut Message,
+        state: &State,
+    ) -> Hooked {
+        let mut ctx = Context::new(user);
+        let mut flow = Flow::Continue;
+        for plugin in &self.0 {
+            flow = plugin.on_message(&mut ctx, message).await;
+            if flow == Flow::Drop {
+                break;
+            }
+        }
+        message.text = single_line(std::mem::take(&mut message.text));
+        finish(ctx, flow, state)
+    }
+
+    pub async fn join(&self, user: &Registration, room: &str, state: &State) -> Hooked {
+        let mut ctx = Context::new(user);
+        let mut flow = Flow::Continue;
+        for plugin in &self.0 {
+            flow = plugin.on_join(&mut ctx, room).await;
+            if flow == Flow::Drop {
+                break;
+            }
+        }
+        finish(ctx, flow, state)
+    }
+
+    /// Runs `on_disconnect` in the background, the session is already gone.
+    pub fn disconnect(user: &Registration, reason: Option<String>, state: Arc<State>) {
+        if state.plugins.0.is_empty() {
+            return;
+        }
+        let mut ctx = Context::new(user);
+        tokio::spawn(async move {
+            for plugin in &state.plugins.0 {
+                plugin.on_disconnect(&mut ctx, reason.as_deref()).await;
+            }
+            ctx.replies.clear();
+            finish(ctx, Flow::Continue, &state);
+        });
+    }
+}
+
+/// Plugins can write anything, but a line break would start a new frame.
+pub fn single_line(text: String) -> String {
+    match text.contains(['\r', '\n']) {
+        true => text.replace(['\r', '\n'], " "),
+        false => text,
+    }
+}
+
+/// Send the broadcasts a chain of hooks asked for and turn its replies into
+/// notices.
+fn finish(ctx: Context, flow: Flow, state: &State) -> Hooked {
+    for (room, text) in ctx.broadcasts {
+        state.notify(room.as_deref(), &text);
+    }
+    Hooked {
+        flow,
+        replies: ctx.replies.into_iter().map(ServerFrame::notice).collect(),
+    }
+}
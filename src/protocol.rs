@@ -0,0 +1,1070 @@
+//! Wire protocol, version 1.
+//!
+//! Every frame is one line of UTF-8 ending in `\n` (a `\r` before it is
+//! ignored), so the protocol stays usable from telnet.
+//!
+//! Client to server:
+//! ```text
+//! hello everyone        message to the current room
+//! //shrug               message starting with a `/`
+//! /nick <name>          /join #room       /part [#room]
+//! /rooms                /names [#room]        /msg <nick> <text>
+//! /history <n>         /topic [text]
+//! /register <nick> <password>          /login <nick> <password>
+//! /pong [token]         answer to PING
+//! ```
+//!
+//! Server to client:
+//! ```text
+//! CHAT/1 <text>                greeting, sent once with the protocol version
+//! [#room] <nick> <text>        message in a room
+//! [pm] <nick> <text>           private message
+//! [#room] *** <text>           notice about a room
+//! [#room] *** members: <nicks> who is in a room, the answer to /names
+//! *** <text>                   server-wide notice
+//! +OK <text>                   a command worked
+//! -ERR <text>                  a command or line was rejected
+//! PING <token>                 are you still there? answer with /pong
+//! ```
+//!
+//! Sending `/hello json` as the first line (or connecting to the JSON port)
+//! switches the connection to JSON lines: one object per line each way, see
+//! `ClientFrame::from_json` and `ServerFrame::to_json`.
+
+use std::{
+    fmt, io,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    codec::{Decoder, Encoder, Lines},
+    json::{JsonError, Value},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How frames are written on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Text,
+    Json,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Mode::Text),
+            "json" => Ok(Mode::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Text => "text",
+            Mode::Json => "json",
+        })
+    }
+}
+
+/// Identifies an event: a server-wide sequence number and when it happened,
+/// in milliseconds since the Unix epoch. Everyone who receives the same
+/// event sees the same stamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub id: u64,
+    pub ts: u64,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Stamp {
+    pub fn new() -> Stamp {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        Stamp {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            ts,
+        }
+    }
+
+    /// Make sure new stamps get ids after `id`, e.g. one loaded from history
+    /// written by an earlier run.
+    pub fn resume_after(id: u64) {
+        NEXT_ID.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+    }
+}
+
+impl Default for Stamp {
+    fn default() -> Self {
+        Stamp::new()
+    }
+}
+
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientFrame {
+    /// Switch the connection to another mode, only before `Nick`
+    Hello(Mode),
+    /// Message to the given room, or the current one
+    Message {
+        room: Option<String>,
+        text: String,
+    },
+    Nick(String),
+    Join(String),
+    /// Leave the named room, or the current one
+    Part(Option<String>),
+    Rooms,
+    /// Who is in the given room, or the current one
+    Names(Option<String>),
+    Msg {
+        to: String,
+        text: String,
+    },
+    /// Last `count` messages of the given room, or the current one
+    History {
+        room: Option<String>,
+        count: usize,
+    },
+    /// Show the topic of the given room (or the current one), or set it
+    Topic {
+        room: Option<String>,
+        topic: Option<String>,
+    },
+    /// Create an account for `nick` and log in to it
+    Register {
+        nick: String,
+        password: String,
+    },
+    Login {
+        nick: String,
+        password: String,
+    },
+    /// Answer to `ServerFrame::Ping`, with its token
+    Pong(String),
+    /// Admins only: turn away an address or range, e.g. `192.0.2.0/24`
+    Ban(String),
+    Unban(String),
+    Bans,
+}
+
+impl ClientFrame {
+    /// Parse one line without its `\n`. Blank lines are `Ok(None)`.
+    pub fn parse(line: &str) -> Result<Option<ClientFrame>, ProtocolError> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        // The line break ending the line is gone, a `\r` left inside would
+        // still end the line for IRC clients
+        let single_line = |name, text: &str| match text.contains('\r') {
+            true => Err(ProtocolError::LineBreak(name)),
+            false => Ok(text.to_string()),
+        };
+        let message = |text: &str| {
+            Ok(Some(ClientFrame::Message {
+                room: None,
+                text: single_line("text", text)?,
+            }))
+        };
+        let Some(command) = line.strip_prefix('/') else {
+            return message(line);
+        };
+        if command.starts_with('/') {
+            return message(command);
+        }
+        let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+        let arg = arg.trim();
+        let required = |usage| match arg {
+            "" => Err(ProtocolError::Usage(usage)),
+            arg => Ok(arg.to_string()),
+        };
+        let frame = match name {
+            "hello" => ClientFrame::Hello(
+                arg.parse()
+                    .map_err(|()| ProtocolError::Usage("/hello text|json"))?,
+            ),
+            "nick" => ClientFrame::Nick(required("/nick <name>")?),
+            "join" => ClientFrame::Join(required("/join #room")?),
+            "part" => ClientFrame::Part((!arg.is_empty()).then(|| arg.to_string())),
+            "rooms" => ClientFrame::Rooms,
+            "names" => ClientFrame::Names((!arg.is_empty()).then(|| arg.to_string())),
+            "msg" => match arg.split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => ClientFrame::Msg {
+                    to: to.to_string(),
+                    text: single_line("text", text.trim())?,
+                },
+                _ => return Err(ProtocolError::Usage("/msg <nick> <text>")),
+            },
+            "history" => ClientFrame::History {
+                room: None,
+                count: arg
+                    .parse()
+                    .map_err(|_| ProtocolError::Usage("/history <n>"))?,
+            },
+            "topic" => ClientFrame::Topic {
+                room: None,
+                topic: match arg {
+                    "" => None,
+                    topic => Some(single_line("topic", topic)?),
+                },
+            },
+            "pong" => ClientFrame::Pong(arg.to_string()),
+            "ban" => ClientFrame::Ban(required("/ban <address or range>")?),
+            "unban" => ClientFrame::Unban(required("/unban <address or range>")?),
+            "bans" => ClientFrame::Bans,
+            "register" | "login" => {
+                let (nick, password) = match arg.split_once(' ') {
+                    Some((nick, password)) => (nick.to_string(), password.to_string()),
+                    None if name == "register" => {
+                        return Err(ProtocolError::Usage("/register <nick> <password>"))
+                    }
+                    None => return Err(ProtocolError::Usage("/login <nick> <password>")),
+                };
+                match name {
+                    "register" => ClientFrame::Register { nick, password },
+                    _ => ClientFrame::Login { nick, password },
+                }
+            }
+            _ => return Err(ProtocolError::UnknownCommand(name.to_string())),
+        };
+        Ok(Some(frame))
+    }
+
+    /// Parse a JSON-mode line, an object with a `type` and the fields that
+    /// type needs:
+    /// ```text
+    /// {"type":"hello","mode":"text"}      {"type":"nick","nick":"bob"}
+    /// {"type":"join","room":"#rust"}      {"type":"part","room":"#rust"}
+    /// {"type":"rooms"}                    {"type":"msg","to":"bob","text":"hi"}
+    /// {"type":"names","room":"#rust"}
+    /// {"type":"message","room":"#rust","text":"hi"}
+    /// {"type":"history","room":"#rust","count":50}
+    /// {"type":"topic","room":"#rust","topic":"all things Rust"}
+    /// {"type":"register","nick":"bob","password":"hunter22"}
+    /// {"type":"login","nick":"bob","password":"hunter22"}
+    /// {"type":"pong","token":"3"}
+    /// {"type":"ban","range":"192.0.2.0/24"}  {"type":"unban","range":"192.0.2.0/24"}
+    /// {"type":"bans"}
+    /// ```
+    /// `room` is optional for `part`, `names`, `message`, `history` and `topic`,
+    /// `topic` without a `topic` shows the current one. Other fields are
+    /// ignored.
+    pub fn from_json(line: &str) -> Result<Option<ClientFrame>, ProtocolError> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        let value = Value::parse(line).map_err(ProtocolError::Json)?;
+        let kind = value
+            .str_field("type")
+            .ok_or(ProtocolError::MissingField("type"))?;
+        // Anything here may end up in a text or IRC line, where a line break
+        // would start a new frame
+        let single_line = |name, text: &str| match text.contains(['\r', '\n']) {
+            true => Err(ProtocolError::LineBreak(name)),
+            false => Ok(text.to_string()),
+        };
+        let field = |name| match value.str_field(name) {
+            Some(text) => single_line(name, text),
+            None => Err(ProtocolError::MissingField(name)),
+        };
+        let room = value.str_field("room").map(str::to_string);
+        let frame = match kind {
+            "hello" => ClientFrame::Hello(
+                field("mode")?
+                    .parse()
+                    .map_err(|()| ProtocolError::Usage("\"mode\": \"text\" or \"json\""))?,
+            ),
+            "nick" => ClientFrame::Nick(field("nick")?),
+            "join" => ClientFrame::Join(field("room")?),
+            "part" => ClientFrame::Part(room),
+            "rooms" => ClientFrame::Rooms,
+            "names" => ClientFrame::Names(room),
+            "message" => ClientFrame::Message {
+                room,
+                text: field("text")?,
+            },
+            "msg" => ClientFrame::Msg {
+                to: field("to")?,
+                text: field("text")?,
+            },
+            "history" => ClientFrame::History {
+                room,
+                count: value
+                    .get("count")
+                    .and_then(Value::as_u64)
+                    .and_then(|count| count.try_into().ok())
+                    .ok_or(ProtocolError::Usage("\"count\": a non-negative integer"))?,
+            },
+            "topic" => ClientFrame::Topic {
+                room,
+                topic: match value.str_field("topic") {
+                    Some(topic) => Some(single_line("topic", topic)?),
+                    None => None,
+                },
+            },
+            "register" => ClientFrame::Register {
+                nick: field("nick")?,
+                password: field("password")?,
+            },
+            "login" => ClientFrame::Login {
+                nick: field("nick")?,
+                password: field("password")?,
+            },
+            "pong" => ClientFrame::Pong(value.str_field("token").unwrap_or_default().to_string()),
+            "ban" => ClientFrame::Ban(field("range")?),
+            "unban" => ClientFrame::Unban(field("range")?),
+            "bans" => ClientFrame::Bans,
+            other => return Err(ProtocolError::UnknownType(other.to_string())),
+        };
+        Ok(Some(frame))
+    }
+
+    /// Inverse of `from_json`, for clients. Optional fields that aren't set
+    /// are left out.
+    pub fn to_json(&self) -> Value {
+        let field = |name: &str, value: &str| (name.to_string(), Value::from(value));
+        let room = |room: &Option<String>| room.as_deref().map(|room| field("room", room));
+        let (kind, fields) = match self {
+            ClientFrame::Hello(mode) => ("hello", vec![field("mode", &mode.to_string())]),
+            ClientFrame::Message { room: target, text } => {
+                let fields = room(target).into_iter().chain([field("text", text)]);
+                ("message", fields.collect())
+            }
+            ClientFrame::Nick(nick) => ("nick", vec![field("nick", nick)]),
+            ClientFrame::Join(target) => ("join", vec![field("room", target)]),
+            ClientFrame::Part(target) => ("part", room(target).into_iter().collect()),
+            ClientFrame::Rooms => ("rooms", Vec::new()),
+            ClientFrame::Names(target) => ("names", room(target).into_iter().collect()),
+            ClientFrame::Msg { to, text } => ("msg", vec![field("to", to), field("text", text)]),
+            ClientFrame::History {
+                room: target,
+                count,
+            } => {
+                let count = ("count".to_string(), Value::from(*count as u64));
+                ("history", room(target).into_iter().chain([count]).collect())
+            }
+            ClientFrame::Topic {
+                room: target,
+                topic,
+            } => {
+                let topic = topic.as_deref().map(|topic| field("topic", topic));
+                ("topic", room(target).into_iter().chain(topic).collect())
+            }
+            ClientFrame::Register { nick, password } => (
+                "register",
+                vec![field("nick", nick), field("password", password)],
+            ),
+            ClientFrame::Login { nick, password } => (
+                "login",
+                vec![field("nick", nick), field("password", password)],
+            ),
+            ClientFrame::Pong(token) => ("pong", vec![field("token", token)]),
+            ClientFrame::Ban(range) => ("ban", vec![field("range", range)]),
+            ClientFrame::Unban(range) => ("unban", vec![field("range", range)]),
+            ClientFrame::Bans => ("bans", Vec::new()),
+        };
+        Value::Object(std::iter::once(field("type", kind)).chain(fields).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerFrame {
+    Hello {
+        version: u32,
+        text: String,
+    },
+    Message {
+        stamp: Stamp,
+        room: String,
+        from: String,
+        text: String,
+    },
+    Private {
+        stamp: Stamp,
+        from: String,
+        text: String,
+    },
+    /// `room` is `None` for notices every user should see
+    Notice {
+        stamp: Stamp,
+        room: Option<String>,
+        text: String,
+    },
+    /// Someone joined, left or was renamed. Rendered like a notice, but
+    /// clients that keep member lists can follow it.
+    Event {
+        stamp: Stamp,
+        /// `None` for the whole server, e.g. connecting or disconnecting
+        room: Option<String>,
+        nick: String,
+        event: Event,
+    },
+    /// Who is in `room`, sorted. The answer to `ClientFrame::Names`.
+    Names {
+        room: String,
+        nicks: Vec<String>,
+    },
+    Ack(String),
+    Error(String),
+    /// Heartbeat, the client answers with `ClientFrame::Pong` and the token
+    Ping(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Connected, or joined the room
+    Join,
+    /// Disconnected, with the reason if the server ended the session, or
+    /// left the room
+    Part(Option<String>),
+    /// Changed nickname to this one
+    Nick(String),
+    /// Set the room's topic to this, empty if it was cleared
+    Topic(String),
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::Join => "join",
+            Event::Part(_) => "part",
+            Event::Nick(_) => "nick",
+            Event::Topic(_) => "topic",
+        }
+    }
+}
+
+impl ServerFrame {
+    pub fn notice(text: impl Into<String>) -> ServerFrame {
+        ServerFrame::Notice {
+            stamp: Stamp::new(),
+            room: None,
+            text: text.into(),
+        }
+    }
+
+    /// Room the frame belongs to, `None` if it isn't about a room.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            ServerFrame::Message { room, .. } | ServerFrame::Names { room, .. } => Some(room),
+            ServerFrame::Notice { room, .. } | ServerFrame::Event { room, .. } => room.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The JSON-mode form of the frame. Every event has the same fields:
+    /// ```text
+    /// {"type":"message","id":7,"ts":1700000000000,"room":"#rust","sender":"alice","text":"hi"}
+    /// ```
+    /// `type` is one of hello, message, private, notice, join, part, nick,
+    /// topic, names, ack, error or ping. `room` and `sender` are null when
+    /// they don't apply, hello also has `version`, nick has `new_nick`, topic
+    /// has `topic`, names has the array `nicks` and part may have a `reason`.
+    /// The text of a ping is its token. For join, part, nick and topic
+    /// `sender` is who it is about and `text` the same sentence text clients
+    /// show. Replies (hello, ack, error) are stamped when they are written.
+    pub fn to_json(&self) -> Value {
+        let description;
+        let (kind, stamp, room, sender, text) = match self {
+            ServerFrame::Hello { text, .. } => ("hello", Stamp::new(), None, None, text),
+            ServerFrame::Message {
+                stamp,
+                room,
+                from,
+                text,
+            } => ("message", *stamp, Some(room), Some(from), text),
+            ServerFrame::Private { stamp, from, text } => {
+                ("private", *stamp, None, Some(from), text)
+            }
+            ServerFrame::Notice { stamp, room, text } => {
+                ("notice", *stamp, room.as_ref(), None, text)
+            }
+            ServerFrame::Event {
+                stamp,
+                room,
+                nick,
+                event,
+            } => {
+                description = self.describe();
+                (
+                    event.kind(),
+                    *stamp,
+                    room.as_ref(),
+                    Some(nick),
+                    &description,
+                )
+            }
+            ServerFrame::Names { room, nicks } => {
+                description = nicks.join(", ");
+                ("names", Stamp::new(), Some(room), None, &description)
+            }
+            ServerFrame::Ack(text) => ("ack", Stamp::new(), None, None, text),
+            ServerFrame::Error(text) => ("error", Stamp::new(), None, None, text),
+            ServerFrame::Ping(token) => ("ping", Stamp::new(), None, None, token),
+        };
+        let mut fields = vec![
+            ("type".to_string(), Value::from(kind)),
+            ("id".to_string(), Value::from(stamp.id)),
+            ("ts".to_string(), Value::from(stamp.ts)),
+            ("room".to_string(), Value::from(room.cloned())),
+            ("sender".to_string(), Value::from(sender.cloned())),
+            ("text".to_string(), Value::from(text.as_str())),
+        ];
+        match self {
+            ServerFrame::Hello { version, .. } => {
+                fields.push(("version".to_string(), Value::from(u64::from(*version))));
+            }
+            ServerFrame::Event {
+                event: Event::Nick(new_nick),
+                ..
+            } => fields.push(("new_nick".to_string(), Value::from(new_nick.as_str()))),
+            ServerFrame::Event {
+                event: Event::Topic(topic),
+                ..
+            } => fields.push(("topic".to_string(), Value::from(topic.as_str()))),
+            ServerFrame::Event {
+                event: Event::Part(Some(reason)),
+                ..
+            } => fields.push(("reason".to_string(), Value::from(reason.as_str()))),
+            ServerFrame::Names { nicks, .. } => {
+                let nicks = nicks
+                    .iter()
+                    .map(|nick| Value::from(nick.as_str()))
+                    .collect();
+                fields.push(("nicks".to_string(), Value::Array(nicks)));
+            }
+            _ => {}
+        }
+        Value::Object(fields)
+    }
+
+    /// Inverse of `to_json`. Replies lose their stamps, which is fine as they
+    /// get new ones when written again.
+    pub fn from_json(value: &Value) -> Option<ServerFrame> {
+        let text = value.str_field("text")?.to_string();
+        let stamp = || {
+            Some(Stamp {
+                id: value.get("id")?.as_u64()?,
+                ts: value.get("ts")?.as_u64()?,
+            })
+        };
+        let room = value.str_field("room").map(str::to_string);
+        let sender = || value.str_field("sender").map(str::to_string);
+        Some(match value.str_field("type")? {
+            "hello" => ServerFrame::Hello {
+                version: value.get("version")?.as_u64()?.try_into().ok()?,
+                text,
+            },
+            "message" => ServerFrame::Message {
+                stamp: stamp()?,
+                room: room?,
+                from: sender()?,
+                text,
+            },
+            "private" => ServerFrame::Private {
+                stamp: stamp()?,
+                from: sender()?,
+                text,
+            },
+            "notice" => ServerFrame::Notice {
+                stamp: stamp()?,
+                room,
+                text,
+            },
+            kind @ ("join" | "part" | "nick" | "topic") => ServerFrame::Event {
+                stamp: stamp()?,
+                room,
+                nick: sender()?,
+                event: match kind {
+                    "join" => Event::Join,
+                    "part" => Event::Part(value.str_field("reason").map(str::to_string)),
+                    "nick" => Event::Nick(value.str_field("new_nick")?.to_string()),
+                    _ => Event::Topic(value.str_field("topic")?.to_string()),
+                },
+            },
+            "names" => ServerFrame::Names {
+                room: room?,
+                nicks: match value.get("nicks")? {
+                    Value::Array(nicks) => (nicks.iter())
+                        .map(|nick| nick.as_str().map(str::to_string))
+                        .collect::<Option<_>>()?,
+                    _ => return None,
+                },
+            },
+            "ack" => ServerFrame::Ack(text),
+            "error" => ServerFrame::Error(text),
+            "ping" => ServerFrame::Ping(text),
+            _ => return None,
+        })
+    }
+
+    /// What an event looks like as a sentence, e.g. `bob joined #rust`.
+    fn describe(&self) -> String {
+        let ServerFrame::Event {
+            room, nick, event, ..
+        } = self
+        else {
+            return String::new();
+        };
+        match (event, room) {
+            (Event::Join, None) => format!("{nick} joined"),
+            (Event::Join, Some(room)) => format!("{nick} joined {room}"),
+            (Event::Part(None), None) => format!("{nick} left"),
+            (Event::Part(Some(reason)), None) => format!("{nick} left ({reason})"),
+            (Event::Part(_), Some(room)) => format!("{nick} left {room}"),
+            (Event::Nick(new_nick), _) => format!("{nick} is now known as {new_nick}"),
+            (Event::Topic(topic), _) if topic.is_empty() => format!("{nick} cleared the topic"),
+            (Event::Topic(topic), _) => format!("{nick} changed the topic to: {topic}"),
+        }
+    }
+}
+
+impl fmt::Display for ServerFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerFrame::Hello { version, text } => write!(f, "CHAT/{version} {text}"),
+            ServerFrame::Message {
+                room, from, text, ..
+            } => write!(f, "[{room}] <{from}> {text}"),
+            ServerFrame::Private { from, text, .. } => write!(f, "[pm] <{from}> {text}"),
+            ServerFrame::Notice {
+                room: None, text, ..
+            } => write!(f, "*** {text}"),
+            ServerFrame::Notice {
+                room: Some(room),
+                text,
+                ..
+            } => write!(f, "[{room}] *** {text}"),
+            ServerFrame::Event { room: None, .. } => write!(f, "*** {}", self.describe()),
+            ServerFrame::Event {
+                room: Some(room), ..
+            } => write!(f, "[{room}] *** {}", self.describe()),
+            ServerFrame::Names { room, nicks } => {
+                write!(f, "[{room}] *** members: {}", nicks.join(", "))
+            }
+            ServerFrame::Ack(text) => write!(f, "+OK {text}"),
+            ServerFrame::Error(text) => write!(f, "-ERR {text}"),
+            ServerFrame::Ping(token) => write!(f, "PING {token}"),
+        }
+    }
+}
+
+/// Only `Io` means the connection is unusable, the rest are client mistakes
+/// that get answered with an error frame.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    LineTooLong(usize),
+    InvalidUtf8,
+    UnknownCommand(String),
+    Usage(&'static str),
+    Json(JsonError),
+    UnknownType(String),
+    MissingField(&'static str),
+    LineBreak(&'static str),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "{err}"),
+            ProtocolError::LineTooLong(max) => write!(f, "line is longer than {max} bytes"),
+            ProtocolError::InvalidUtf8 => write!(f, "line is not valid UTF-8"),
+            ProtocolError::UnknownCommand(name) => write!(f, "unknown command /{name}"),
+            ProtocolError::Usage(usage) => write!(f, "usage: {usage}"),
+            ProtocolError::Json(err) => write!(f, "{err}"),
+            ProtocolError::UnknownType(kind) => write!(f, "unknown frame type `{kind}`"),
+            ProtocolError::MissingField(field) => write!(f, "missing string field `{field}`"),
+            ProtocolError::LineBreak(field) => write!(f, "`{field}` cannot contain line breaks"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(err) => Some(err),
+            ProtocolError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+/// Server side of the protocol: decodes `ClientFrame`s and encodes
+/// `ServerFrame`s, as text or JSON depending on the mode. Lines longer than
+/// `max_length` are answered with `ProtocolError::LineTooLong`, see `Lines`.
+#[derive(Debug)]
+pub struct ChatCodec {
+    mode: Mode,
+    lines: Lines,
+}
+
+impl ChatCodec {
+    pub fn new(mode: Mode) -> ChatCodec {
+        ChatCodec::with_max_length(mode, DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    pub fn with_max_length(mode: Mode, max_length: usize) -> ChatCodec {
+        ChatCodec {
+            mode,
+            lines: Lines::new(max_length),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    fn parse_line(&self, line: &[u8]) -> Result<Option<ClientFrame>, ProtocolError> {
+        let line = std::str::from_utf8(line).map_err(|_| ProtocolError::InvalidUtf8)?;
+        match self.mode {
+            Mode::Text => ClientFrame::parse(line),
+            Mode::Json => ClientFrame::from_json(line),
+        }
+    }
+}
+
+impl Decoder for ChatCodec {
+    type Item = ClientFrame;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ClientFrame>, ProtocolError> {
+        // Blank lines don't produce a frame, keep going until one does
+        while let Some(line) = self.lines.next_line(src)? {
+            if let Some(frame) = self.parse_line(&line)? {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<ClientFrame>, ProtocolError> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        match self.lines.last_line(src) {
+            Some(line) => self.parse_line(&line),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<ServerFrame> for ChatCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, frame: ServerFrame, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        let line = match self.mode {
+            Mode::Text => frame.to_string(),
+            Mode::Json => frame.to_json().to_string(),
+        };
+        dst.extend_from_slice(line.as_bytes());
+        dst.extend_from_slice(b"\n");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Option<ClientFrame>, String> {
+        ClientFrame::parse(line).map_err(|err| err.to_string())
+    }
+
+    fn from_json(line: &str) -> Result<Option<ClientFrame>, String> {
+        ClientFrame::from_json(line).map_err(|err| err.to_string())
+    }
+
+    fn message(text: &str) -> ClientFrame {
+        ClientFrame::Message {
+            room: None,
+            text: text.to_string(),
+        }
+    }
+
+    fn stamp() -> Stamp {
+        Stamp {
+            id: 42,
+            ts: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn parses_messages_and_commands() {
+        assert_eq!(
+            parse("hello everyone\r"),
+            Ok(Some(message("hello everyone")))
+        );
+        assert_eq!(parse("//shrug"), Ok(Some(message("/shrug"))));
+        assert_eq!(parse("  "), Ok(None));
+        assert_eq!(
+            parse("/nick  bob "),
+            Ok(Some(ClientFrame::Nick("bob".into())))
+        );
+        assert_eq!(parse("/part"), Ok(Some(ClientFrame::Part(None))));
+        assert_eq!(
+            parse("/msg bob see you at 5"),
+            Ok(Some(ClientFrame::Msg {
+                to: "bob".into(),
+                text: "see you at 5".into()
+            }))
+        );
+        assert_eq!(
+            parse("/topic"),
+            Ok(Some(ClientFrame::Topic {
+                room: None,
+                topic: None
+            }))
+        );
+        assert_eq!(
+            parse("/login bob two words"),
+            Ok(Some(ClientFrame::Login {
+                nick: "bob".into(),
+                password: "two words".into()
+            }))
+        );
+        assert_eq!(
+            parse("/hello json"),
+            Ok(Some(ClientFrame::Hello(Mode::Json)))
+        );
+    }
+
+    #[test]
+    fn parse_errors_show_usage() {
+        let cases = [
+            ("/nick", "usage: /nick <name>"),
+            ("/join ", "usage: /join #room"),
+            ("/msg bob", "usage: /msg <nick> <text>"),
+            ("/msg bob   ", "usage: /msg <nick> <text>"),
+            ("/history ten", "usage: /history <n>"),
+            ("/hello xml", "usage: /hello text|json"),
+            ("/register bob", "usage: /register <nick> <password>"),
+            ("/login", "usage: /login <nick> <password>"),
+            ("/ban", "usage: /ban <address or range>"),
+            ("/frobnicate now", "unknown command /frobnicate"),
+            ("hi\rPRIVMSG #x :spoof", "`text` cannot contain line breaks"),
+            ("//hi\rthere", "`text` cannot contain line breaks"),
+            (
+                "/msg bob hi\r:x PRIVMSG",
+                "`text` cannot contain line breaks",
+            ),
+            ("/topic a\rb\r", "`topic` cannot contain line breaks"),
+        ];
+        for (line, error) in cases {
+            assert_eq!(parse(line), Err(error.to_string()), "{line}");
+        }
+    }
+
+    #[test]
+    fn json_errors() {
+        let cases = [
+            ("{", "unexpected end of JSON"),
+            (r#"{"text":"hi"}"#, "missing string field `type`"),
+            (r#"{"type":"dance"}"#, "unknown frame type `dance`"),
+            (
+                r#"{"type":"msg","to":"bob"}"#,
+                "missing string field `text`",
+            ),
+            (r#"{"type":"nick","nick":7}"#, "missing string field `nick`"),
+            (
+                r#"{"type":"message","text":"a\r\nPRIVMSG"}"#,
+                "`text` cannot contain line breaks",
+            ),
+            (
+                r#"{"type":"topic","topic":"a\nb"}"#,
+                "`topic` cannot contain line breaks",
+            ),
+            (
+                r#"{"type":"history","count":-1}"#,
+                "usage: \"count\": a non-negative integer",
+            ),
+            (
+                r#"{"type":"hello","mode":"xml"}"#,
+                "usage: \"mode\": \"text\" or \"json\"",
+            ),
+        ];
+        for (line, error) in cases {
+            assert_eq!(from_json(line), Err(error.to_string()), "{line}");
+        }
+        assert_eq!(from_json(" "), Ok(None));
+    }
+
+    #[test]
+    fn client_frames_survive_json() {
+        let room = Some("#rust".to_string());
+        let frames = [
+            ClientFrame::Hello(Mode::Text),
+            message("hi"),
+            ClientFrame::Message {
+                room: room.clone(),
+                text: "hi \"there\"".into(),
+            },
+            ClientFrame::Nick("bob".into()),
+            ClientFrame::Join("#rust".into()),
+            ClientFrame::Part(None),
+            ClientFrame::Part(room.clone()),
+            ClientFrame::Rooms,
+            ClientFrame::Names(room.clone()),
+            ClientFrame::Msg {
+                to: "alice".into(),
+                text: "psst".into(),
+            },
+            ClientFrame::History {
+                room: room.clone(),
+                count: 50,
+            },
+            ClientFrame::Topic {
+                room: None,
+                topic: Some("all things Rust".into()),
+            },
+            ClientFrame::Register {
+                nick: "bob".into(),
+                password: "hunter22".into(),
+            },
+            ClientFrame::Login {
+                nick: "bob".into(),
+                password: "hunter22".into(),
+            },
+            ClientFrame::Pong("3".into()),
+            ClientFrame::Ban("192.0.2.0/24".into()),
+            ClientFrame::Unban("192.0.2.0/24".into()),
+            ClientFrame::Bans,
+        ];
+        for frame in frames {
+            let line = frame.to_json().to_string();
+            assert_eq!(from_json(&line), Ok(Some(frame)), "{line}");
+        }
+    }
+
+    #[test]
+    fn server_frames_survive_json() {
+        let room = || Some("#rust".to_string());
+        let event = |room, event| ServerFrame::Event {
+            stamp: stamp(),
+            room,
+            nick: "bob".into(),
+            event,
+        };
+        let frames = [
+            ServerFrame::Hello {
+                version: PROTOCOL_VERSION,
+                text: "welcome".into(),
+            },
+            ServerFrame::Message {
+                stamp: stamp(),
+                room: "#rust".into(),
+                from: "alice".into(),
+                text: "héllo \u{1F980}".into(),
+            },
+            ServerFrame::Private {
+                stamp: stamp(),
+                from: "alice".into(),
+                text: "psst".into(),
+            },
+            ServerFrame::Notice {
+                stamp: stamp(),
+                room: None,
+                text: "server is shutting down".into(),
+            },
+            event(None, Event::Join),
+            event(room(), Event::Join),
+            event(None, Event::Part(Some("timeout".into()))),
+            event(room(), Event::Part(None)),
+            event(None, Event::Nick("robert".into())),
+            event(room(), Event::Topic(String::new())),
+            ServerFrame::Names {
+                room: "#rust".into(),
+                nicks: vec!["alice".into(), "bob".into()],
+            },
+            ServerFrame::Ack("you joined #rust".into()),
+            ServerFrame::Error("unknown command /foo".into()),
+            ServerFrame::Ping("3".into()),
+        ];
+        for frame in frames {
+            let line = frame.to_json().to_string();
+            let value = Value::parse(&line).unwrap();
+            assert_eq!(ServerFrame::from_json(&value), Some(frame), "{line}");
+        }
+    }
+
+    #[test]
+    fn server_frames_as_text() {
+        let frame = ServerFrame::Event {
+            stamp: stamp(),
+            room: Some("#rust".into()),
+            nick: "bob".into(),
+            event: Event::Topic("news".into()),
+        };
+        assert_eq!(
+            frame.to_string(),
+            "[#rust] *** bob changed the topic to: news"
+        );
+        let frame = ServerFrame::Private {
+            stamp: stamp(),
+            from: "alice".into(),
+            text: "psst".into(),
+        };
+        assert_eq!(frame.to_string(), "[pm] <alice> psst");
+        assert_eq!(ServerFrame::Error("no".into()).to_string(), "-ERR no");
+    }
+
+    #[test]
+    fn codec_decodes_crlf_and_skips_blank_lines() {
+        let mut codec = ChatCodec::new(Mode::Text);
+        let mut src = BytesMut::from("\r\n\nhi\r\n/nick bob\r\n");
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(message("hi")));
+        let nick = ClientFrame::Nick("bob".into());
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(nick));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn codec_reports_bad_lines_and_keeps_going() {
+        let mut codec = ChatCodec::with_max_length(Mode::Text, 8);
+        let mut src = BytesMut::from(&b"\xff\xfe\nthis is too long\nok\nbye"[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(ProtocolError::InvalidUtf8)
+        ));
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(ProtocolError::LineTooLong(8))
+        ));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(message("ok")));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(codec.decode_eof(&mut src).unwrap(), Some(message("bye")));
+    }
+
+    #[test]
+    fn codec_switches_modes() {
+        let mut codec = ChatCodec::new(Mode::Json);
+        let mut src = BytesMut::from("{\"type\":\"rooms\"}\n/rooms\n");
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(ClientFrame::Rooms));
+        codec.set_mode(Mode::Text);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(ClientFrame::Rooms));
+
+        let mut dst = BytesMut::new();
+        codec
+            .encode(ServerFrame::Ack("ok".into()), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], b"+OK ok\n");
+    }
+}
@@ -0,0 +1,288 @@
+//! Flood protection: token buckets on the messages and bytes each session
+//! sends.
+//!
+//! A frame over the limit isn't refused, the session just stops reading
+//! until the buckets have refilled, so TCP pushes back on the client. The
+//! first time that happens the client is warned, and a client that keeps
+//! hitting the limit is disconnected.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+
+/// Messages and bytes per second. Bursts of up to `BURST` seconds' worth
+/// go through without waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub bytes: u64,
+}
+
+const BURST: f64 = 2.0;
+
+/// Strikes are forgotten after this long without a new one
+const STRIKE_MEMORY: Duration = Duration::from_secs(10);
+
+/// What to do with a frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Hold the frame back this long, telling the client why if `warn`
+    Throttle {
+        wait: Duration,
+        warn: bool,
+    },
+    /// Too many strikes, disconnect
+    Kick,
+}
+
+pub struct Bucket {
+    /// Per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// A full bucket refilling at `rate` tokens per second.
+    pub fn new(rate: f64, capacity: f64) -> Bucket {
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take `cost` tokens, going into debt if there aren't enough. Returns
+    /// how long until the debt is paid off.
+    fn take(&mut self, cost: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= cost;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Take `cost` tokens only if there are enough.
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> bool {
+        self.refill(now);
+        let enough = self.tokens >= cost;
+        if enough {
+            self.tokens -= cost;
+        }
+        enough
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// A pair of buckets for one `RateLimit`.
+struct Buckets {
+    messages: Bucket,
+    bytes: Bucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Buckets {
+        Buckets {
+            messages: Bucket::new(limit.messages.into(), f64::from(limit.messages) * BURST),
+            bytes: Bucket::new(limit.bytes as f64, limit.bytes as f64 * BURST),
+        }
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let messages = self.messages.take(1.0, now);
+        messages.max(self.bytes.take(bytes as f64, now))
+    }
+}
+
+/// The limits of one session. Guests and logged in members have their own
+/// limits, and rooms can have stricter (or looser) ones on top.
+pub struct Limiter {
+    guest_limit: Option<RateLimit>,
+    member_limit: Option<RateLimit>,
+    room_limits: Vec<(String, RateLimit)>,
+    kick_after: u32,
+    member: bool,
+    session: Option<Buckets>,
+    // lowercased room -> buckets, for rooms with their own limit
+    rooms: HashMap<String, Buckets>,
+    strikes: u32,
+    last_strike: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Limiter {
+        Limiter {
+            guest_limit: config.rate_limit,
+            member_limit: config.member_rate_limit,
+            room_limits: config.room_rate_limits.clone(),
+            kick_after: config.rate_kick_after,
+            member: false,
+            session: config.rate_limit.map(Buckets::new),
+            rooms: HashMap::new(),
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    /// Account for a frame of `bytes` from a guest or member, sent to
+    /// `room` if it is a room message.
+    pub fn check(&mut self, member: bool, room: Option<&str>, bytes: usize) -> Verdict {
+        if member != self.member {
+            self.member = member;
+            let limit = if member {
+                self.member_limit
+            } else {
+                self.guest_limit
+            };
+            self.session = limit.map(Buckets::new);
+        }
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        if let Some(session) = &mut self.session {
+            wait = session.take(bytes, now);
+        }
+        if let Some(room) = room {
+            let limit = (self.room_limits.iter())
+                .find(|(name, _)| name.eq_ignore_ascii_case(room))
+                .map(|(_, limit)| *limit);
+            if let Some(limit) = limit {
+                let buckets = (self.rooms)
+                    .entry(room.to_ascii_lowercase())
+                    .or_insert_with(|| Buckets::new(limit));
+                wait = wait.max(buckets.take(bytes, now));
+            }
+        }
+        if wait.is_zero() {
+            return Verdict::Pass;
+        }
+        if self
+            .last_strike
+            .is_some_and(|last| now.duration_since(last) > STRIKE_MEMORY)
+        {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        if self.kick_after > 0 && self.strikes >= self.kick_after {
+            return Verdict::Kick;
+        }
+        Verdict::Throttle {
+            wait,
+            warn: self.strikes == 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(messages: u32, kick_after: u32) -> Limiter {
+        Limiter::new(&Config {
+            rate_limit: Some(RateLimit {
+                messages,
+                bytes: 1 << 20,
+            }),
+            member_rate_limit: None,
+            room_rate_limits: vec![(
+                "#slow".to_string(),
+                RateLimit {
+                    messages: 1,
+                    bytes: 1 << 20,
+                },
+            )],
+            rate_kick_after: kick_after,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn buckets_go_into_debt_and_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2.0, 4.0);
+        assert!(bucket.is_full(start));
+        assert_eq!(bucket.take(4.0, start), Duration::ZERO);
+        assert_eq!(bucket.take(1.0, start), Duration::from_millis(500));
+        assert!(!bucket.try_take(1.0, start));
+        // Refilled at 2 per second, paying off the debt first
+        let later = start + Duration::from_secs(1);
+        assert!(bucket.try_take(1.0, later));
+        assert!(!bucket.try_take(1.0, later));
+        // Never more than the capacity
+        assert!(bucket.is_full(start + Duration::from_secs(60)));
+        assert!(bucket.try_take(4.0, start + Duration::from_secs(60)));
+        assert!(!bucket.try_take(0.5, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn bursts_pass_then_frames_are_held_back() {
+        let mut limiter = limiter(5, 0);
+        // Two seconds' worth go through at once
+        for _ in 0..10 {
+            assert_eq!(limiter.check(false, None, 10), Verdict::Pass);
+        }
+        match limiter.check(false, None, 10) {
+            Verdict::Throttle { wait, warn: true } => {
+                assert!(wait <= Duration::from_millis(200), "{wait:?}")
+            }
+            verdict => panic!("{verdict:?}"),
+        }
+        assert!(matches!(
+            limiter.check(false, None, 10),
+            Verdict::Throttle { warn: false, .. }
+        ));
+    }
+
+    #[test]
+    fn strikes_end_in_a_kick() {
+        let mut limiter = limiter(1, 3);
+        assert_eq!(limiter.check(false, None, 0), Verdict::Pass);
+        assert_eq!(limiter.check(false, None, 0), Verdict::Pass);
+        assert!(matches!(
+            limiter.check(false, None, 0),
+            Verdict::Throttle { .. }
+        ));
+        assert!(matches!(
+            limiter.check(false, None, 0),
+            Verdict::Throttle { .. }
+        ));
+        assert_eq!(limiter.check(false, None, 0), Verdict::Kick);
+    }
+
+    #[test]
+    fn rooms_and_members_have_their_own_limits() {
+        let mut limiter = limiter(100, 0);
+        assert_eq!(limiter.check(false, Some("#SLOW"), 0), Verdict::Pass);
+        assert_eq!(limiter.check(false, Some("#slow"), 0), Verdict::Pass);
+        assert!(matches!(
+            limiter.check(false, Some("#slow"), 0),
+            Verdict::Throttle { .. }
+        ));
+        assert_eq!(limiter.check(false, Some("#lobby"), 0), Verdict::Pass);
+        // No member limit, but the room's still applies
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(true, None, 1 << 20), Verdict::Pass);
+        }
+        assert!(matches!(
+            limiter.check(true, Some("#slow"), 0),
+            Verdict::Throttle { .. }
+        ));
+    }
+}
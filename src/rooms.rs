@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+pub const MAX_ROOM_LEN: usize = 32;
+
+pub const MAX_TOPIC_LEN: usize = 300;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoomError {
+    MissingHash,
+    Empty,
+    TooLong,
+    BadChar(char),
+    NotMember(String),
+    NoCurrentRoom,
+    TopicTooLong,
+    /// Room is only open to users logged in to an account
+    AuthRequired(String),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::MissingHash => write!(f, "room names start with #"),
+            RoomError::Empty => write!(f, "room name cannot be empty"),
+            RoomError::TooLong => write!(f, "room name is longer than {MAX_ROOM_LEN} characters"),
+            RoomError::BadChar(c) => write!(f, "room name cannot contain `{c}`"),
+            RoomError::NotMember(room) => write!(f, "you are not in {room}"),
+            RoomError::NoCurrentRoom => write!(f, "you are not in a room, /join #name first"),
+            RoomError::TopicTooLong => {
+                write!(f, "topic is longer than {MAX_TOPIC_LEN} characters")
+            }
+            RoomError::AuthRequired(room) => {
+                write!(f, "{room} is only open to registered users, /login first")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+/// `#` followed by letters, digits, `_` or `-`.
+pub fn validate_room(name: &str) -> Result<(), RoomError> {
+    let rest = name.strip_prefix('#').ok_or(RoomError::MissingHash)?;
+    if rest.is_empty() {
+        return Err(RoomError::Empty);
+    }
+    if name.len() > MAX_ROOM_LEN {
+        return Err(RoomError::TooLong);
+    }
+    match rest
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        Some(c) => Err(RoomError::BadChar(c)),
+        None => Ok(()),
+    }
+}
+
+struct Room {
+    /// Name as it was first spelled
+    name: String,
+    /// connection -> nickname of its user
+    members: HashMap<SocketAddr, String>,
+    topic: Option<String>,
+}
+
+/// Rooms and who is in them. A room exists as long as it has members: the
+/// first join creates it and the last part removes it.
+#[derive(Default)]
+pub struct Rooms {
+    // lowercased name -> room, sorted so listings come out in order
+    rooms: Mutex<BTreeMap<String, Room>>,
+}
+
+impl Rooms {
+    /// Add `addr` to the room, creating it if needed. Returns the room's
+    /// canonical name and whether `addr` wasn't a member already.
+    pub fn join(
+        &self,
+        name: &str,
+        addr: SocketAddr,
+        nick: &str,
+    ) -> Result<(String, bool), RoomError> {
+        validate_room(name)?;
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .entry(name.to_ascii_lowercase())
+            .or_insert_with(|| Room {
+                name: name.to_string(),
+                members: HashMap::new(),
+                topic: None,
+            });
+        let joined = room.members.insert(addr, nick.to_string()).is_none();
+        Ok((room.name.clone(), joined))
+    }
+
+    /// Remove `addr` from the room and return the room's canonical name.
+    pub fn part(&self, name: &str, addr: SocketAddr) -> Result<String, RoomError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let key = name.to_ascii_lowercase();
+        let room = rooms
+            .get_mut(&key)
+            .filter(|room| room.members.contains_key(&addr))
+            .ok_or_else(|| RoomError::NotMember(name.to_string()))?;
+        room.members.remove(&addr);
+        let name = room.name.clone();
+        if room.members.is_empty() {
+            rooms.remove(&key);
+        }
+        Ok(name)
+    }
+
+    /// Remove `addr` from every room it is in.
+    pub fn part_all(&self, addr: SocketAddr) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| {
+            room.members.remove(&addr);
+            !room.members.is_empty()
+        });
+    }
+
+    /// Update the nickname shown for `addr` in all of its rooms.
+    pub fn rename(&self, addr: SocketAddr, nick: &str) {
+        for room in self.rooms.lock().unwrap().values_mut() {
+            if let Some(member) = room.members.get_mut(&addr) {
+                *member = nick.to_string();
+            }
+        }
+    }
+
+    pub fn is_member(&self, name: &str, addr: SocketAddr) -> bool {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(&name.to_ascii_lowercase())
+            .is_some_and(|room| room.members.contains_key(&addr))
+    }
+
+    /// Every room with its number of members.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .map(|room| (room.name.clone(), room.members.len()))
+            .collect()
+    }
+
+    /// Nicknames in the room, sorted, or `None` if there is no such room.
+    pub fn members(&self, name: &str) -> Option<Vec<String>> {
+        let rooms = self.rooms.lock().unwrap();
+        let room = rooms.get(&name.to_ascii_lowercase())?;
+        let mut members: Vec<_> = room.members.values().cloned().collect();
+        members.sort_by_key(|nick| nick.to_ascii_lowercase());
+        Some(members)
+    }
+
+    pub fn topic(&self, name: &str) -> Option<String> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(&name.to_ascii_lowercase())?.topic.clone()
+    }
+
+    /// Set the topic of a room `addr` is in, an empty one clears it. Returns
+    /// the room's canonical name.
+    pub fn set_topic(
+        &self,
+        name: &str,
+        addr: SocketAddr,
+        topic: &str,
+    ) -> Result<String, RoomError> {
+        if topic.chars().count() > MAX_TOPIC_LEN {
+            return Err(RoomError::TopicTooLong);
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .get_mut(&name.to_ascii_lowercase())
+            .filter(|room| room.members.contains_key(&addr))
+            .ok_or_else(|| RoomError::NotMember(name.to_string()))?;
+        room.topic = (!topic.is_empty()).then(|| topic.to_string());
+        Ok(room.name.clone())
+    }
+
+    /// Rooms `addr` is in.
+    pub fn joined(&self, addr: SocketAddr) -> Vec<String> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|room| room.members.contains_key(&addr))
+            .map(|room| room.name.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,431 @@
+//! The embeddable server: build a `ChatServer`, keep a `ServerHandle` to
+//! talk to it, then `run` it on a listener until the handle shuts it down.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    access::Access,
+    accounts::Accounts,
+    bot::{self, Bot, BotError, BotHandle},
+    config::Config,
+    connection::handle_connection,
+    error::ChatError,
+    irc::handle_irc,
+    limits::Refusal,
+    plugin::{ChatPlugin, Plugins},
+    protocol::{Mode, ServerFrame},
+    state::State,
+    tls,
+    websocket::{self, HANDSHAKE_TIMEOUT},
+};
+
+/// How long to wait before retrying after `accept()` fails (e.g. EMFILE).
+/// Doubles on every consecutive failure up to the maximum.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Time between keepalive probes once they started
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often shutdown checks whether all sessions are gone
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// What clients on a listener speak.
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    /// Protocol lines, starting in text mode
+    Text,
+    /// Protocol lines, starting in JSON mode
+    Json,
+    /// Protocol lines over TLS, starting in text mode
+    Tls,
+    /// Protocol lines carried in WebSocket text messages, starting in text mode
+    WebSocket,
+    /// IRC commands and numeric replies
+    Irc,
+}
+
+/// Sets up a `ChatServer`. Everything is optional, the defaults are the
+/// same as the binary's.
+#[derive(Default)]
+pub struct ChatServerBuilder {
+    config: Config,
+    listeners: Vec<(TcpListener, Transport)>,
+    plugins: Plugins,
+}
+
+impl ChatServerBuilder {
+    /// Settings to run with. `port` and the other port settings are up to
+    /// whoever binds the listeners, the server only looks at `tls_port` to
+    /// tell whether `run`'s listener speaks TLS.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Also serve clients that speak JSON from the start on `listener`.
+    pub fn json_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Transport::Json));
+        self
+    }
+
+    /// Also accept WebSocket connections on `listener`.
+    pub fn ws_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Transport::WebSocket));
+        self
+    }
+
+    /// Also serve the text protocol over TLS on `listener`. Needs `tls_cert`
+    /// and `tls_key` in the config.
+    pub fn tls_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Transport::Tls));
+        self
+    }
+
+    /// Also accept IRC clients on `listener`.
+    pub fn irc_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Transport::Irc));
+        self
+    }
+
+    /// Add a plugin. Plugins see events in the order they were added.
+    pub fn plugin(mut self, plugin: impl ChatPlugin) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Load the accounts and access files and the TLS certificate the config
+    /// names, if any.
+    pub fn build(self) -> io::Result<ChatServer> {
+        let config = self.config;
+        let accounts = match config.accounts_file.as_deref() {
+            Some(path) => Some(Accounts::load(path).map_err(|err| {
+                let message = format!("cannot load accounts from {}: {err}", path.display());
+                io::Error::new(err.kind(), message)
+            })?),
+            None => None,
+        };
+        let access = Access::load(config.access_file.as_deref()).map_err(|err| {
+            io::Error::new(err.kind(), format!("cannot load access rules: {err}"))
+        })?;
+        let tls = match (config.tls_cert.as_deref(), config.tls_key.as_deref()) {
+            (Some(cert), Some(key)) => {
+                let client_ca = config.tls_client_ca.as_deref();
+                Some(tls::acceptor(cert, key, client_ca).map_err(|err| {
+                    io::Error::new(err.kind(), format!("cannot load TLS certificates: {err}"))
+                })?)
+            }
+            (None, None) if config.tls_client_ca.is_some() => {
+                let message = "tls_client_ca needs tls_cert and tls_key";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            (None, None) => None,
+            _ => {
+                let message = "tls_cert and tls_key must be set together";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        };
+        let tls_listener = (self.listeners.iter()).any(|(_, t)| matches!(t, Transport::Tls));
+        if tls_listener && tls.is_none() {
+            let message = "serving TLS needs tls_cert and tls_key";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        Ok(ChatServer {
+            state: Arc::new(State::new(config, accounts, access, self.plugins)),
+            listeners: self.listeners,
+            tls,
+        })
+    }
+}
+
+/// A chat server that hasn't started yet.
+pub struct ChatServer {
+    state: Arc<State>,
+    listeners: Vec<(TcpListener, Transport)>,
+    /// `None` without a certificate
+    tls: Option<TlsAcceptor>,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::default()
+    }
+
+    /// A handle to the server, it keeps working while `run` is running.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            state: Arc::downgrade(&self.state),
+        }
+    }
+
+    /// Serve the text protocol on `listener`, and the builder's other
+    /// listeners, until `ServerHandle::shutdown`. With a certificate but no
+    /// `tls_port`, `listener` speaks TLS. Returns once the sessions said
+    /// goodbye (or the shutdown timeout passed) and history is saved.
+    pub async fn run(self, listener: TcpListener) {
+        let state = self.state;
+        let main = match &self.tls {
+            Some(_) if state.config.tls_port.is_none() => Transport::Tls,
+            _ => Transport::Text,
+        };
+        for (listener, transport) in self.listeners {
+            tokio::spawn(serve(listener, transport, state.clone(), self.tls.clone()));
+        }
+        tokio::spawn(serve(listener, main, state.clone(), self.tls));
+
+        state.shutting_down().await;
+        drain(&state).await;
+        if let Some(history) = &state.history {
+            if let Err(err) = history.flush().await {
+                error!("cannot save history: {err}");
+            }
+        }
+    }
+}
+
+/// Talks to a running server from the outside. Cheap to clone, and
+/// harmless to keep after the server stopped: it just doesn't do anything
+/// anymore.
+#[derive(Clone)]
+pub struct ServerHandle {
+    // Weak, so the server can tell when all sessions are gone
+    state: Weak<State>,
+}
+
+impl ServerHandle {
+    /// Send a notice to the members of `room`, or to everyone. Line breaks
+    /// in `text` become spaces, so it can't pass for other lines.
+    pub fn broadcast(&self, room: Option<&str>, text: &str) {
+        if let Some(state) = self.state.upgrade() {
+            state.notify(room, text);
+        }
+    }
+
+    /// Nicknames of everyone online, sorted.
+    pub fn users(&self) -> Vec<String> {
+        (self.state.upgrade()).map_or_else(Vec::new, |state| state.users.list())
+    }
+
+    /// Rooms with their number of members, sorted by name.
+    pub fn rooms(&self) -> Vec<(String, usize)> {
+        let Some(state) = self.state.upgrade() else {
+            return Vec::new();
+        };
+        let mut rooms = state.rooms.list();
+        rooms.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+        rooms
+    }
+
+    /// Connection and lag counters, one line each.
+    pub fn stats(&self) -> Vec<String> {
+        let Some(state) = self.state.upgrade() else {
+            return Vec::new();
+        };
+        vec![
+            format!("connections: {}", state.connections),
+            format!("lag policy: {}", state.lag_stats),
+        ]
+    }
+
+    /// Read the access file again. On errors the old rules stay. Returns
+    /// the number of `allow` and `deny` rules, `None` without a file.
+    pub fn reload_access(&self) -> io::Result<Option<(usize, usize)>> {
+        match self.state.upgrade() {
+            Some(state) => state.access.reload(),
+            None => Ok(None),
+        }
+    }
+
+    /// Start `bot` as a user of this server. Fails if its nick is taken or
+    /// it can't join one of its rooms, or a plugin refuses it.
+    pub async fn spawn_bot(&self, bot: Bot) -> Result<BotHandle, BotError> {
+        let state = self.state.upgrade().ok_or(BotError::Stopped)?;
+        bot::spawn(bot, state).await
+    }
+
+    /// Stop accepting clients and tell every session to wrap up. `run`
+    /// returns once they are done.
+    pub fn shutdown(&self) {
+        if let Some(state) = self.state.upgrade() {
+            state.begin_shutdown();
+        }
+    }
+}
+
+/// Wait until the listeners, sessions and their helper tasks are done, or
+/// the shutdown timeout passed. Each of them holds a clone of `state`, so
+/// they are done when only ours is left.
+async fn drain(state: &Arc<State>) {
+    let timeout = state.config.shutdown_timeout;
+    let drained = time::timeout(timeout, async {
+        while Arc::strong_count(state) > 1 {
+            time::sleep(DRAIN_POLL).await;
+        }
+    });
+    if drained.await.is_err() {
+        warn!("sessions still busy after {timeout:?}, closing them anyway");
+    }
+}
+
+/// Accept clients on `listener` until the server shuts down. All listeners
+/// share the same connection limits. `tls` is only used by TLS listeners.
+async fn serve(
+    listener: TcpListener,
+    transport: Transport,
+    state: Arc<State>,
+    tls: Option<TlsAcceptor>,
+) {
+    let mut accept_backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = state.shutting_down() => return,
+        };
+        let (socket, addr) = match accepted {
+            Ok(accepted) => {
+                accept_backoff = ACCEPT_BACKOFF_MIN;
+                accepted
+            }
+            Err(err) => {
+                error!("{}, retrying in {accept_backoff:?}", ChatError::Accept(err));
+                time::sleep(accept_backoff).await;
+                accept_backoff = (accept_backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        if !state.access.allows(addr.ip()) {
+            state.connections.refused(Refusal::Denied);
+            debug!("refusing {addr}: {}", Refusal::Denied);
+            refuse(&socket, transport, Refusal::Denied);
+            continue;
+        }
+        let slot = match state.connections.admit(addr.ip()) {
+            Ok(slot) => slot,
+            Err(refusal) => {
+                warn!("refusing {addr}: {refusal}");
+                refuse(&socket, transport, refusal);
+                continue;
+            }
+        };
+        if let Some(idle) = state.config.tcp_keepalive {
+            let keepalive = TcpKeepalive::new()
+                .with_time(idle)
+                .with_interval(KEEPALIVE_INTERVAL);
+            if let Err(err) = SockRef::from(&socket).set_tcp_keepalive(&keepalive) {
+                warn!("cannot turn on TCP keepalive for {addr}: {err}");
+            }
+        }
+        let state = state.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            info!("{addr} connected ({transport:?})");
+            let result = match transport {
+                Transport::Text => handle_connection(socket, addr, state, Mode::Text, None).await,
+                Transport::Json => handle_connection(socket, addr, state, Mode::Json, None).await,
+                Transport::Tls => {
+                    let acceptor = tls.expect("`build` checked there is a certificate");
+                    serve_tls(socket, addr, state, acceptor).await
+                }
+                Transport::WebSocket => {
+                    let max_message = state.config.max_line_length;
+                    let upgrade = websocket::accept(socket, max_message);
+                    let upgrade = time::timeout(HANDSHAKE_TIMEOUT, upgrade);
+                    match upgrade.await {
+                        Ok(Ok(stream)) => {
+                            handle_connection(stream, addr, state, Mode::Text, None).await
+                        }
+                        Ok(Err(err)) => Err(ChatError::Handshake(err)),
+                        Err(_) => Err(ChatError::Handshake(io::ErrorKind::TimedOut.into())),
+                    }
+                }
+                Transport::Irc => handle_irc(socket, addr, state).await,
+            };
+            match result {
+                Ok(()) => info!("{addr} disconnected"),
+                Err(err) => warn!("{addr} dropped: {err}"),
+            }
+        });
+    }
+}
+
+/// Finish the TLS handshake and run the session. A client certificate, if
+/// the config asks for one, logs the client in under the nick it names.
+async fn serve_tls(
+    socket: TcpStream,
+    addr: SocketAddr,
+    state: Arc<State>,
+    acceptor: TlsAcceptor,
+) -> Result<(), ChatError> {
+    let handshake = time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(socket));
+    let stream = match handshake.await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => return Err(ChatError::Tls(err)),
+        Err(_) => return Err(ChatError::Tls(io::ErrorKind::TimedOut.into())),
+    };
+    // rustls verified the certificate already, if there is one
+    let nick = match stream.get_ref().1.peer_certificates() {
+        Some(certs) => match tls::certificate_nick(&certs[0]) {
+            Some(nick) => Some(nick),
+            None => {
+                let message = "client certificate names no valid nickname";
+                return Err(ChatError::Tls(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    message,
+                )));
+            }
+        },
+        None => None,
+    };
+    handle_connection(stream, addr, state, Mode::Text, nick).await
+}
+
+/// Tell a client why it is turned away, in its own protocol. Best effort
+/// and without waiting, so refusals can't hold up the accept loop. Dropping
+/// the socket afterwards closes it.
+fn refuse(socket: &TcpStream, transport: Transport, refusal: Refusal) {
+    let reason = refusal.to_string();
+    let text = match transport {
+        Transport::Text => format!("{}\n", ServerFrame::Error(reason)),
+        Transport::Json => format!("{}\n", ServerFrame::Error(reason).to_json()),
+        // Nothing a TLS client could read before the handshake
+        Transport::Tls => return,
+        Transport::WebSocket => format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}",
+            reason.len()
+        ),
+        Transport::Irc => format!("ERROR :Closing link ({reason})\r\n"),
+    };
+    // Straight to the kernel, `try_write` would wait for the first readiness
+    // event. A fresh socket's send buffer is empty, so this fits.
+    let _ = SockRef::from(socket).send(text.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn broadcasts_stay_on_one_line() {
+        let server = ChatServer::builder().build().unwrap();
+        let mut bus = server.state.channel_send.subscribe();
+        server
+            .handle()
+            .broadcast(None, "hi\n+OK you are now admin\r\n");
+        let message = bus.try_recv().unwrap();
+        assert_eq!(message.frame.to_string(), "*** hi +OK you are now admin  ");
+    }
+}
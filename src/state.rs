@@ -0,0 +1,116 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    access::Access,
+    accounts::Accounts,
+    config::Config,
+    connection::ChatMessage,
+    history::History,
+    lag::LagStats,
+    limits::Connections,
+    plugin::{single_line, Plugins},
+    protocol::{Event, ServerFrame, Stamp},
+    rooms::Rooms,
+    users::Users,
+};
+
+/// Sender of frames that come from the server itself rather than a client.
+/// No client connects from port 0, so everyone gets them.
+pub const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Everything the connection tasks share.
+pub struct State {
+    pub config: Config,
+    pub channel_send: broadcast::Sender<ChatMessage>,
+    pub lag_stats: LagStats,
+    /// Connection limits and their counters
+    pub connections: Arc<Connections>,
+    /// Who may connect at all
+    pub access: Access,
+    pub plugins: Plugins,
+    pub users: Users,
+    pub rooms: Rooms,
+    /// `None` unless a history directory is configured
+    pub history: Option<History>,
+    /// `None` unless an accounts file is configured
+    pub accounts: Option<Accounts>,
+    /// Flips to `true` once, when the server starts shutting down
+    shutdown: watch::Sender<bool>,
+}
+
+impl State {
+    pub fn new(
+        config: Config,
+        accounts: Option<Accounts>,
+        access: Access,
+        plugins: Plugins,
+    ) -> State {
+        let (channel_send, _) = broadcast::channel(config.channel_capacity);
+        State {
+            channel_send,
+            lag_stats: LagStats::default(),
+            connections: Arc::new(Connections::new(&config)),
+            access,
+            plugins,
+            users: Users::new(config.offline_messages),
+            rooms: Rooms::default(),
+            history: config
+                .history_dir
+                .as_ref()
+                .map(|dir| History::new(dir, config.history_max_age, config.history_max_size)),
+            accounts,
+            shutdown: watch::channel(false).0,
+            config,
+        }
+    }
+
+    /// Ask every listener and session to wrap up.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once `begin_shutdown` was called. Cancel safe, so it can be
+    /// used in `select!`.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        if !*shutdown.borrow() {
+            // The sender lives as long as `self`
+            let _ = shutdown.changed().await;
+        }
+    }
+
+    /// Send a notice from the server to the members of `room`, or to
+    /// everyone. Line breaks in `text` become spaces.
+    pub fn notify(&self, room: Option<&str>, text: &str) {
+        let frame = ServerFrame::Notice {
+            stamp: Stamp::new(),
+            room: room.map(str::to_string),
+            text: single_line(text.to_string()),
+        };
+        // Nobody listening is fine
+        let _ = self.channel_send.send(ChatMessage {
+            frame,
+            from: SERVER_ADDR,
+        });
+    }
+
+    /// Tell everyone except `about` (or only the members of `room`) that
+    /// `nick` joined, left or was renamed. Nobody listening is not an error
+    /// for an event.
+    pub fn announce(&self, room: Option<&str>, nick: &str, event: Event, about: SocketAddr) {
+        let _ = self.channel_send.send(ChatMessage {
+            frame: ServerFrame::Event {
+                stamp: Stamp::new(),
+                room: room.map(str::to_string),
+                nick: nick.to_string(),
+                event,
+            },
+            from: about,
+        });
+    }
+}
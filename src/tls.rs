@@ -0,0 +1,191 @@
+//! TLS for the text protocol, terminated with rustls, and client
+//! certificates standing in for a login.
+
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use crate::users::validate_nick;
+
+use tokio_rustls::{
+    rustls::{
+        pki_types::{
+            pem::{self, PemObject},
+            CertificateDer, PrivateKeyDer,
+        },
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// How long a client gets to finish the TLS handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build an acceptor from a PEM certificate chain (leaf first) and the PEM
+/// private key that goes with it. With `client_ca`, clients must present a
+/// certificate issued by one of the PEM certificates in that file.
+pub fn acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| pem_error(key_path, err))?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert).map_err(|err| invalid_data(ca_path, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|err| invalid_data(ca_path, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid_data(cert_path, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The nick a client certificate stands for: its subject's common name, or
+/// else one of its DNS names, whichever is a valid nickname first. `None`
+/// if there is no such name, or the certificate can't be read.
+pub fn certificate_nick(cert: &CertificateDer<'_>) -> Option<String> {
+    let (common_names, dns_names) = certificate_names(cert)?;
+    (common_names.into_iter().rev())
+        .chain(dns_names)
+        .find(|name| validate_nick(name).is_ok())
+}
+
+/// Subject common names and DNS names of a DER certificate, `None` if it
+/// can't be parsed. The certificate was verified already.
+fn certificate_names(cert: &[u8]) -> Option<(Vec<String>, Vec<String>)> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let common_names = (cert.subject().iter_common_name())
+        .map(|name| name.as_str().map(str::to_string))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let dns_names = match cert.subject_alternative_name().ok()? {
+        Some(names) => (names.value.general_names.iter())
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    Some((common_names, dns_names))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(path, err))?;
+    if certs.is_empty() {
+        let message = format!("no certificate in {}", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(certs)
+}
+
+fn invalid_data(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+fn pem_error(path: &Path, err: pem::Error) -> io::Error {
+    let kind = match &err {
+        pem::Error::Io(err) => err.kind(),
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, format!("{}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::*;
+
+    #[test]
+    fn bad_files_are_reported() {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+
+        let missing = acceptor(&dir.join("missing.pem"), &empty, None)
+            .err()
+            .unwrap();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        assert!(missing.to_string().contains("missing.pem"), "{missing}");
+        let no_cert = acceptor(&empty, &empty, None).err().unwrap();
+        assert_eq!(no_cert.kind(), io::ErrorKind::InvalidData);
+        assert!(no_cert.to_string().starts_with("no certificate in"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// CN "Alice Smith", DNS names alice.example.com and alice
+    const ALICE: &str = "-----BEGIN CERTIFICATE-----
+MIIByzCCAXKgAwIBAgIUVw1f17o7cIFpR61LEDqSv7jhFAcwCgYIKoZIzj0EAwIw
+KDEQMA4GA1UECgwHRXhhbXBsZTEUMBIGA1UEAwwLQWxpY2UgU21pdGgwIBcNMjYx
+MDE2MDAzNjUzWhgPMjEyNjA5MjIwMDM2NTNaMCgxEDAOBgNVBAoMB0V4YW1wbGUx
+FDASBgNVBAMMC0FsaWNlIFNtaXRoMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE
+qj2EC4zeYap4/Nd6gk8iMd6Dzusq5/gFy5O0SvTPrLU9KOLQ5Gl2/ShkXfbS3sny
+yHSEHdPZktAXoPBCLAtrl6N4MHYwHQYDVR0OBBYEFCDzfJKlmz+osLuCAQEMLGnl
+cpkPMB8GA1UdIwQYMBaAFCDzfJKlmz+osLuCAQEMLGnlcpkPMA8GA1UdEwEB/wQF
+MAMBAf8wIwYDVR0RBBwwGoIRYWxpY2UuZXhhbXBsZS5jb22CBWFsaWNlMAoGCCqG
+SM49BAMCA0cAMEQCICmGrZ7DvfXgcZL8ZV9euZogoEV7XXnGGgGOqann8PXzAiAc
+22OydVEimng/aTNDEiu2C46AXgdXG/tVOuIe1V0R3w==
+-----END CERTIFICATE-----";
+
+    /// CN bob, no DNS names
+    const BOB: &str = "-----BEGIN CERTIFICATE-----
+MIIBlzCCAT2gAwIBAgIULXsVOnspDmwOc1YakJVHfHKFHtswCgYIKoZIzj0EAwIw
+IDEQMA4GA1UECgwHRXhhbXBsZTEMMAoGA1UEAwwDYm9iMCAXDTI2MTAxNjAwMzY1
+M1oYDzIxMjYwOTIyMDAzNjUzWjAgMRAwDgYDVQQKDAdFeGFtcGxlMQwwCgYDVQQD
+DANib2IwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATNU57ICTf1LXDZYq3i9iNm
+qvF2NYDNa/YpszfF6aSlF/q1Hbb8A/vfTBaTZgs30yUjSnTI0RIH3bUXAs5vdw+0
+o1MwUTAdBgNVHQ4EFgQU1rKFl18d70JYQS4V2B8Fosm/Q5cwHwYDVR0jBBgwFoAU
+1rKFl18d70JYQS4V2B8Fosm/Q5cwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQD
+AgNIADBFAiEA3sR+AAFWQoNnh/rbsKfAFqNGOdn68SVUO7BjeSGSOF8CIBJarG5+
+e8jdbV8z2MNnrnzTmMysSptZSFrZvDnUNPkO
+-----END CERTIFICATE-----";
+
+    fn cert(pem: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn certificate_names_are_found() {
+        let names = certificate_names(&cert(ALICE)).unwrap();
+        assert_eq!(names.0, ["Alice Smith"]);
+        assert_eq!(names.1, ["alice.example.com", "alice"]);
+        let names = certificate_names(&cert(BOB)).unwrap();
+        assert_eq!(names, (vec!["bob".to_string()], Vec::new()));
+    }
+
+    #[test]
+    fn nick_is_the_first_valid_name() {
+        assert_eq!(certificate_nick(&cert(ALICE)).as_deref(), Some("alice"));
+        assert_eq!(certificate_nick(&cert(BOB)).as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn truncated_certificates_have_no_names() {
+        let der = cert(BOB);
+        for len in [0, 1, 10, 100] {
+            assert_eq!(certificate_names(&der[..len]), None, "{len}");
+        }
+        assert_eq!(
+            certificate_nick(&CertificateDer::from(vec![0x30, 0x84])),
+            None
+        );
+    }
+}
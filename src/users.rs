@@ -0,0 +1,372 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    plugin::Plugins,
+    protocol::{Event, ServerFrame},
+    rooms::RoomError,
+    state::State,
+};
+
+pub const MAX_NICK_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NickError {
+    Empty,
+    TooLong,
+    BadStart,
+    BadChar(char),
+    Taken(String),
+    /// Belongs to an account the user isn't logged in to
+    Reserved(String),
+}
+
+impl fmt::Display for NickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NickError::Empty => write!(f, "nickname cannot be empty"),
+            NickError::TooLong => write!(f, "nickname is longer than {MAX_NICK_LEN} characters"),
+            NickError::BadStart => write!(f, "nickname must start with a letter"),
+            NickError::BadChar(c) => write!(f, "nickname cannot contain `{c}`"),
+            NickError::Taken(nick) => write!(f, "nickname {nick} is already taken"),
+            NickError::Reserved(nick) => {
+                write!(f, "nickname {nick} is registered, /login to use it")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NickError {}
+
+/// Letters, digits, `_` and `-`, starting with a letter.
+pub fn validate_nick(nick: &str) -> Result<(), NickError> {
+    let mut chars = nick.chars();
+    match chars.next() {
+        None => return Err(NickError::Empty),
+        Some(c) if !c.is_ascii_alphabetic() => return Err(NickError::BadStart),
+        _ => {}
+    }
+    if nick.len() > MAX_NICK_LEN {
+        return Err(NickError::TooLong);
+    }
+    match chars.find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-')) {
+        Some(c) => Err(NickError::BadChar(c)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MsgError {
+    Offline(String),
+    MailboxFull(String),
+    QueueFull(String),
+}
+
+impl fmt::Display for MsgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgError::Offline(nick) => write!(f, "{nick} is not online"),
+            MsgError::MailboxFull(nick) => {
+                write!(f, "{nick} has too many unread messages, try again later")
+            }
+            MsgError::QueueFull(nick) => write!(f, "{nick} has too many messages waiting already"),
+        }
+    }
+}
+
+impl std::error::Error for MsgError {}
+
+/// What happened to a private message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Handed to the recipient's session, with their nick as they spell it
+    Sent(String),
+    /// Recipient is offline, kept until they connect
+    Queued,
+}
+
+struct User {
+    /// Nick as the user typed it
+    nick: String,
+    addr: SocketAddr,
+    mailbox: mpsc::Sender<ServerFrame>,
+}
+
+/// Nicknames in use and where to reach their owners. Lookups are
+/// case-insensitive so `Alice` and `alice` can't both be online.
+pub struct Users {
+    // lowercased nick -> user
+    online: Mutex<HashMap<String, User>>,
+    // lowercased nick -> private messages sent while they were away
+    offline: Mutex<HashMap<String, VecDeque<ServerFrame>>>,
+    offline_limit: usize,
+}
+
+impl Users {
+    /// `offline_limit` is how many private messages are kept per offline
+    /// nick, 0 turns the offline queue off.
+    pub fn new(offline_limit: usize) -> Users {
+        Users {
+            online: Mutex::default(),
+            offline: Mutex::default(),
+            offline_limit,
+        }
+    }
+
+    pub fn claim(
+        &self,
+        nick: &str,
+        addr: SocketAddr,
+        mailbox: mpsc::Sender<ServerFrame>,
+    ) -> Result<(), NickError> {
+        validate_nick(nick)?;
+        let mut online = self.online.lock().unwrap();
+        match online.entry(nick.to_ascii_lowercase()) {
+            Entry::Occupied(_) => Err(NickError::Taken(nick.to_string())),
+            Entry::Vacant(entry) => {
+                entry.insert(User {
+                    nick: nick.to_string(),
+                    addr,
+                    mailbox,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    pub fn rename(
+        &self,
+        old_nick: &str,
+        new_nick: &str,
+        addr: SocketAddr,
+    ) -> Result<(), NickError> {
+        validate_nick(new_nick)?;
+        let mut online = self.online.lock().unwrap();
+        let new_key = new_nick.to_ascii_lowercase();
+        if online.get(&new_key).is_some_and(|user| user.addr != addr) {
+            return Err(NickError::Taken(new_nick.to_string()));
+        }
+        let mut user = online
+            .remove(&old_nick.to_ascii_lowercase())
+            .expect("a registered user owns its nick");
+        user.nick = new_nick.to_string();
+        online.insert(new_key, user);
+        Ok(())
+    }
+
+    pub fn is_online(&self, nick: &str) -> bool {
+        self.online
+            .lock()
+            .unwrap()
+            .contains_key(&nick.to_ascii_lowercase())
+    }
+
+    /// Nicknames of everyone online, sorted.
+    pub fn list(&self) -> Vec<String> {
+        let online = self.online.lock().unwrap();
+        let mut nicks: Vec<_> = online.values().map(|user| user.nick.clone()).collect();
+        nicks.sort_by_key(|nick| nick.to_ascii_lowercase());
+        nicks
+    }
+
+    pub fn release(&self, nick: &str) {
+        self.online
+            .lock()
+            .unwrap()
+            .remove(&nick.to_ascii_lowercase());
+    }
+
+    /// Send a private message to `to`'s session, or queue it if they are
+    /// offline and the offline queue is on.
+    pub fn deliver(&self, to: &str, frame: ServerFrame) -> Result<Delivery, MsgError> {
+        let key = to.to_ascii_lowercase();
+        if let Some(user) = self.online.lock().unwrap().get(&key) {
+            return match user.mailbox.try_send(frame) {
+                Ok(()) => Ok(Delivery::Sent(user.nick.clone())),
+                Err(TrySendError::Full(_)) => Err(MsgError::MailboxFull(user.nick.clone())),
+                // Session is on its way out, same as being offline
+                Err(TrySendError::Closed(_)) => Err(MsgError::Offline(user.nick.clone())),
+            };
+        }
+        if self.offline_limit == 0 || validate_nick(to).is_err() {
+            return Err(MsgError::Offline(to.to_string()));
+        }
+        let mut offline = self.offline.lock().unwrap();
+        let queue = offline.entry(key).or_default();
+        if queue.len() >= self.offline_limit {
+            return Err(MsgError::QueueFull(to.to_string()));
+        }
+        queue.push_back(frame);
+        Ok(Delivery::Queued)
+    }
+
+    /// Private messages that were sent to `nick` while it was offline.
+    pub fn take_offline(&self, nick: &str) -> VecDeque<ServerFrame> {
+        self.offline
+            .lock()
+            .unwrap()
+            .remove(&nick.to_ascii_lowercase())
+            .unwrap_or_default()
+    }
+}
+
+/// A registered user. Gives the nickname back, leaves all rooms and announces
+/// the departure when the session ends, however it ends.
+pub struct Registration {
+    pub nick: String,
+    /// Room that plain lines are sent to, the last one joined
+    pub room: Option<String>,
+    pub addr: SocketAddr,
+    /// Account the user logged in to, as it was registered
+    pub account: Option<String>,
+    /// Why the server ended the session, for the departure notice
+    quit_reason: Option<String>,
+    state: Arc<State>,
+}
+
+impl Registration {
+    /// Register an anonymous user, who can't take a registered nick.
+    pub fn register(
+        nick: &str,
+        addr: SocketAddr,
+        mailbox: mpsc::Sender<ServerFrame>,
+        state: &Arc<State>,
+    ) -> Result<Self, NickError> {
+        if is_reserved(nick, state) {
+            return Err(NickError::Reserved(nick.to_string()));
+        }
+        Self::claim(nick, None, addr, mailbox, state)
+    }
+
+    /// Register a user whose password for `account` was checked, under the
+    /// account's nick.
+    pub fn authenticated(
+        account: String,
+        addr: SocketAddr,
+        mailbox: mpsc::Sender<ServerFrame>,
+        state: &Arc<State>,
+    ) -> Result<Self, NickError> {
+        Self::claim(&account.clone(), Some(account), addr, mailbox, state)
+    }
+
+    fn claim(
+        nick: &str,
+        account: Option<String>,
+        addr: SocketAddr,
+        mailbox: mpsc::Sender<ServerFrame>,
+        state: &Arc<State>,
+    ) -> Result<Self, NickError> {
+        state.users.claim(nick, addr, mailbox)?;
+        state.announce(None, nick, Event::Join, addr);
+        Ok(Registration {
+            nick: nick.to_string(),
+            room: None,
+            addr,
+            account,
+            quit_reason: None,
+            state: state.clone(),
+        })
+    }
+
+    /// Change nick. Registered nicks are only available to their account.
+    pub fn rename(&mut self, new_nick: &str) -> Result<(), NickError> {
+        let own =
+            (self.account.as_deref()).is_some_and(|account| account.eq_ignore_ascii_case(new_nick));
+        if !own && is_reserved(new_nick, &self.state) {
+            return Err(NickError::Reserved(new_nick.to_string()));
+        }
+        self.change_nick(new_nick)
+    }
+
+    /// Log in to `account`, whose password was checked, and take its nick.
+    pub fn log_in(&mut self, account: String) -> Result<(), NickError> {
+        if self.nick != account {
+            self.change_nick(&account)?;
+        }
+        self.account = Some(account);
+        Ok(())
+    }
+
+    fn change_nick(&mut self, new_nick: &str) -> Result<(), NickError> {
+        self.state.users.rename(&self.nick, new_nick, self.addr)?;
+        self.state.rooms.rename(self.addr, new_nick);
+        let old_nick = std::mem::replace(&mut self.nick, new_nick.to_string());
+        let renamed = Event::Nick(new_nick.to_string());
+        self.state.announce(None, &old_nick, renamed, self.addr);
+        Ok(())
+    }
+
+    /// Join `room` (or switch to it if already there) and make it the room
+    /// plain lines go to. Returns the reply for the user.
+    pub fn join(&mut self, room: &str) -> Result<String, RoomError> {
+        let config = &self.state.config;
+        if self.account.is_none()
+            && config
+                .auth_rooms
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(room))
+        {
+            return Err(RoomError::AuthRequired(room.to_string()));
+        }
+        let (room, joined) = self.state.rooms.join(room, self.addr, &self.nick)?;
+        let reply = if joined {
+            self.state
+                .announce(Some(&room), &self.nick, Event::Join, self.addr);
+            format!("you joined {room}")
+        } else {
+            format!("now talking in {room}")
+        };
+        self.room = Some(room);
+        Ok(reply)
+    }
+
+    pub fn part(&mut self, room: &str) -> Result<String, RoomError> {
+        let room = self.state.rooms.part(room, self.addr)?;
+        self.state
+            .announce(Some(&room), &self.nick, Event::Part(None), self.addr);
+        if self.room.as_deref() == Some(room.as_str()) {
+            // Fall back to one of the rooms we are still in, if any
+            self.room = self.state.rooms.joined(self.addr).pop();
+        }
+        Ok(match &self.room {
+            Some(active) => format!("you left {room}, now talking in {active}"),
+            None => format!("you left {room}"),
+        })
+    }
+
+    /// Change the topic of a room the user is in. Returns the room's
+    /// canonical name.
+    pub fn set_topic(&mut self, room: &str, topic: &str) -> Result<String, RoomError> {
+        let room = self.state.rooms.set_topic(room, self.addr, topic)?;
+        let changed = Event::Topic(topic.to_string());
+        self.state
+            .announce(Some(&room), &self.nick, changed, self.addr);
+        Ok(room)
+    }
+
+    /// End the session for `reason`, which everyone else is told.
+    pub fn leave(mut self, reason: &str) {
+        self.quit_reason = Some(reason.to_string());
+    }
+}
+
+fn is_reserved(nick: &str, state: &State) -> bool {
+    (state.accounts.as_ref()).is_some_and(|accounts| accounts.is_registered(nick))
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state.rooms.part_all(self.addr);
+        self.state.users.release(&self.nick);
+        let reason = self.quit_reason.take();
+        let left = Event::Part(reason.clone());
+        self.state.announce(None, &self.nick, left, self.addr);
+        Plugins::disconnect(self, reason, self.state.clone());
+    }
+}
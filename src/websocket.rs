@@ -0,0 +1,461 @@
+//! WebSocket gateway for browsers (RFC 6455).
+//!
+//! After the HTTP upgrade, every text message from the browser becomes one
+//! protocol line and every line the server writes goes out as one text
+//! message. The session itself is the same `handle_connection` the TCP
+//! listener uses, it just reads and writes through an in-memory pipe.
+
+use std::{io, time::Duration};
+
+use log::debug;
+use tokio::{
+    io::{
+        self as tokio_io, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, DuplexStream, ReadHalf, WriteHalf,
+    },
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::crypto::{base64, sha1};
+
+/// How long a client gets to finish the HTTP upgrade
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest HTTP upgrade request accepted
+const MAX_REQUEST: usize = 8 * 1024;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Complete the HTTP upgrade on `socket` and return the stream the chat
+/// session should use. Malformed requests get a `400` and an `InvalidData`
+/// error. Messages from the browser longer than `max_message` close the
+/// connection, like a protocol line would.
+pub async fn accept(mut socket: TcpStream, max_message: usize) -> io::Result<DuplexStream> {
+    let request = read_request(&mut socket).await?;
+    let key = match upgrade_key(&request) {
+        Ok(key) => key,
+        Err(reason) => {
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}",
+                reason.len()
+            );
+            // Best effort, the connection is dropped either way
+            let _ = socket.write_all(response.as_bytes()).await;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    socket.write_all(response.as_bytes()).await?;
+
+    let (session, bridge) = tokio_io::duplex(2 * max_message);
+    let (bridge_read, bridge_write) = tokio_io::split(bridge);
+    let (socket_read, socket_write) = socket.into_split();
+    let (control_send, control_recv) = mpsc::channel(4);
+    let reader = tokio::spawn(browser_to_session(
+        socket_read,
+        bridge_write,
+        control_send,
+        max_message,
+    ));
+    tokio::spawn(session_to_browser(
+        bridge_read,
+        socket_write,
+        control_recv,
+        reader,
+    ));
+    Ok(session)
+}
+
+/// The request head, up to and including the blank line.
+async fn read_request(socket: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "upgrade request too long",
+            ));
+        }
+        // Could read past the head, but clients have to wait for the
+        // response before sending frames
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(request)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "upgrade request is not UTF-8"))
+}
+
+/// `Sec-WebSocket-Key` of a valid upgrade request.
+fn upgrade_key(request: &str) -> Result<&str, &'static str> {
+    if !request.starts_with("GET ") {
+        return Err("expected a GET request");
+    }
+    let header = |name: &str| {
+        request.split("\r\n").skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    };
+    let has_token = |name, token: &str| {
+        header(name).is_some_and(|value: &str| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err("expected a WebSocket upgrade");
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Err("unsupported WebSocket version");
+    }
+    header("Sec-WebSocket-Key").ok_or("missing Sec-WebSocket-Key")
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+/// Frames the reading side wants written back to the browser.
+enum Control {
+    Pong(Vec<u8>),
+    Close(u16),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Reads messages from the browser and writes them into the session as lines.
+/// A message can't hold more than one line, so line breaks in it close the
+/// connection, except for one at the very end.
+async fn browser_to_session<R: AsyncRead + Unpin>(
+    mut socket: R,
+    mut session: WriteHalf<DuplexStream>,
+    control: mpsc::Sender<Control>,
+    max_message: usize,
+) {
+    let mut message = Vec::new();
+    // Whether the last text frame didn't finish its message
+    let mut fragmented = false;
+    let code = loop {
+        let frame = match read_frame(&mut socket, max_message).await {
+            Ok(frame) => frame,
+            Err(FrameError::Invalid(reason)) => {
+                debug!("bad WebSocket frame: {reason}");
+                break CLOSE_PROTOCOL_ERROR;
+            }
+            Err(FrameError::TooBig) => break CLOSE_TOO_BIG,
+            Err(FrameError::Io(err)) => {
+                debug!("WebSocket read failed: {err}");
+                return;
+            }
+        };
+        match frame.opcode {
+            OP_TEXT | OP_CONTINUATION => {
+                // Continuations only follow an unfinished message, and a
+                // new message only starts after the last one finished
+                if fragmented != (frame.opcode == OP_CONTINUATION) {
+                    debug!("WebSocket message fragments out of order");
+                    break CLOSE_PROTOCOL_ERROR;
+                }
+                if message.len() + frame.payload.len() > max_message {
+                    break CLOSE_TOO_BIG;
+                }
+                message.extend_from_slice(&frame.payload);
+                fragmented = !frame.fin;
+                if fragmented {
+                    continue;
+                }
+                let ending = [&b"\r\n"[..], b"\n"]
+                    .into_iter()
+                    .find(|ending| message.ends_with(ending))
+                    .map_or(0, <[u8]>::len);
+                message.truncate(message.len() - ending);
+                if message.iter().any(|&b| b == b'\n' || b == b'\r') {
+                    debug!("WebSocket message with more than one line");
+                    break CLOSE_INVALID_DATA;
+                }
+                message.push(b'\n');
+                if session.write_all(&message).await.is_err() {
+                    // Session is over, the other half closes the socket
+                    return;
+                }
+                message.clear();
+            }
+            OP_BINARY if fragmented => break CLOSE_PROTOCOL_ERROR,
+            OP_BINARY => break CLOSE_UNSUPPORTED,
+            OP_PING => {
+                let _ = control.send(Control::Pong(frame.payload)).await;
+            }
+            OP_PONG => {}
+            OP_CLOSE => break CLOSE_NORMAL,
+            _ => break CLOSE_PROTOCOL_ERROR,
+        }
+    };
+    let _ = control.send(Control::Close(code)).await;
+}
+
+/// Writes every line from the session to the browser as a text message.
+/// Stops `reader` when done, it would keep the socket open otherwise.
+async fn session_to_browser(
+    session: ReadHalf<DuplexStream>,
+    mut socket: OwnedWriteHalf,
+    mut control: mpsc::Receiver<Control>,
+    reader: JoinHandle<()>,
+) {
+    let mut session = BufReader::new(session);
+    let mut line = Vec::new();
+    let result: io::Result<()> = async {
+        loop {
+            tokio::select! {
+                // `read_until` keeps partial reads in `line`, so this is cancel safe
+                read = session.read_until(b'\n', &mut line) => {
+                    if read? == 0 {
+                        write_close(&mut socket, CLOSE_NORMAL).await?;
+                        return Ok(());
+                    }
+                    let text = line.strip_suffix(b"\n").unwrap_or(&line);
+                    write_frame(&mut socket, OP_TEXT, text).await?;
+                    line.clear();
+                }
+                Some(frame) = control.recv() => match frame {
+                    Control::Pong(payload) => write_frame(&mut socket, OP_PONG, &payload).await?,
+                    Control::Close(code) => {
+                        write_close(&mut socket, code).await?;
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+    .await;
+    if let Err(err) = result {
+        debug!("WebSocket write failed: {err}");
+    }
+    reader.abort();
+    let _ = socket.shutdown().await;
+}
+
+enum FrameError {
+    Io(io::Error),
+    Invalid(&'static str),
+    TooBig,
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    max_message: usize,
+) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    socket.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Invalid("reserved bits set"));
+    }
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(FrameError::Invalid("client frames must be masked"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => u64::from(socket.read_u16().await?),
+        127 => socket.read_u64().await?,
+        len => u64::from(len),
+    };
+    let is_control = opcode & 0x8 != 0;
+    if is_control && (len > 125 || !fin) {
+        return Err(FrameError::Invalid("bad control frame"));
+    }
+    if len > max_message as u64 {
+        return Err(FrameError::TooBig);
+    }
+    let mut mask = [0; 4];
+    socket.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    socket.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    socket: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    // Servers never mask
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    socket.write_all(&frame).await
+}
+
+async fn write_close<W: AsyncWrite + Unpin>(socket: &mut W, code: u16) -> io::Result<()> {
+    write_frame(socket, OP_CLOSE, &code.to_be_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame as a browser sends it, masked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![u8::from(fin) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Feed `frames` to `browser_to_session`. Returns what the session got
+    /// and the close code, if the browser got one.
+    async fn browse(frames: &[Vec<u8>]) -> (String, Option<u16>) {
+        let (session, bridge) = tokio_io::duplex(1024);
+        let (_, bridge_write) = tokio_io::split(bridge);
+        let (control_send, mut control_recv) = mpsc::channel(4);
+        let (mut browser, socket) = tokio_io::duplex(1024);
+        browser.write_all(&frames.concat()).await.unwrap();
+        drop(browser);
+        browser_to_session(socket, bridge_write, control_send, 100).await;
+
+        let mut close = None;
+        while let Some(control) = control_recv.recv().await {
+            if let Control::Close(code) = control {
+                close = Some(code);
+            }
+        }
+        let mut lines = String::new();
+        let (mut session, _) = tokio_io::split(session);
+        session.read_to_string(&mut lines).await.unwrap();
+        (lines, close)
+    }
+
+    #[tokio::test]
+    async fn messages_become_lines() {
+        let frames = [
+            frame(false, OP_TEXT, b"hel"),
+            frame(false, OP_CONTINUATION, b""),
+            frame(true, OP_CONTINUATION, b"lo"),
+            frame(true, OP_TEXT, b"/join #rust\r\n"),
+            frame(true, OP_TEXT, b"bye\n"),
+            frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()),
+        ];
+        assert_eq!(
+            browse(&frames).await,
+            ("hello\n/join #rust\nbye\n".to_string(), Some(CLOSE_NORMAL))
+        );
+    }
+
+    #[tokio::test]
+    async fn line_breaks_inside_a_message_close_the_connection() {
+        for text in [&b"hi\n/quit"[..], b"hi\rPRIVMSG", b"\n\n"] {
+            let frames = [frame(true, OP_TEXT, b"ok"), frame(true, OP_TEXT, text)];
+            assert_eq!(
+                browse(&frames).await,
+                ("ok\n".to_string(), Some(CLOSE_INVALID_DATA))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fragments_must_be_in_order() {
+        let stray_continuation = [frame(true, OP_CONTINUATION, b"hi")];
+        let text_inside_message = [frame(false, OP_TEXT, b"a"), frame(true, OP_TEXT, b"b")];
+        let binary_inside_message = [frame(false, OP_TEXT, b"a"), frame(true, OP_BINARY, b"b")];
+        for frames in [
+            &stray_continuation[..],
+            &text_inside_message,
+            &binary_inside_message,
+        ] {
+            assert_eq!(
+                browse(frames).await,
+                (String::new(), Some(CLOSE_PROTOCOL_ERROR))
+            );
+        }
+        // Control frames may come between fragments
+        let frames = [
+            frame(false, OP_TEXT, b"a"),
+            frame(true, OP_PING, b""),
+            frame(true, OP_CONTINUATION, b"b"),
+        ];
+        assert_eq!(browse(&frames).await.0, "ab\n");
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let frames = [frame(true, OP_TEXT, &[b'a'; 101])];
+        assert_eq!(browse(&frames).await, (String::new(), Some(CLOSE_TOO_BIG)));
+        let frames = [
+            frame(false, OP_TEXT, &[b'a'; 60]),
+            frame(true, OP_CONTINUATION, &[b'a'; 60]),
+        ];
+        assert_eq!(browse(&frames).await, (String::new(), Some(CLOSE_TOO_BIG)));
+        let frames = [frame(true, OP_BINARY, b"a")];
+        assert_eq!(
+            browse(&frames).await,
+            (String::new(), Some(CLOSE_UNSUPPORTED))
+        );
+        let unmasked = vec![0x80 | OP_TEXT, 1, b'a'];
+        assert_eq!(
+            browse(&[unmasked]).await,
+            (String::new(), Some(CLOSE_PROTOCOL_ERROR))
+        );
+    }
+
+    #[test]
+    fn upgrade_requests() {
+        let request = "GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let key = upgrade_key(request).unwrap();
+        // The example from RFC 6455
+        assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(upgrade_key("POST / HTTP/1.1\r\n\r\n").is_err());
+        let old = request.replace("Version: 13", "Version: 8");
+        assert_eq!(upgrade_key(&old), Err("unsupported WebSocket version"));
+    }
+}